@@ -0,0 +1,161 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+
+// The port of the agent receiver of the first site on a Checkmk server
+pub const DEFAULT_PORT: u16 = 8000;
+
+// An agent receiver address as host:port, where IPv6 literals are enclosed in brackets as in
+// URLs. The host is kept as written, such that addresses keep matching existing registrations.
+#[derive(PartialEq, Debug)]
+pub struct Address<'a> {
+    pub host: &'a str,
+    pub port: u16,
+}
+
+impl Address<'_> {
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+}
+
+impl fmt::Display for Address<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.ip() {
+            Some(IpAddr::V6(_)) => write!(f, "[{}]:{}", self.host, self.port),
+            _ => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+fn parse_port(address: &str, port: &str) -> Result<u16, String> {
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => Err(format!(
+            "{} has an invalid port {}, expected a number from 1 to 65535",
+            address, port
+        )),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn check_host_name(address: &str, host: &str) -> Result<(), String> {
+    if host.is_empty() {
+        return Err(format!("{} lacks a host, expected host:port", address));
+    }
+    match host
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_')))
+    {
+        Some(c) => Err(format!(
+            "{} contains the invalid character '{}', expected host:port",
+            address, c
+        )),
+        None => Ok(()),
+    }
+}
+
+pub fn parse(address: &str) -> Result<Address<'_>, String> {
+    if address.contains("://") {
+        return Err(format!(
+            "{} must not contain a scheme like https://, expected host:port",
+            address
+        ));
+    }
+
+    if let Some(bracketed) = address.strip_prefix('[') {
+        let (host, rest) = bracketed
+            .split_once(']')
+            .ok_or_else(|| format!("{} lacks the closing bracket of the IPv6 address", address))?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(format!("{} is no valid IPv6 address", host));
+        }
+        let port = match rest {
+            "" => DEFAULT_PORT,
+            _ => parse_port(
+                address,
+                rest.strip_prefix(':').ok_or_else(|| {
+                    format!(
+                        "{} has unexpected characters after the IPv6 address",
+                        address
+                    )
+                })?,
+            )?,
+        };
+        return Ok(Address { host, port });
+    }
+
+    let (host, port) = match address.split_once(':') {
+        Some((_, port)) if port.contains(':') => {
+            return Err(format!(
+                "IPv6 addresses must be enclosed in brackets, as in [{}]:{}",
+                address, DEFAULT_PORT
+            ))
+        }
+        Some((host, port)) => (host, parse_port(address, port)?),
+        None => (address, DEFAULT_PORT),
+    };
+    check_host_name(address, host)?;
+    Ok(Address { host, port })
+}
+
+// An agent receiver on this host may be given by the absolute path of its unix socket instead,
+// which parse does not accept
+pub fn is_unix_socket(address: &str) -> bool {
+    address.starts_with('/')
+}
+
+// Addresses without port get the default one, such that registrations are always stored and
+// looked up under host:port
+pub fn normalize(address: &str) -> Result<String, String> {
+    if is_unix_socket(address) {
+        return Ok(String::from(address));
+    }
+    Ok(parse(address)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("server:8001"),
+            Ok(Address {
+                host: "server",
+                port: 8001
+            })
+        );
+        assert_eq!(
+            parse("[2001:db8::1]:8000"),
+            Ok(Address {
+                host: "2001:db8::1",
+                port: 8000
+            })
+        );
+        assert_eq!(parse("[::1]").map(|address| address.port), Ok(DEFAULT_PORT));
+        assert!(parse("2001:db8::1").is_err());
+        assert!(parse("[2001:db8::1").is_err());
+        assert!(parse("[server]:8000").is_err());
+        assert!(parse(":8000").is_err());
+        assert!(parse("server:http").is_err());
+        assert!(parse("server:0").is_err());
+        assert!(parse("https://server:8000").is_err());
+        assert!(parse("my server").is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("server").unwrap(), "server:8000");
+        assert_eq!(normalize("server:8001").unwrap(), "server:8001");
+        assert_eq!(normalize("[::1]").unwrap(), "[::1]:8000");
+        assert_eq!(normalize("192.168.0.1").unwrap(), "192.168.0.1:8000");
+        assert_eq!(
+            normalize("/omd/sites/mysite/tmp/run/agent-receiver.sock").unwrap(),
+            "/omd/sites/mysite/tmp/run/agent-receiver.sock"
+        );
+    }
+}
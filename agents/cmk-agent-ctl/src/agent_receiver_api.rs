@@ -2,12 +2,550 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use crate::certs;
-use anyhow::{anyhow, Context, Result as AnyhowResult};
+use crate::error::ApiError;
+use crate::secret::Secret;
+use crate::{address, certs, compression, config, crypto, rate_limit};
+use anyhow::{Context, Result as AnyhowResult};
+use http::header::{
+    HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, HOST, RETRY_AFTER, USER_AGENT,
+};
 use http::StatusCode;
-use reqwest;
+use log::warn;
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use reqwest::{Body, Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::future::Future;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Runtime;
+
+// Versions of the agent receiver API implemented by this client, oldest first. Receivers
+// without the api_versions endpoint only speak the first one.
+pub const API_VERSIONS: [&str; 1] = ["1"];
+
+// Upper limit for the delay between two attempts, however many retries are configured
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+// Receivers asking for longer breaks are tried again after this at the latest
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const UPLOAD_BUFFERED_CHUNKS: usize = 4;
+const PAYLOAD_SIGNATURE: &str = "payload-signature";
+const PAYLOAD_ENCRYPTION: &str = "payload-encryption";
+// Encrypted payloads say what they contain once decrypted
+const PAYLOAD_CONTENT_TYPE: &str = "payload-content-type";
+const PAYLOAD_CONTENT_ENCODING: &str = "payload-content-encoding";
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+// Clients with different settings, e.g. after reloading, are only kept up to this number
+const MAX_CACHED_CLIENTS: usize = 16;
+
+// Root certificate, client certificate if any and options a client was built with
+type CachedClient = (String, Option<String>, certs::ClientOptions, Client);
+
+thread_local! {
+    // Pooled connections are driven by the runtime which opened them, so runtime and clients
+    // are kept per thread, e.g. for the push loop and the renewal thread each
+    static RUNTIME: RefCell<Option<Runtime>> = const { RefCell::new(None) };
+    static CLIENTS: RefCell<Vec<CachedClient>> = const { RefCell::new(vec![]) };
+}
+
+// The requests are async, such that several receivers can be contacted concurrently. Callers
+// which are not async themselves run them to completion with this.
+pub fn block_on<T>(future: impl Future<Output = ApiResult<T>>) -> AnyhowResult<T> {
+    RUNTIME.with(|runtime| {
+        let mut runtime = runtime.borrow_mut();
+        let runtime = match &mut *runtime {
+            Some(runtime) => runtime,
+            None => runtime.insert(
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .context("Error creating the runtime for the agent receiver client")?,
+            ),
+        };
+        Ok(runtime.block_on(future)?)
+    })
+}
+
+// Waiting in the runtime lets pooled connections notice when the receiver closes them, which
+// would otherwise only show when the next request fails on them
+pub fn idle(duration: Duration) {
+    if block_on(async {
+        tokio::time::sleep(duration).await;
+        Ok(())
+    })
+    .is_err()
+    {
+        thread::sleep(duration);
+    }
+}
+
+pub fn jitter(max: Duration) -> Duration {
+    let mut random = [0; 4];
+    if rand_bytes(&mut random).is_err() {
+        return Duration::ZERO;
+    }
+    max.mul_f64(f64::from(u32::from_ne_bytes(random)) / f64::from(u32::MAX))
+}
+
+// Errors on the way to the receiver, e.g. a restarting reverse proxy, which may be gone on the
+// next attempt
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+// Either seconds or an HTTP date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    let retry_after = match value.parse::<u64>() {
+        Ok(seconds) => Duration::from_secs(seconds),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(now)
+            .unwrap_or_default(),
+    };
+    Some(retry_after.min(MAX_RETRY_AFTER))
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    if !matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+    parse_retry_after(
+        response.headers().get(RETRY_AFTER)?.to_str().ok()?,
+        SystemTime::now(),
+    )
+}
+
+fn status_error(status: StatusCode, retry_after: Option<Duration>, body: String) -> ApiError {
+    match retry_after {
+        Some(retry_after) => ApiError::Busy(status, retry_after, body),
+        None => ApiError::from_status(status, body),
+    }
+}
+
+fn is_transient_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request()
+}
+
+// The delay doubles with every attempt, and a random share of it is added, such that many agents
+// do not hit a recovering receiver at the same time
+fn backoff(client_options: &certs::ClientOptions, attempt: u32) -> Duration {
+    let delay = client_options
+        .retry_backoff
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_BACKOFF);
+    delay + jitter(delay / 2)
+}
+
+// The source of an io::Error is the source of the error it wraps, which rustls errors are
+// hidden in, possibly wrapped several times
+fn next_source<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a (dyn Error + 'static)> {
+    match error
+        .downcast_ref::<io::Error>()
+        .and_then(io::Error::get_ref)
+    {
+        Some(inner) => Some(inner),
+        None => error.source(),
+    }
+}
+
+fn is_tls_error(error: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn Error + 'static)> = Some(error);
+    while let Some(error) = source {
+        if error.is::<openssl::error::ErrorStack>()
+            || error.is::<openssl::ssl::Error>()
+            || error.is::<rustls019::TLSError>()
+        {
+            return true;
+        }
+        source = next_source(error);
+    }
+    false
+}
+
+// Each error of the chain repeats the messages of its sources, the innermost one says what
+// actually happened
+fn root_cause(error: &(dyn Error + 'static)) -> String {
+    let mut cause = error;
+    while let Some(source) = next_source(cause) {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> ApiError {
+        if is_tls_error(&error) {
+            ApiError::Tls(root_cause(&error))
+        } else if error.is_decode() {
+            ApiError::Malformed(root_cause(&error))
+        } else if error.is_timeout() {
+            ApiError::Timeout(String::from("Timed out waiting for the agent receiver"))
+        } else {
+            ApiError::Connection(root_cause(&error))
+        }
+    }
+}
+
+// reqwest only says that a timeout elapsed, but not which one
+fn classify(
+    error: reqwest::Error,
+    timeout: Duration,
+    client_options: &certs::ClientOptions,
+) -> ApiError {
+    match (error.is_timeout(), error.is_connect()) {
+        (true, true) => ApiError::Timeout(format!(
+            "Could not connect to the agent receiver within {}s",
+            client_options.connect_timeout.as_secs_f64()
+        )),
+        (true, false) => ApiError::Timeout(format!(
+            "The agent receiver did not answer within {}s",
+            timeout.as_secs_f64()
+        )),
+        _ => ApiError::from(error),
+    }
+}
+
+// Reusing the client keeps connections alive between requests, which spares repeated pushes
+// a TLS handshake each
+fn client(root_cert: &str, client_options: &certs::ClientOptions) -> ApiResult<Client> {
+    authenticated_client(root_cert, None, client_options)
+}
+
+fn authenticated_client(
+    root_cert: &str,
+    client_cert: Option<&certs::ClientCert>,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<Client> {
+    let certificate = client_cert.map(|client_cert| client_cert.certificate);
+    CLIENTS.with(|clients| {
+        let mut clients = clients.borrow_mut();
+        if let Some((_, _, _, client)) = clients.iter().find(|(cert, client_cert, options, _)| {
+            cert == root_cert && client_cert.as_deref() == certificate && options == client_options
+        }) {
+            return Ok(client.clone());
+        }
+        let client = certs::client(
+            Some(String::from(root_cert).into_bytes()),
+            client_cert,
+            client_options,
+        )
+        .map_err(|error| ApiError::Client(format!("{:#}", error)))?;
+        if clients.len() >= MAX_CACHED_CLIENTS {
+            clients.clear();
+        }
+        clients.push((
+            String::from(root_cert),
+            certificate.map(String::from),
+            client_options.clone(),
+            client.clone(),
+        ));
+        Ok(client)
+    })
+}
+
+// Identifies the controller to agent receivers and to the proxies in front of them
+fn user_agent() -> String {
+    let uname = nix::sys::utsname::uname();
+    format!(
+        "cmk-agent-ctl/{} ({} {}; {})",
+        env!("CARGO_PKG_VERSION"),
+        uname.sysname(),
+        uname.release(),
+        uname.machine()
+    )
+}
+
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("HTTP header {} must have the form \"Name: value\"", header))?;
+    Ok((
+        HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|error| format!("Invalid name of HTTP header {}: {}", header, error))?,
+        HeaderValue::from_str(value.trim())
+            .map_err(|error| format!("Invalid value of HTTP header {}: {}", header, error))?,
+    ))
+}
+
+// The configured headers are added to every request, the User-Agent unless they have one
+fn extra_headers(
+    client_options: &certs::ClientOptions,
+) -> ApiResult<Vec<(HeaderName, HeaderValue)>> {
+    let mut headers = client_options
+        .http_headers
+        .iter()
+        .map(|header| parse_header(header).map_err(ApiError::Client))
+        .collect::<ApiResult<Vec<(HeaderName, HeaderValue)>>>()?;
+    if !headers.iter().any(|(name, _)| name == USER_AGENT) {
+        headers.push((
+            USER_AGENT,
+            HeaderValue::from_str(&user_agent())
+                .map_err(|error| ApiError::Client(error.to_string()))?,
+        ));
+    }
+    Ok(headers)
+}
+
+// Headers a request sets itself are never replaced
+fn add_headers(request: &mut reqwest::Request, headers: &[(HeaderName, HeaderValue)]) {
+    let own: Vec<HeaderName> = request.headers().keys().cloned().collect();
+    for (name, value) in headers {
+        if !own.contains(name) {
+            request.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+}
+
+// All requests of this API may be repeated: pairing signs the same CSR again, registering and
+// pushing overwrite what the first attempt stored. The request is built again for every attempt,
+// because streamed bodies cannot be cloned. Returns whether the request had to be repeated.
+async fn send(
+    client: &Client,
+    request: impl Fn() -> RequestBuilder,
+    timeout: Duration,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<(Response, bool)> {
+    let headers = extra_headers(client_options)?;
+    let mut attempt = 0;
+    loop {
+        let request = request().timeout(timeout).build().map(|mut request| {
+            add_headers(&mut request, &headers);
+            request
+        });
+        // Failures come with whether they are worth another attempt
+        let outcome = match request {
+            Ok(request) => match unix_socket(request.url()) {
+                Some(path) => send_local(&path, request, timeout).await,
+                None => client.execute(request).await.map_err(|error| {
+                    (
+                        is_transient_error(&error),
+                        classify(error, timeout, client_options),
+                    )
+                }),
+            },
+            Err(error) => Err((false, ApiError::from(error))),
+        };
+        let (error, retry_after) = match outcome {
+            Ok(response)
+                if attempt < client_options.retries && is_transient_status(response.status()) =>
+            {
+                // Waiting longer is left to the push scheduler, which skips the receiver meanwhile
+                let retry_after = retry_after(&response);
+                if retry_after.is_some_and(|retry_after| retry_after > MAX_RETRY_BACKOFF) {
+                    return Ok((response, attempt > 0));
+                }
+                (
+                    status_error(response.status(), retry_after, String::new()),
+                    retry_after,
+                )
+            }
+            Ok(response) => return Ok((response, attempt > 0)),
+            Err((true, error)) if attempt < client_options.retries => (error, None),
+            Err((_, error)) => return Err(error),
+        };
+        let delay = retry_after.unwrap_or_else(|| backoff(client_options, attempt));
+        warn!(
+            "Attempt {} of {} failed, retrying in {:.1}s: {}",
+            attempt + 1,
+            client_options.retries + 1,
+            delay.as_secs_f64(),
+            error
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// reqwest only speaks HTTP over TCP, so requests to agent receivers behind a unix socket are
+// handed over to hyper, on a connection of their own
+async fn send_local(
+    path: &Path,
+    request: reqwest::Request,
+    timeout: Duration,
+) -> Result<Response, (bool, ApiError)> {
+    let url = request.url();
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => String::from(url.path()),
+    };
+    let (mut parts, body) = http::Request::try_from(request)
+        .map_err(|error| (false, ApiError::from(error)))?
+        .into_parts();
+    parts.uri = path_and_query
+        .parse()
+        .map_err(|error: http::uri::InvalidUri| (false, ApiError::Client(error.to_string())))?;
+    parts
+        .headers
+        .insert(HOST, HeaderValue::from_static("localhost"));
+    // reqwest offers no other way to read a request body as a stream
+    let body = hyper::Body::wrap_stream(Response::from(http::Response::new(body)).bytes_stream());
+    let request = http::Request::from_parts(parts, body);
+
+    let exchange = async {
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(|error| root_cause(&error))?;
+        tokio::spawn(connection);
+        sender
+            .send_request(request)
+            .await
+            .map_err(|error| root_cause(&error))
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(response)) => Ok(Response::from(response)),
+        Ok(Err(message)) => Err((true, ApiError::Connection(message))),
+        Err(_) => Err((
+            true,
+            ApiError::Timeout(format!(
+                "The agent receiver did not answer within {}s",
+                timeout.as_secs_f64()
+            )),
+        )),
+    }
+}
+
+// Get the text() instead of directly calling json(), because both methods would consume the
+// response. Otherwise, in case of a json parsing error, we would have no information about the
+// body.
+async fn parse<T: DeserializeOwned>(response: Response) -> ApiResult<T> {
+    let status = response.status();
+    let retry_after = retry_after(&response);
+    let body = response.text().await?;
+    if status != StatusCode::OK {
+        return Err(status_error(status, retry_after, body));
+    }
+    serde_json::from_str::<T>(&body)
+        .map_err(|error| ApiError::Malformed(format!("{} in response body {}", error, body)))
+}
+
+async fn expect_no_content(response: Response) -> ApiResult<()> {
+    let status = response.status();
+    if status == StatusCode::NO_CONTENT {
+        return Ok(());
+    }
+    let retry_after = retry_after(&response);
+    Err(status_error(
+        status,
+        retry_after,
+        response.text().await.unwrap_or_default(),
+    ))
+}
+
+// Version 1 predates versioned URLs, later versions are served below /v<version>. Agent
+// receivers behind a unix socket get the hex encoded path of the socket as host, which send
+// connects to.
+pub fn base_url(address: &str, api_version: Option<&str>) -> String {
+    let origin = if address::is_unix_socket(address) {
+        let host: String = address
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("unix://{}", host)
+    } else {
+        format!("https://{}", address)
+    };
+    match api_version {
+        None | Some("1") => origin,
+        Some(api_version) => format!("{}/v{}", origin, api_version),
+    }
+}
+
+fn unix_socket(url: &reqwest::Url) -> Option<PathBuf> {
+    if url.scheme() != "unix" {
+        return None;
+    }
+    let host = url.host_str()?;
+    let path = (0..host.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(host.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(PathBuf::from(String::from_utf8(path).ok()?))
+}
+
+// The newest version both sides speak
+fn choose_api_version(offered: &[String]) -> Option<&'static str> {
+    API_VERSIONS
+        .iter()
+        .rev()
+        .find(|version| offered.iter().any(|offered| offered == *version))
+        .copied()
+}
+
+#[derive(Deserialize)]
+struct ApiVersionsResponse {
+    versions: Vec<String>,
+}
+
+async fn negotiate_api_version(
+    server_address: &str,
+    root_cert: &str,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<&'static str> {
+    let client = client(root_cert, client_options)?;
+    let (response, _) = send(
+        &client,
+        || client.get(format!("{}/api_versions", base_url(server_address, None))),
+        client_options.request_timeout,
+        client_options,
+    )
+    .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(API_VERSIONS[0]);
+    }
+
+    let offered = parse::<ApiVersionsResponse>(response).await?.versions;
+    choose_api_version(&offered).ok_or_else(|| {
+        ApiError::Incompatible(format!(
+            "The agent receiver speaks the API versions {}, but this agent controller only {}. \
+             Update the agent controller or the site, whichever is older.",
+            offered.join(", "),
+            API_VERSIONS.join(", ")
+        ))
+    })
+}
+
+async fn capabilities(
+    base_url: &str,
+    root_cert: &str,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<Option<config::Capabilities>> {
+    let client = client(root_cert, client_options)?;
+    let (response, _) = send(
+        &client,
+        || client.get(format!("{}/capabilities", base_url)),
+        client_options.request_timeout,
+        client_options,
+    )
+    .await?;
+    // Older agent receivers cannot tell
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(parse::<config::Capabilities>(response).await?))
+}
 
 #[derive(Deserialize)]
 struct JSONResponse {
@@ -30,88 +568,792 @@ struct RegistrationWithHNBody {
     host_name: String,
 }
 
-pub fn pairing(
-    server_address: &str,
+async fn pairing(
+    base_url: &str,
     root_cert: &str,
     csr: String,
     credentials: &str,
-) -> AnyhowResult<String> {
-    let response = certs::client(Some(String::from(root_cert).into_bytes()))?
-        .post(format!("https://{}/pairing", server_address))
-        .header("authentication", format!("Bearer {}", credentials))
-        .json(&PairingBody { csr })
-        .send()?;
-    let status = response.status();
-    // Get the text() instead of directly calling json(), because both methods would consume the response.
-    // Otherwise, in case of a json parsing error, we would have no information about the body.
-    let body = response.text()?;
-
-    if let StatusCode::OK = status {
-        Ok(serde_json::from_str::<PairingResponse>(&body)
-            .context(format!("Error parsing this response body: {}", body))?
-            .cert)
-    } else {
-        Err(anyhow!("Request failed with code {}: {}", status, body))
-    }
+    client_options: &certs::ClientOptions,
+) -> ApiResult<String> {
+    let client = client(root_cert, client_options)?;
+    let body = PairingBody { csr };
+    let (response, _) = send(
+        &client,
+        || {
+            client
+                .post(format!("{}/pairing", base_url))
+                .header("authentication", format!("Bearer {}", credentials))
+                .json(&body)
+        },
+        client_options.request_timeout,
+        client_options,
+    )
+    .await?;
+    Ok(parse::<PairingResponse>(response).await?.cert)
 }
 
-pub fn register_with_hostname(
-    server_address: &str,
+async fn register_with_hostname(
+    base_url: &str,
     root_cert: &str,
     credentials: &str,
     uuid: &str,
     host_name: &str,
-) -> AnyhowResult<()> {
-    let response = certs::client(Some(String::from(root_cert).into_bytes()))?
-        .post(format!("https://{}/register_with_hostname", server_address))
-        .header("authentication", format!("Bearer {}", credentials))
-        .json(&RegistrationWithHNBody {
-            uuid: String::from(uuid),
-            host_name: String::from(host_name),
-        })
-        .send()?;
-    let status = response.status();
+    client_options: &certs::ClientOptions,
+) -> ApiResult<()> {
+    let client = client(root_cert, client_options)?;
+    let body = RegistrationWithHNBody {
+        uuid: String::from(uuid),
+        host_name: String::from(host_name),
+    };
+    let (response, _) = send(
+        &client,
+        || {
+            client
+                .post(format!("{}/register_with_hostname", base_url))
+                .header("authentication", format!("Bearer {}", credentials))
+                .json(&body)
+        },
+        client_options.request_timeout,
+        client_options,
+    )
+    .await?;
+    expect_no_content(response).await
+}
 
-    if let StatusCode::NO_CONTENT = status {
-        Ok(())
-    } else {
-        Err(anyhow!("Request failed with code {}", status,))
+#[derive(Serialize)]
+struct UnregisterBody {
+    uuid: String,
+}
+
+async fn unregister(
+    base_url: &str,
+    root_cert: &str,
+    credentials: &str,
+    uuid: &str,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<()> {
+    let client = client(root_cert, client_options)?;
+    let body = UnregisterBody {
+        uuid: String::from(uuid),
+    };
+    let (response, retried) = send(
+        &client,
+        || {
+            client
+                .post(format!("{}/unregister", base_url))
+                .header("authentication", format!("Bearer {}", credentials))
+                .json(&body)
+        },
+        client_options.request_timeout,
+        client_options,
+    )
+    .await?;
+
+    // An earlier attempt may have been lost on the way back after unregistering already
+    if retried && response.status() == StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    expect_no_content(response).await
+}
+
+#[derive(Deserialize)]
+pub struct RegistrationStatus {
+    pub hostname: Option<String>,
+    pub status: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Approval {
+    Pending,
+    Declined,
+    Accepted,
+}
+
+impl RegistrationStatus {
+    // Sites which approve new hosts manually report them as pending until then, hosts
+    // registered directly have no status
+    pub fn approval(&self) -> Approval {
+        match self.status.as_deref() {
+            Some("pending") => Approval::Pending,
+            Some("declined") => Approval::Declined,
+            _ => Approval::Accepted,
+        }
     }
 }
 
-// .header(
-//     "client-cert",
-//     base64::encode_config(
-//         tls_server::certificate(&mut String::from(client_cert).as_bytes())?.0,
-//         base64::URL_SAFE,
-//     ),
+async fn registration_status(
+    base_url: &str,
+    root_cert: &str,
+    uuid: &str,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<RegistrationStatus> {
+    let client = client(root_cert, client_options)?;
+    let (response, _) = send(
+        &client,
+        || client.get(format!("{}/registration_status/{}", base_url, uuid)),
+        client_options.request_timeout,
+        client_options,
+    )
+    .await?;
+    parse::<RegistrationStatus>(response).await
+}
 
-pub fn agent_data(
-    agent_receiver_address: &str,
+// The multipart form is built by hand, such that it can be compressed as a whole while it is
+// streamed
+fn multipart_body<'a>(
+    boundary: &str,
     uuid: &str,
-    monitoring_data: &Vec<u8>,
-) -> AnyhowResult<String> {
-    // TODO:
-    // - Send client cert in header
-    // - Use root cert
-    let response = certs::client(None)?
-        .post(String::from(agent_receiver_address) + "/agent-data")
-        .multipart(
-            reqwest::blocking::multipart::Form::new()
-                .text("uuid", String::from(uuid))
-                .part(
-                    "upload_file",
-                    reqwest::blocking::multipart::Part::bytes(monitoring_data.to_owned())
-                        // Note: We need to set the file name, otherwise the request won't have the
-                        // right format. However, the value itself does not matter.
-                        .file_name("agent_data"),
-                ),
+    monitoring_data: impl Read + Send + 'a,
+) -> impl Read + Send + 'a {
+    let head = format!(
+        "--{0}\r\n\
+         Content-Disposition: form-data; name=\"uuid\"\r\n\r\n\
+         {1}\r\n\
+         --{0}\r\n\
+         Content-Disposition: form-data; name=\"upload_file\"; filename=\"agent_data\"\r\n\r\n",
+        boundary, uuid
+    );
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    io::Cursor::new(head)
+        .chain(monitoring_data)
+        .chain(io::Cursor::new(tail))
+}
+
+fn multipart_boundary() -> ApiResult<String> {
+    let mut random = [0; 16];
+    rand_bytes(&mut random).map_err(|error| ApiError::Client(error.to_string()))?;
+    Ok(random.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Otherwise, an agent which cannot be reached would look like an unreachable receiver
+fn source_error(error: io::Error) -> io::Error {
+    io::Error::new(
+        error.kind(),
+        format!("Error reading monitoring data: {}", error),
+    )
+}
+
+// The body is read in a thread of its own, because the agent output is read with blocking I/O.
+// As only a few chunks are buffered, the thread waits for the upload if the agent is faster.
+fn streamed_body(reader: io::Result<Box<dyn Read + Send>>) -> Body {
+    let (sender, receiver) =
+        tokio::sync::mpsc::channel::<io::Result<Vec<u8>>>(UPLOAD_BUFFERED_CHUNKS);
+    thread::spawn(move || {
+        let mut reader = match reader {
+            Ok(reader) => reader,
+            Err(error) => {
+                let _ = sender.blocking_send(Err(source_error(error)));
+                return;
+            }
+        };
+        loop {
+            let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+            let item = match reader.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(length) => {
+                    chunk.truncate(length);
+                    Ok(chunk)
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => Err(source_error(error)),
+            };
+            let failed = item.is_err();
+            // The request is gone if nobody receives anymore
+            if sender.blocking_send(item).is_err() || failed {
+                return;
+            }
+        }
+    });
+    Body::wrap_stream(futures_util::stream::unfold(
+        receiver,
+        |mut receiver| async move { Some((receiver.recv().await?, receiver)) },
+    ))
+}
+
+pub struct PushOptions {
+    pub timeout: Duration,
+    pub compression: compression::Settings,
+    pub sign: bool,
+    pub encryption_key: Option<Secret>,
+    pub rate_limit: Option<rate_limit::RateLimit>,
+}
+
+// Encryption and the signature cover the request body as sent, so it has to be read
+// completely before the headers go out. This blocks the other pushes, which read shared data
+// from memory anyway. The signature is made over the encrypted payload, such that the agent
+// receiver can check it before decrypting.
+fn sealed_body(
+    body: io::Result<Box<dyn Read + Send>>,
+    server_spec: &config::ServerSpec,
+    push_options: &PushOptions,
+) -> io::Result<(Vec<u8>, Option<String>)> {
+    let to_io_error = |error: anyhow::Error| io::Error::other(error.to_string());
+    let mut payload = vec![];
+    body?.read_to_end(&mut payload)?;
+    if let Some(key) = &push_options.encryption_key {
+        payload = crypto::encrypt_payload(&payload, key.expose(), &server_spec.uuid)
+            .map_err(to_io_error)?;
+    }
+    if !push_options.sign {
+        return Ok((payload, None));
+    }
+    let signature = certs::sign_payload(&server_spec.private_key, &payload).map_err(to_io_error)?;
+    Ok((payload, Some(base64::encode_block(&signature))))
+}
+
+// The monitoring data is sent with chunked transfer encoding while it is read from the source,
+// which is opened again for repeated attempts. Data pushed later than it was collected, e.g.
+// from the spool, says when it was collected. The client certificate of the registration
+// authenticates the host, such that knowing its UUID does not suffice for sending data.
+// Encrypted payloads are opaque to proxies, so their type and compression move to headers of
+// their own. The rate limit applies to the body as sent, also to sealed bodies, which are
+// streamed from memory then.
+async fn agent_data(
+    base_url: &str,
+    server_spec: &config::ServerSpec,
+    monitoring_data: impl Fn() -> io::Result<Box<dyn Read + Send>>,
+    collected_at: Option<i64>,
+    push_options: &PushOptions,
+    client_options: &certs::ClientOptions,
+) -> ApiResult<String> {
+    let compression = push_options
+        .compression
+        .supported_by(server_spec.capabilities.as_ref());
+    let uuid = &server_spec.uuid;
+    let client = authenticated_client(
+        &server_spec.root_cert,
+        Some(&certs::ClientCert {
+            certificate: &server_spec.certificate,
+            private_key: &server_spec.private_key,
+        }),
+        client_options,
+    )?;
+    let boundary = multipart_boundary()?;
+    let (response, _) = send(
+        &client,
+        || {
+            let body = monitoring_data()
+                .and_then(|reader| compression.encode(multipart_body(&boundary, uuid, reader)));
+            let content_type = format!("multipart/form-data; boundary={}", boundary);
+            let encrypted = push_options.encryption_key.is_some();
+            let request = client.post(format!("{}/agent-data", base_url));
+            let request = if encrypted {
+                request
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .header(PAYLOAD_ENCRYPTION, "aes-256-gcm")
+                    .header(PAYLOAD_CONTENT_TYPE, content_type)
+            } else {
+                request.header(CONTENT_TYPE, content_type)
+            };
+            let limited = |body: io::Result<Box<dyn Read + Send>>| match &push_options.rate_limit {
+                Some(rate_limit) => streamed_body(body.map(|reader| rate_limit.limit(reader))),
+                None => streamed_body(body),
+            };
+            let request = if push_options.sign || encrypted {
+                let sealed = |payload: Vec<u8>| match push_options.rate_limit {
+                    Some(_) => limited(Ok(Box::new(io::Cursor::new(payload)))),
+                    None => Body::from(payload),
+                };
+                match sealed_body(body, server_spec, push_options) {
+                    Ok((payload, Some(signature))) => request
+                        .header(PAYLOAD_SIGNATURE, signature)
+                        .body(sealed(payload)),
+                    Ok((payload, None)) => request.body(sealed(payload)),
+                    Err(error) => request.body(streamed_body(Err(error))),
+                }
+            } else {
+                request.body(limited(body))
+            };
+            let request = match collected_at {
+                Some(collected_at) => request.header("collected-at", collected_at.to_string()),
+                None => request,
+            };
+            match compression.content_encoding() {
+                Some(content_encoding) if encrypted => {
+                    request.header(PAYLOAD_CONTENT_ENCODING, content_encoding)
+                }
+                Some(content_encoding) => request.header(CONTENT_ENCODING, content_encoding),
+                None => request,
+            }
+        },
+        push_options.timeout,
+        client_options,
+    )
+    .await?;
+
+    Ok(parse::<JSONResponse>(response).await?.message)
+}
+
+// The requests the modes make to agent receivers, such that their control flow can be tested
+// against Mock instead of a site
+pub trait AgentReceiver {
+    async fn negotiate_api_version(
+        &self,
+        server_address: &str,
+        root_cert: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<&'static str>;
+
+    async fn capabilities(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<Option<config::Capabilities>>;
+
+    async fn pairing(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        csr: String,
+        credentials: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<String>;
+
+    async fn register_with_hostname(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        credentials: &str,
+        uuid: &str,
+        host_name: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<()>;
+
+    async fn unregister(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        credentials: &str,
+        uuid: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<()>;
+
+    async fn registration_status(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        uuid: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<RegistrationStatus>;
+
+    async fn agent_data(
+        &self,
+        base_url: &str,
+        server_spec: &config::ServerSpec,
+        monitoring_data: impl Fn() -> io::Result<Box<dyn Read + Send>>,
+        collected_at: Option<i64>,
+        push_options: &PushOptions,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<String>;
+}
+
+// The agent receiver of a site, spoken to over HTTPS
+pub struct Http;
+
+impl AgentReceiver for Http {
+    async fn negotiate_api_version(
+        &self,
+        server_address: &str,
+        root_cert: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<&'static str> {
+        negotiate_api_version(server_address, root_cert, client_options).await
+    }
+
+    async fn capabilities(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<Option<config::Capabilities>> {
+        capabilities(base_url, root_cert, client_options).await
+    }
+
+    async fn pairing(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        csr: String,
+        credentials: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<String> {
+        pairing(base_url, root_cert, csr, credentials, client_options).await
+    }
+
+    async fn register_with_hostname(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        credentials: &str,
+        uuid: &str,
+        host_name: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<()> {
+        register_with_hostname(
+            base_url,
+            root_cert,
+            credentials,
+            uuid,
+            host_name,
+            client_options,
         )
-        .send()?;
+        .await
+    }
 
-    if let StatusCode::OK = response.status() {
-        Ok(response.json::<JSONResponse>()?.message)
-    } else {
-        Err(anyhow!("{}", response.text()?))
+    async fn unregister(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        credentials: &str,
+        uuid: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<()> {
+        unregister(base_url, root_cert, credentials, uuid, client_options).await
+    }
+
+    async fn registration_status(
+        &self,
+        base_url: &str,
+        root_cert: &str,
+        uuid: &str,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<RegistrationStatus> {
+        registration_status(base_url, root_cert, uuid, client_options).await
+    }
+
+    async fn agent_data(
+        &self,
+        base_url: &str,
+        server_spec: &config::ServerSpec,
+        monitoring_data: impl Fn() -> io::Result<Box<dyn Read + Send>>,
+        collected_at: Option<i64>,
+        push_options: &PushOptions,
+        client_options: &certs::ClientOptions,
+    ) -> ApiResult<String> {
+        agent_data(
+            base_url,
+            server_spec,
+            monitoring_data,
+            collected_at,
+            push_options,
+            client_options,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509Name, X509NameRef, X509Req, X509};
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    // An agent receiver in memory, which signs CSRs with its own CA and records what it gets
+    pub struct Mock {
+        ca_key: PKey<Private>,
+        pub root_cert: String,
+        // Returned by the next requests instead of answering them
+        pub failures: RefCell<VecDeque<ApiError>>,
+        pub status: RefCell<Option<String>>,
+        // Called whenever the registration status is queried
+        pub on_status: RefCell<Option<Box<dyn Fn()>>>,
+        pub pairings: Cell<usize>,
+        // UUID and host name
+        pub registered: RefCell<Vec<(String, String)>>,
+        pub unregistered: RefCell<Vec<String>>,
+        // UUID, when the data was collected if pushed later, and the data itself
+        pub pushed: RefCell<Vec<(String, Option<i64>, String)>>,
+        // Addresses whose requests fail to connect
+        pub unreachable: RefCell<Vec<String>>,
+        // What the capabilities endpoint answers, None like older agent receivers
+        pub capabilities: RefCell<Option<config::Capabilities>>,
+    }
+
+    fn ca_name() -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Site 'mock' local CA")
+            .unwrap();
+        name.build()
+    }
+
+    fn sign(
+        subject: &X509NameRef,
+        public_key: &PKey<impl openssl::pkey::HasPublic>,
+        ca_key: &PKey<Private>,
+    ) -> String {
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(subject).unwrap();
+        builder.set_issuer_name(&ca_name()).unwrap();
+        builder.set_pubkey(public_key).unwrap();
+        builder
+            .set_not_before(Asn1Time::days_from_now(0).unwrap().as_ref())
+            .unwrap();
+        builder
+            .set_not_after(Asn1Time::days_from_now(365).unwrap().as_ref())
+            .unwrap();
+        builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    impl Mock {
+        pub fn new() -> Mock {
+            let ca_key = PKey::from_ec_key(
+                EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+            )
+            .unwrap();
+            Mock {
+                root_cert: sign(&ca_name(), &ca_key, &ca_key),
+                ca_key,
+                failures: RefCell::new(VecDeque::new()),
+                status: RefCell::new(None),
+                on_status: RefCell::new(None),
+                pairings: Cell::new(0),
+                registered: RefCell::new(vec![]),
+                unregistered: RefCell::new(vec![]),
+                pushed: RefCell::new(vec![]),
+                unreachable: RefCell::new(vec![]),
+                capabilities: RefCell::new(None),
+            }
+        }
+
+        pub fn fail_next(&self, error: ApiError) {
+            self.failures.borrow_mut().push_back(error);
+        }
+
+        fn answer(&self, url: &str) -> ApiResult<()> {
+            if self
+                .unreachable
+                .borrow()
+                .iter()
+                .any(|address| url.contains(address.as_str()))
+            {
+                return Err(ApiError::Connection(format!("{}: Connection refused", url)));
+            }
+            match self.failures.borrow_mut().pop_front() {
+                Some(error) => Err(error),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl AgentReceiver for Mock {
+        async fn negotiate_api_version(
+            &self,
+            server_address: &str,
+            _root_cert: &str,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<&'static str> {
+            self.answer(server_address)?;
+            Ok(API_VERSIONS[0])
+        }
+
+        async fn capabilities(
+            &self,
+            base_url: &str,
+            _root_cert: &str,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<Option<config::Capabilities>> {
+            self.answer(base_url)?;
+            Ok(self.capabilities.borrow().clone())
+        }
+
+        async fn pairing(
+            &self,
+            base_url: &str,
+            _root_cert: &str,
+            csr: String,
+            _credentials: &str,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<String> {
+            self.answer(base_url)?;
+            self.pairings.set(self.pairings.get() + 1);
+            let csr = X509Req::from_pem(csr.as_bytes())
+                .map_err(|error| ApiError::Status(StatusCode::BAD_REQUEST, error.to_string()))?;
+            Ok(sign(
+                csr.subject_name(),
+                &csr.public_key().unwrap(),
+                &self.ca_key,
+            ))
+        }
+
+        async fn register_with_hostname(
+            &self,
+            base_url: &str,
+            _root_cert: &str,
+            _credentials: &str,
+            uuid: &str,
+            host_name: &str,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<()> {
+            self.answer(base_url)?;
+            self.registered
+                .borrow_mut()
+                .push((String::from(uuid), String::from(host_name)));
+            Ok(())
+        }
+
+        async fn unregister(
+            &self,
+            base_url: &str,
+            _root_cert: &str,
+            _credentials: &str,
+            uuid: &str,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<()> {
+            self.answer(base_url)?;
+            self.unregistered.borrow_mut().push(String::from(uuid));
+            Ok(())
+        }
+
+        async fn registration_status(
+            &self,
+            base_url: &str,
+            _root_cert: &str,
+            _uuid: &str,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<RegistrationStatus> {
+            self.answer(base_url)?;
+            if let Some(on_status) = self.on_status.borrow().as_ref() {
+                on_status();
+            }
+            Ok(RegistrationStatus {
+                hostname: None,
+                status: self.status.borrow().clone(),
+                message: None,
+            })
+        }
+
+        async fn agent_data(
+            &self,
+            base_url: &str,
+            server_spec: &config::ServerSpec,
+            monitoring_data: impl Fn() -> io::Result<Box<dyn Read + Send>>,
+            collected_at: Option<i64>,
+            _push_options: &PushOptions,
+            _client_options: &certs::ClientOptions,
+        ) -> ApiResult<String> {
+            self.answer(base_url)?;
+            let mut data = String::new();
+            monitoring_data()
+                .and_then(|mut reader| reader.read_to_string(&mut data))
+                .map_err(|error| ApiError::Connection(source_error(error).to_string()))?;
+            self.pushed
+                .borrow_mut()
+                .push((server_spec.uuid.clone(), collected_at, data));
+            Ok(String::from("ok"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_approval() {
+        let status = |status: Option<&str>| RegistrationStatus {
+            hostname: None,
+            status: status.map(String::from),
+            message: None,
+        };
+        assert_eq!(status(Some("pending")).approval(), Approval::Pending);
+        assert_eq!(status(Some("declined")).approval(), Approval::Declined);
+        assert_eq!(status(Some("ready")).approval(), Approval::Accepted);
+        assert_eq!(status(None).approval(), Approval::Accepted);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111657);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:45:37 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("86400", now), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_api_version() {
+        assert_eq!(
+            base_url("checkmk.example.com:8000", None),
+            "https://checkmk.example.com:8000"
+        );
+        assert_eq!(
+            base_url("checkmk.example.com:8000", Some("2")),
+            "https://checkmk.example.com:8000/v2"
+        );
+        assert_eq!(
+            choose_api_version(&[String::from("1"), String::from("2")]),
+            Some("1")
+        );
+        assert_eq!(choose_api_version(&[String::from("2")]), None);
+
+        let url = base_url("/run/agent-receiver.sock", Some("2"));
+        assert_eq!(
+            unix_socket(&reqwest::Url::parse(&format!("{}/agent-data", url)).unwrap()),
+            Some(PathBuf::from("/run/agent-receiver.sock"))
+        );
+        assert_eq!(
+            unix_socket(&reqwest::Url::parse("https://checkmk.example.com:8000").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_add_headers() {
+        let client_options = certs::ClientOptions {
+            http_headers: vec![
+                String::from("X-Tenant: acme"),
+                String::from("Content-Type: text/plain"),
+            ],
+            ..certs::ClientOptions::default()
+        };
+        let mut request = Client::new()
+            .post("https://checkmk.example.com:8000/agent-data")
+            .header(CONTENT_TYPE, "multipart/form-data")
+            .build()
+            .unwrap();
+        add_headers(&mut request, &extra_headers(&client_options).unwrap());
+        assert_eq!(request.headers()["x-tenant"], "acme");
+        assert_eq!(request.headers()[CONTENT_TYPE], "multipart/form-data");
+        assert!(request.headers()[USER_AGENT]
+            .to_str()
+            .unwrap()
+            .starts_with("cmk-agent-ctl/"));
+
+        assert!(parse_header("X-Tenant").is_err());
+        assert!(parse_header("X Tenant: acme").is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let client_options = certs::ClientOptions {
+            retry_backoff: Duration::from_secs(2),
+            ..certs::ClientOptions::default()
+        };
+        let first = backoff(&client_options, 0);
+        assert!(first >= Duration::from_secs(2) && first <= Duration::from_secs(3));
+        let third = backoff(&client_options, 2);
+        assert!(third >= Duration::from_secs(8) && third <= Duration::from_secs(12));
+        let capped = backoff(&client_options, 40);
+        assert!(capped >= MAX_RETRY_BACKOFF && capped <= MAX_RETRY_BACKOFF * 3 / 2);
     }
 }
@@ -1,54 +1,611 @@
-use anyhow::Result as AnyhowResult;
+use super::secret::Secret;
+use super::{config, fips, pkcs11, proxy};
+use anyhow::{anyhow, Result as AnyhowResult};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::PKey;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::Id;
+use openssl::pkey::{PKey, Private, Public};
 use openssl::rsa::Rsa;
-use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
-use openssl::x509::{X509Name, X509Req};
-use reqwest;
-use reqwest::blocking::{Client, ClientBuilder};
-use reqwest::Certificate;
+use openssl::sign::Signer;
+use openssl::ssl::{SslConnector, SslContextBuilder, SslMethod, SslVerifyMode};
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509Name, X509Ref, X509Req, X509ReqBuilder, X509StoreContext, X509};
+use reqwest::{Certificate, Client, ClientBuilder, Identity, Proxy};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub fn make_csr(cn: &str) -> AnyhowResult<(String, String)> {
+pub const RSA_KEY_SIZES: [u32; 3] = [2048, 3072, 4096];
+pub const KEY_LOG_FILE_VAR: &str = "SSLKEYLOGFILE";
+// Longer than the default push interval, such that the push daemon can reuse connections
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+// Parameters for generating the private key of a registration
+pub struct KeySpec {
+    pub algorithm: config::KeyAlgorithm,
+    pub rsa_key_size: u32,
+    pub fips: bool,
+    // A pkcs11: URI naming the token to generate the key in
+    pub pkcs11_token: Option<String>,
+}
+
+// Where the private key of a registration is kept. Keys generated in a PKCS#11 token never
+// leave it, the state only holds the pkcs11: URI naming them instead of the PEM.
+pub trait KeyBackend: Send + Sync {
+    fn public_key(&self) -> AnyhowResult<PKey<Public>>;
+    // Ed25519 signs the data itself, the other key types sign its SHA256 digest. ECDSA
+    // signatures are DER encoded.
+    fn sign(&self, data: &[u8]) -> AnyhowResult<Vec<u8>>;
+    // Keys which may leave the backend are handed to OpenSSL and the TLS libraries directly
+    fn exportable(&self) -> Option<&PKey<Private>> {
+        None
+    }
+}
+
+pub struct PemKey(PKey<Private>);
+
+impl KeyBackend for PemKey {
+    fn public_key(&self) -> AnyhowResult<PKey<Public>> {
+        Ok(PKey::public_key_from_der(&self.0.public_key_to_der()?)?)
+    }
+
+    fn sign(&self, data: &[u8]) -> AnyhowResult<Vec<u8>> {
+        let mut signer = match self.0.id() {
+            Id::ED25519 => Signer::new_without_digest(&self.0)?,
+            _ => Signer::new(MessageDigest::sha256(), &self.0)?,
+        };
+        Ok(signer.sign_oneshot_to_vec(data)?)
+    }
+
+    fn exportable(&self) -> Option<&PKey<Private>> {
+        Some(&self.0)
+    }
+}
+
+pub fn key_backend(private_key: &Secret) -> AnyhowResult<Arc<dyn KeyBackend>> {
+    if pkcs11::is_uri(private_key.expose()) {
+        let uri = pkcs11::parse_uri(private_key.expose()).map_err(|error| anyhow!(error))?;
+        return Ok(Arc::new(pkcs11::Pkcs11Key::new(uri)));
+    }
+    Ok(Arc::new(PemKey(PKey::private_key_from_pem(
+        private_key.expose().as_bytes(),
+    )?)))
+}
+
+// Returns the key together with what the state keeps of it
+fn new_key(cn: &str, key_spec: &KeySpec) -> AnyhowResult<(Arc<dyn KeyBackend>, Secret)> {
+    if let Some(token) = &key_spec.pkcs11_token {
+        if key_spec.algorithm != config::KeyAlgorithm::EcdsaP256 {
+            return Err(anyhow!("PKCS#11 tokens only hold ecdsa-p256 keys"));
+        }
+        let token = pkcs11::parse_uri(token).map_err(|error| anyhow!(error))?;
+        let handle = pkcs11::generate(&token, &format!("cmk-agent-ctl {}", cn))?;
+        return Ok((key_backend(&handle)?, handle));
+    }
+    let key_pair = generate_key(key_spec)?;
+    let pem = Secret::from(String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?);
+    Ok((Arc::new(PemKey(key_pair)), pem))
+}
+
+fn generate_key(key_spec: &KeySpec) -> AnyhowResult<PKey<Private>> {
+    if key_spec.fips {
+        fips::check_key_algorithm(key_spec.algorithm)?;
+    }
+    Ok(match key_spec.algorithm {
+        config::KeyAlgorithm::Rsa => {
+            if !RSA_KEY_SIZES.contains(&key_spec.rsa_key_size) {
+                return Err(anyhow!(
+                    "Unsupported RSA key size {}",
+                    key_spec.rsa_key_size
+                ));
+            }
+            PKey::from_rsa(Rsa::generate(key_spec.rsa_key_size)?)?
+        }
+        config::KeyAlgorithm::EcdsaP256 => PKey::from_ec_key(EcKey::generate(
+            EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?.as_ref(),
+        )?)?,
+        config::KeyAlgorithm::Ed25519 => PKey::generate_ed25519()?,
+    })
+}
+
+// What the site learns about this host from a CSR, besides the UUID as common name
+#[derive(Default)]
+pub struct CsrAttributes {
+    pub host_name: Option<String>,
+    // As key=value, e.g. datacenter=fra1
+    pub attributes: Vec<String>,
+}
+
+pub fn parse_csr_attribute(attribute: &str) -> Option<(&str, &str)> {
+    match attribute.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Some((key.trim(), value.trim())),
+        _ => None,
+    }
+}
+
+pub fn make_csr(
+    cn: &str,
+    key_spec: &KeySpec,
+    csr_attributes: &CsrAttributes,
+) -> AnyhowResult<(String, Secret)> {
     // https://github.com/sfackler/rust-openssl/blob/master/openssl/examples/mk_certs.rs
-    let rsa = Rsa::generate(2048)?;
-    let key_pair = PKey::from_rsa(rsa)?;
+    let (key, private_key) = new_key(cn, key_spec)?;
 
+    // Custom attributes have no standard name, they become organizational units
     let mut name = X509Name::builder()?;
     name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    for attribute in &csr_attributes.attributes {
+        let (key, value) = parse_csr_attribute(attribute)
+            .ok_or_else(|| anyhow!("Invalid CSR attribute {}", attribute))?;
+        name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, &format!("{}={}", key, value))?;
+    }
     let name = name.build();
 
     let mut crt_builder = X509Req::builder()?;
-    crt_builder.set_version(2).unwrap();
+    crt_builder.set_version(0).unwrap();
     crt_builder.set_subject_name(&name).unwrap();
-    crt_builder.set_pubkey(&key_pair).unwrap();
-    crt_builder.sign(&key_pair, MessageDigest::sha256())?;
+    if let Some(host_name) = &csr_attributes.host_name {
+        let mut extensions = Stack::new()?;
+        extensions.push(
+            SubjectAlternativeName::new()
+                .dns(host_name)
+                .build(&crt_builder.x509v3_context(None))?,
+        )?;
+        crt_builder.add_extensions(&extensions)?;
+    }
+    crt_builder.set_pubkey(key.public_key()?.as_ref()).unwrap();
+    let csr = match key.exportable() {
+        Some(key_pair) => {
+            crt_builder.sign(key_pair, digest(key_pair.id()))?;
+            crt_builder.build().to_pem()?
+        }
+        None => sign_with_backend(crt_builder, key.as_ref())?,
+    };
+
+    Ok((String::from_utf8(csr)?, private_key))
+}
+
+// Ed25519 signs the message itself, without a separate digest
+fn digest(id: Id) -> MessageDigest {
+    match id {
+        Id::ED25519 => MessageDigest::null(),
+        _ => MessageDigest::sha256(),
+    }
+}
+
+// Splits off the first DER element, returning its tag, its content and what follows it
+pub fn der_split(data: &[u8]) -> AnyhowResult<(u8, &[u8], &[u8])> {
+    let invalid = || anyhow!("Invalid DER encoding");
+    let (tag, first) = match data {
+        [tag, first, ..] => (*tag, *first),
+        _ => return Err(invalid()),
+    };
+    let (header, length) = if first < 0x80 {
+        (2, first as usize)
+    } else {
+        let count = (first & 0x7f) as usize;
+        let bytes = data
+            .get(2..2 + count)
+            .filter(|_| (1..=4).contains(&count))
+            .ok_or_else(invalid)?;
+        (
+            2 + count,
+            bytes
+                .iter()
+                .fold(0, |length, byte| length << 8 | *byte as usize),
+        )
+    };
+    let end = Some(header + length)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(invalid)?;
+    Ok((tag, &data[header..end], &data[end..]))
+}
+
+fn der_encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    if content.len() < 0x80 {
+        der.push(content.len() as u8);
+    } else {
+        let length: Vec<u8> = content
+            .len()
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        der.push(0x80 | length.len() as u8);
+        der.extend(length);
+    }
+    der.extend(content);
+    der
+}
+
+// OpenSSL only signs requests with keys it holds itself. So the request is signed with a
+// throwaway key of the same type, which yields the algorithm identifier, and the signature is
+// replaced by the one of the backend then.
+fn sign_with_backend(mut builder: X509ReqBuilder, key: &dyn KeyBackend) -> AnyhowResult<Vec<u8>> {
+    let throwaway = match key.public_key()?.id() {
+        Id::EC => PKey::from_ec_key(EcKey::generate(
+            EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?.as_ref(),
+        )?)?,
+        Id::ED25519 => PKey::generate_ed25519()?,
+        Id::RSA => PKey::from_rsa(Rsa::generate(2048)?)?,
+        _ => return Err(anyhow!("Unsupported key type")),
+    };
+    builder.sign(&throwaway, digest(throwaway.id()))?;
+    let der = builder.build().to_der()?;
+    let (_, request, _) = der_split(&der)?;
+    let (info_tag, info, rest) = der_split(request)?;
+    let (algorithm_tag, algorithm, _) = der_split(rest)?;
+    let info = der_encode(info_tag, info);
+    // A BIT STRING without unused bits
+    let signature = [&[0][..], &key.sign(&info)?].concat();
+    let request = [
+        info,
+        der_encode(algorithm_tag, algorithm),
+        der_encode(0x03, &signature),
+    ]
+    .concat();
+    Ok(X509Req::from_der(&der_encode(0x30, &request))?.to_pem()?)
+}
+
+// Valid for a day, which is plenty for the demo mode it is used for
+pub fn make_self_signed(cn: &str, key_spec: &KeySpec) -> AnyhowResult<(String, Secret)> {
+    let key_pair = generate_key(key_spec)?;
+
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let mut crt_builder = X509::builder()?;
+    crt_builder.set_version(2)?;
+    crt_builder.set_serial_number(serial.to_asn1_integer()?.as_ref())?;
+    crt_builder.set_subject_name(&name)?;
+    crt_builder.set_issuer_name(&name)?;
+    crt_builder.set_pubkey(&key_pair)?;
+    crt_builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    crt_builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+    crt_builder.sign(&key_pair, digest(key_pair.id()))?;
 
     Ok((
         String::from_utf8(crt_builder.build().to_pem()?)?,
-        String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?,
+        Secret::from(String::from_utf8(key_pair.private_key_to_pem_pkcs8()?)?),
     ))
 }
 
-pub fn client(root_cert: Option<Vec<u8>>) -> AnyhowResult<Client> {
-    let client_builder = ClientBuilder::new();
+// How connections to the agent receiver are made
+#[derive(Clone, Default, PartialEq)]
+pub struct ClientOptions {
+    pub proxy_url: Option<String>,
+    pub tls_verify: config::TlsVerify,
+    pub verify_hostname: bool,
+    pub receiver_cert_fingerprint: Option<String>,
+    pub fips: bool,
+    pub tls_key_log: bool,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    pub http_headers: Vec<String>,
+}
+
+// The certificate and private key of a registration, with which the agent authenticates itself
+// to the agent receiver
+pub struct ClientCert<'a> {
+    pub certificate: &'a str,
+    pub private_key: &'a Secret,
+}
+
+// native-tls only takes client certificates as PKCS#12, which needs no password here since it
+// never leaves the process
+fn native_tls_identity(client_cert: &ClientCert) -> AnyhowResult<Identity> {
+    let cert = X509::from_pem(client_cert.certificate.as_bytes())?;
+    let private_key = PKey::private_key_from_pem(client_cert.private_key.expose().as_bytes())?;
+    let pkcs12 = Pkcs12::builder().build("", "cmk-agent-ctl", &private_key, &cert)?;
+    Ok(Identity::from_pkcs12_der(&pkcs12.to_der()?, "")?)
+}
+
+fn rustls_client_cert(
+    tls_config: &mut rustls019::ClientConfig,
+    client_cert: &ClientCert,
+) -> AnyhowResult<()> {
+    let cert = X509::from_pem(client_cert.certificate.as_bytes())?;
+    let key = key_backend(client_cert.private_key)?;
+    let key_pair = match key.exportable() {
+        Some(key_pair) => key_pair,
+        None => {
+            tls_config.client_auth_cert_resolver =
+                Arc::new(BackendClientCert(rustls019::sign::CertifiedKey::new(
+                    vec![rustls019::Certificate(cert.to_der()?)],
+                    Arc::new(Box::new(BackendSigningKey::new(key)?)),
+                )));
+            return Ok(());
+        }
+    };
+    // rustls only takes PKCS#8 for all key types, which keys of older registrations may not be
+    let private_key = key_pair.private_key_to_pem_pkcs8()?;
+    let private_key = rustls_pemfile::pkcs8_private_keys(&mut private_key.as_slice())?
+        .pop()
+        .ok_or_else(|| anyhow!("Could not convert the private key to PKCS#8"))?;
+    tls_config
+        .set_single_client_cert(
+            vec![rustls019::Certificate(cert.to_der()?)],
+            rustls019::PrivateKey(private_key),
+        )
+        .map_err(|error| anyhow!("Unusable client certificate: {}", error))
+}
+
+// Signs TLS handshakes with keys which never leave their backend. Such keys are P-256 keys,
+// see pkcs11, so ECDSA with SHA256 is the only scheme offered. The server side does the same
+// with the types of rustls 0.20, see tls_server.
+#[derive(Clone)]
+pub struct BackendSigningKey(Arc<dyn KeyBackend>);
+
+impl BackendSigningKey {
+    pub fn new(key: Arc<dyn KeyBackend>) -> AnyhowResult<BackendSigningKey> {
+        if key.public_key()?.id() != Id::EC {
+            return Err(anyhow!("Only EC keys can be used from a key backend"));
+        }
+        Ok(BackendSigningKey(key))
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        self.0.sign(message).map_err(|error| format!("{:#}", error))
+    }
+}
+
+impl rustls019::sign::SigningKey for BackendSigningKey {
+    fn choose_scheme(
+        &self,
+        offered: &[rustls019::SignatureScheme],
+    ) -> Option<Box<dyn rustls019::sign::Signer>> {
+        if offered.contains(&rustls019::SignatureScheme::ECDSA_NISTP256_SHA256) {
+            Some(Box::new(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> rustls019::internal::msgs::enums::SignatureAlgorithm {
+        rustls019::internal::msgs::enums::SignatureAlgorithm::ECDSA
+    }
+}
+
+impl rustls019::sign::Signer for BackendSigningKey {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls019::TLSError> {
+        BackendSigningKey::sign(self, message).map_err(rustls019::TLSError::General)
+    }
+
+    fn get_scheme(&self) -> rustls019::SignatureScheme {
+        rustls019::SignatureScheme::ECDSA_NISTP256_SHA256
+    }
+}
+
+struct BackendClientCert(rustls019::sign::CertifiedKey);
+
+impl rustls019::ResolvesClientCert for BackendClientCert {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[rustls019::SignatureScheme],
+    ) -> Option<rustls019::sign::CertifiedKey> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+// The root certificate of a registration may come with intermediate CAs, when the site
+// issues its certificates with one, and all of them are trusted
+pub fn root_certs(root_cert: &str) -> AnyhowResult<Vec<X509>> {
+    let certs = X509::stack_from_pem(root_cert.as_bytes())?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found"));
+    }
+    Ok(certs)
+}
+
+pub fn client(
+    root_cert: Option<Vec<u8>>,
+    client_cert: Option<&ClientCert>,
+    options: &ClientOptions,
+) -> AnyhowResult<Client> {
+    // Idle connections are kept for the next push, unless the receiver closes them earlier
+    let client_builder = ClientBuilder::new()
+        .connect_timeout(options.connect_timeout)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE);
+
+    // native-tls can neither restrict the cipher suites nor log session keys, so crypto_policy
+    // fips and tls_key_log need rustls as well, and so do keys which never leave their backend.
+    // rustls 0.19 always offers X25519 though, which the agent receiver may pick.
+    let backend_key =
+        client_cert.is_some_and(|client_cert| pkcs11::is_uri(client_cert.private_key.expose()));
+    if options.receiver_cert_fingerprint.is_some()
+        || options.fips
+        || options.tls_key_log
+        || backend_key
+    {
+        let mut tls_config = rustls019::ClientConfig::new();
+        // reqwest only offers HTTP/2 itself for TLS it configures
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        if options.tls_key_log {
+            tls_config.key_log = Arc::new(rustls019::KeyLogFile::new());
+        }
+        if options.fips {
+            tls_config
+                .ciphersuites
+                .retain(|suite| fips::is_approved_cipher_suite(&format!("{:?}", suite.suite)));
+        }
+        if let Some(client_cert) = client_cert {
+            rustls_client_cert(&mut tls_config, client_cert)?;
+        }
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(ReceiverCertVerifier {
+                fingerprint: options.receiver_cert_fingerprint.clone(),
+                tls_verify: options.tls_verify,
+                verify_hostname: options.verify_hostname,
+                root_certs: match root_cert {
+                    Some(cert) => root_certs(&String::from_utf8(cert)?)?,
+                    None => vec![],
+                },
+            }));
+        return with_proxy(client_builder.use_preconfigured_tls(tls_config), options);
+    }
+
+    // Only the root certificate of the site is trusted. Older sites do not issue their
+    // certificates for the host name of the agent receiver, which is why checking it can be
+    // turned off. Publicly trusted certificates, e.g. of a reverse proxy, are verified as usual.
+    let accept_invalid_hostnames = !options.verify_hostname;
+    let client_builder = match (options.tls_verify, root_cert) {
+        (config::TlsVerify::Site, Some(cert)) => {
+            let mut client_builder = client_builder
+                .tls_built_in_root_certs(false)
+                .danger_accept_invalid_hostnames(accept_invalid_hostnames);
+            for cert in root_certs(&String::from_utf8(cert)?)? {
+                client_builder =
+                    client_builder.add_root_certificate(Certificate::from_der(&cert.to_der()?)?);
+            }
+            client_builder
+        }
+        (config::TlsVerify::Site, None) => {
+            client_builder.danger_accept_invalid_hostnames(accept_invalid_hostnames)
+        }
+        (config::TlsVerify::System, _) => client_builder,
+    };
+    let client_builder = match client_cert {
+        Some(client_cert) => client_builder.identity(native_tls_identity(client_cert)?),
+        None => client_builder,
+    };
 
-    let client_builder = if let Some(cert) = root_cert {
-        client_builder.add_root_certificate(Certificate::from_pem(&cert)?)
+    with_proxy(client_builder, options)
+}
+
+fn with_proxy(client_builder: ClientBuilder, options: &ClientOptions) -> AnyhowResult<Client> {
+    // Credentials in the URL are used for basic authentication with the proxy
+    let client_builder = if let Some(proxy_url) = &options.proxy_url {
+        client_builder.proxy(Proxy::all(proxy::parse(proxy_url)?)?)
     } else {
         client_builder
     };
 
-    Ok(client_builder
-        .danger_accept_invalid_hostnames(true)
-        .build()?)
+    Ok(client_builder.build()?)
+}
+
+// The certificate of the agent receiver is verified as selected by tls_verify, and has to match
+// receiver_cert_fingerprint if set. reqwest only supports custom verification with its rustls
+// backend, which cannot connect to IP addresses.
+struct ReceiverCertVerifier {
+    fingerprint: Option<String>,
+    tls_verify: config::TlsVerify,
+    verify_hostname: bool,
+    root_certs: Vec<X509>,
 }
 
-pub fn fetch_root_cert(address: &str) -> AnyhowResult<String> {
-    let tcp_stream = TcpStream::connect(address).unwrap();
+impl ReceiverCertVerifier {
+    fn verify(
+        &self,
+        presented_certs: &[rustls019::Certificate],
+        dns_name: webpki021::DNSNameRef,
+    ) -> AnyhowResult<()> {
+        let (end_entity, intermediates) = presented_certs
+            .split_first()
+            .ok_or_else(|| anyhow!("The agent receiver sent no certificate"))?;
+        if let Some(expected) = &self.fingerprint {
+            let fingerprint = der_fingerprint(X509::from_der(&end_entity.0)?.as_ref())?;
+            if normalize_fingerprint(&fingerprint) != normalize_fingerprint(expected) {
+                return Err(anyhow!(
+                    "Certificate fingerprint {} does not match receiver_cert_fingerprint {}",
+                    fingerprint,
+                    expected
+                ));
+            }
+        }
+
+        let mut store = X509StoreBuilder::new()?;
+        match self.tls_verify {
+            config::TlsVerify::Site => {
+                for cert in &self.root_certs {
+                    store.add_cert(cert.clone())?;
+                }
+            }
+            config::TlsVerify::System => store.set_default_paths()?,
+        }
+        // Host names are checked the same way as in client
+        if self.verify_hostname || self.tls_verify == config::TlsVerify::System {
+            webpki021::EndEntityCert::from(&end_entity.0)
+                .and_then(|cert| cert.verify_is_valid_for_dns_name(dns_name))
+                .map_err(|error| anyhow!("Invalid certificate for host name: {:?}", error))?;
+        }
+        let intermediates: Vec<&[u8]> =
+            intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+        verify_chain(&store.build(), &end_entity.0, &intermediates)
+    }
+}
+
+impl rustls019::ServerCertVerifier for ReceiverCertVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls019::RootCertStore,
+        presented_certs: &[rustls019::Certificate],
+        dns_name: webpki021::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls019::ServerCertVerified, rustls019::TLSError> {
+        self.verify(presented_certs, dns_name)
+            .map_err(|error| rustls019::TLSError::General(format!("{:#}", error)))?;
+        Ok(rustls019::ServerCertVerified::assertion())
+    }
+}
+
+pub fn verify_chain(
+    store: &X509Store,
+    end_entity: &[u8],
+    intermediates: &[&[u8]],
+) -> AnyhowResult<()> {
+    let cert = X509::from_der(end_entity)?;
+    let mut chain = Stack::new()?;
+    for intermediate in intermediates {
+        chain.push(X509::from_der(intermediate)?)?;
+    }
+    let mut context = X509StoreContext::new()?;
+    let (verified, error) = context.init(store, &cert, &chain, |context| {
+        Ok((context.verify_cert()?, context.error()))
+    })?;
+    if !verified {
+        return Err(anyhow!("{}", error.error_string()));
+    }
+    Ok(())
+}
+
+pub fn fetch_root_cert(address: &str, options: &ClientOptions) -> AnyhowResult<String> {
+    let tcp_stream = match &options.proxy_url {
+        Some(proxy_url) => proxy::tunnel(proxy_url, address)?,
+        None => TcpStream::connect(address)?,
+    };
     let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls())?;
     ssl_connector_builder.set_verify(SslVerifyMode::NONE);
+    if options.fips {
+        fips::restrict_openssl(&mut ssl_connector_builder)?;
+    }
+    if options.tls_key_log {
+        log_tls_keys(&mut ssl_connector_builder);
+    }
     let mut ssl_stream = ssl_connector_builder.build().connect("dummy", tcp_stream)?;
 
     let root_cert = ssl_stream
@@ -64,3 +621,428 @@ pub fn fetch_root_cert(address: &str) -> AnyhowResult<String> {
 
     Ok(String::from_utf8(root_cert)?)
 }
+
+// Appends in the NSS key log format, like KeyLogFile of rustls does for the other connections
+pub fn log_tls_keys(builder: &mut SslContextBuilder) {
+    if let Some(path) = env::var_os(KEY_LOG_FILE_VAR) {
+        builder.set_keylog_callback(move |_, line| {
+            if let Ok(mut file) = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(&path)
+            {
+                let _ = writeln!(file, "{}", line);
+            }
+        });
+    }
+}
+
+pub fn fingerprint(cert: &str) -> AnyhowResult<String> {
+    der_fingerprint(X509::from_pem(cert.as_bytes())?.as_ref())
+}
+
+pub fn der_fingerprint(cert: &X509Ref) -> AnyhowResult<String> {
+    Ok(cert
+        .digest(MessageDigest::sha256())?
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":"))
+}
+
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_uppercase()
+}
+
+pub fn subject(cert: &str) -> AnyhowResult<String> {
+    let cert = X509::from_pem(cert.as_bytes())?;
+    let mut entries = vec![];
+    for entry in cert.subject_name().entries() {
+        entries.push(format!(
+            "{}={}",
+            entry.object().nid().short_name()?,
+            entry.data().as_utf8()?
+        ));
+    }
+    Ok(entries.join(", "))
+}
+
+pub fn common_name(cert: &str) -> AnyhowResult<String> {
+    let cert = X509::from_pem(cert.as_bytes())?;
+    let entry = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .ok_or_else(|| anyhow!("Certificate has no common name"))?;
+    Ok(entry.data().as_utf8()?.to_string())
+}
+
+// Protects the state against certificates which were not issued for the submitted CSR
+pub fn check_certificate(certificate: &str, private_key: &Secret, uuid: &str) -> AnyhowResult<()> {
+    let cert = X509::from_pem(certificate.as_bytes())?;
+    if !cert
+        .public_key()?
+        .public_eq(key_backend(private_key)?.public_key()?.as_ref())
+    {
+        return Err(anyhow!(
+            "Certificate does not belong to the generated private key"
+        ));
+    }
+    let common_name = common_name(certificate)?;
+    if common_name != uuid {
+        return Err(anyhow!(
+            "Certificate was issued for {}, but the UUID is {}",
+            common_name,
+            uuid
+        ));
+    }
+    Ok(())
+}
+
+pub fn sign_payload(private_key: &Secret, payload: &[u8]) -> AnyhowResult<Vec<u8>> {
+    key_backend(private_key)?.sign(payload)
+}
+
+// Checkmk site CAs are named "Site 'mysite' local CA"
+pub fn site_name(root_cert: &str) -> Option<String> {
+    let common_name = common_name(root_cert).ok()?;
+    let site = common_name
+        .strip_prefix("Site '")?
+        .strip_suffix("' local CA")?;
+    Some(String::from(site))
+}
+
+pub struct Validity {
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+fn unix_timestamp(time: &Asn1TimeRef) -> AnyhowResult<i64> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    Ok(i64::from(diff.days) * 86400 + i64::from(diff.secs))
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    match Asn1Time::from_unix(timestamp as _) {
+        Ok(time) => time.to_string(),
+        Err(_) => timestamp.to_string(),
+    }
+}
+
+// A threshold of 0 disables the warning
+pub fn expires_within(not_after: i64, now: i64, threshold: u64) -> bool {
+    threshold > 0 && not_after - now < threshold as i64
+}
+
+pub fn validity(cert: &str) -> AnyhowResult<Validity> {
+    let cert = X509::from_pem(cert.as_bytes())?;
+    Ok(Validity {
+        not_before: unix_timestamp(cert.not_before())?,
+        not_after: unix_timestamp(cert.not_after())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn test_make_csr() {
+        for key_algorithm in [
+            config::KeyAlgorithm::Rsa,
+            config::KeyAlgorithm::EcdsaP256,
+            config::KeyAlgorithm::Ed25519,
+        ] {
+            let key_spec = KeySpec {
+                algorithm: key_algorithm,
+                rsa_key_size: 2048,
+                fips: false,
+                pkcs11_token: None,
+            };
+            let (csr, private_key) =
+                make_csr("uuid", &key_spec, &CsrAttributes::default()).unwrap();
+            let csr = X509Req::from_pem(csr.as_bytes()).unwrap();
+            assert!(csr.verify(&csr.public_key().unwrap()).unwrap());
+            let private_key = PKey::private_key_from_pem(private_key.expose().as_bytes()).unwrap();
+            assert!(private_key.public_eq(&csr.public_key().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_csr_attributes() {
+        let key_spec = KeySpec {
+            algorithm: config::KeyAlgorithm::EcdsaP256,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let csr_attributes = CsrAttributes {
+            host_name: Some(String::from("myhost")),
+            attributes: vec![String::from("datacenter = fra1")],
+        };
+        let (csr, _) = make_csr("uuid", &key_spec, &csr_attributes).unwrap();
+        let csr = X509Req::from_pem(csr.as_bytes()).unwrap();
+        let units: Vec<String> = csr
+            .subject_name()
+            .entries_by_nid(Nid::ORGANIZATIONALUNITNAME)
+            .map(|entry| entry.data().as_utf8().unwrap().to_string())
+            .collect();
+        assert_eq!(units, vec!["datacenter=fra1"]);
+        assert_eq!(csr.extensions().unwrap().len(), 1);
+
+        let invalid = CsrAttributes {
+            host_name: None,
+            attributes: vec![String::from("=fra1")],
+        };
+        assert!(make_csr("uuid", &key_spec, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_make_self_signed() {
+        let key_spec = KeySpec {
+            algorithm: config::KeyAlgorithm::EcdsaP256,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let (certificate, private_key) = make_self_signed("uuid", &key_spec).unwrap();
+        assert!(check_certificate(&certificate, &private_key, "uuid").is_ok());
+    }
+
+    #[test]
+    fn test_client_cert() {
+        for algorithm in [
+            config::KeyAlgorithm::Rsa,
+            config::KeyAlgorithm::EcdsaP256,
+            config::KeyAlgorithm::Ed25519,
+        ] {
+            let key_spec = KeySpec {
+                algorithm,
+                rsa_key_size: 2048,
+                fips: false,
+                pkcs11_token: None,
+            };
+            let (certificate, private_key) = make_self_signed("uuid", &key_spec).unwrap();
+            let client_cert = ClientCert {
+                certificate: &certificate,
+                private_key: &private_key,
+            };
+            assert!(native_tls_identity(&client_cert).is_ok());
+            assert!(rustls_client_cert(&mut rustls019::ClientConfig::new(), &client_cert).is_ok());
+        }
+    }
+
+    fn client_options(receiver_cert_fingerprint: Option<String>) -> ClientOptions {
+        ClientOptions {
+            proxy_url: None,
+            tls_verify: config::TlsVerify::Site,
+            verify_hostname: false,
+            receiver_cert_fingerprint,
+            fips: false,
+            tls_key_log: false,
+            connect_timeout: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(1),
+            retries: 0,
+            retry_backoff: Duration::from_secs(1),
+            http_headers: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rustls_client() {
+        // reqwest refuses a rustls configuration of another version than its own
+        let key_spec = KeySpec {
+            algorithm: config::KeyAlgorithm::EcdsaP256,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let (certificate, private_key) = make_self_signed("uuid", &key_spec).unwrap();
+        let client_cert = ClientCert {
+            certificate: &certificate,
+            private_key: &private_key,
+        };
+        assert!(client(
+            Some(certificate.clone().into_bytes()),
+            Some(&client_cert),
+            &client_options(Some(String::from("00:11"))),
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_receiver_cert_fingerprint() {
+        let key_spec = KeySpec {
+            algorithm: config::KeyAlgorithm::EcdsaP256,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let (certificate, _) = make_self_signed("uuid", &key_spec).unwrap();
+        let cert = X509::from_pem(certificate.as_bytes()).unwrap();
+        let fingerprint = der_fingerprint(&cert).unwrap();
+        let verifier = |fingerprint: &str| ReceiverCertVerifier {
+            fingerprint: Some(String::from(fingerprint)),
+            tls_verify: config::TlsVerify::Site,
+            verify_hostname: false,
+            root_certs: vec![cert.clone()],
+        };
+        let presented = [rustls019::Certificate(cert.to_der().unwrap())];
+        let dns_name = webpki021::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        assert!(verifier(&fingerprint.to_lowercase())
+            .verify(&presented, dns_name)
+            .is_ok());
+        let error = verifier("00:11:22")
+            .verify(&presented, dns_name)
+            .unwrap_err();
+        assert!(format!("{}", error).contains("does not match"));
+    }
+
+    #[test]
+    fn test_sign_payload() {
+        for algorithm in [
+            config::KeyAlgorithm::Rsa,
+            config::KeyAlgorithm::EcdsaP256,
+            config::KeyAlgorithm::Ed25519,
+        ] {
+            let key_spec = KeySpec {
+                algorithm,
+                rsa_key_size: 2048,
+                fips: false,
+                pkcs11_token: None,
+            };
+            let (certificate, private_key) = make_self_signed("uuid", &key_spec).unwrap();
+            let signature = sign_payload(&private_key, b"<<<check_mk>>>").unwrap();
+            let public_key = X509::from_pem(certificate.as_bytes())
+                .unwrap()
+                .public_key()
+                .unwrap();
+            let mut verifier = match public_key.id() {
+                Id::ED25519 => Verifier::new_without_digest(&public_key).unwrap(),
+                _ => Verifier::new(MessageDigest::sha256(), &public_key).unwrap(),
+            };
+            assert!(verifier
+                .verify_oneshot(&signature, b"<<<check_mk>>>")
+                .unwrap());
+        }
+    }
+
+    fn sign(csr: &str, private_key: &str) -> String {
+        let csr = X509Req::from_pem(csr.as_bytes()).unwrap();
+        let private_key = PKey::private_key_from_pem(private_key.as_bytes()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(csr.subject_name()).unwrap();
+        builder.set_issuer_name(csr.subject_name()).unwrap();
+        builder.set_pubkey(&csr.public_key().unwrap()).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&private_key, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_check_certificate() {
+        let key_spec = KeySpec {
+            algorithm: config::KeyAlgorithm::EcdsaP256,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let (csr, private_key) = make_csr("uuid", &key_spec, &CsrAttributes::default()).unwrap();
+        let (_, other_private_key) =
+            make_csr("uuid", &key_spec, &CsrAttributes::default()).unwrap();
+        let certificate = sign(&csr, private_key.expose());
+        assert!(check_certificate(&certificate, &private_key, "uuid").is_ok());
+        assert!(check_certificate(&certificate, &other_private_key, "uuid").is_err());
+        assert!(check_certificate(&certificate, &private_key, "other-uuid").is_err());
+    }
+
+    // Like a key in a token, which OpenSSL cannot use itself
+    struct Unexportable(PemKey);
+
+    impl KeyBackend for Unexportable {
+        fn public_key(&self) -> AnyhowResult<PKey<Public>> {
+            self.0.public_key()
+        }
+
+        fn sign(&self, data: &[u8]) -> AnyhowResult<Vec<u8>> {
+            self.0.sign(data)
+        }
+    }
+
+    #[test]
+    fn test_sign_with_backend() {
+        for algorithm in [
+            config::KeyAlgorithm::Rsa,
+            config::KeyAlgorithm::EcdsaP256,
+            config::KeyAlgorithm::Ed25519,
+        ] {
+            let key_spec = KeySpec {
+                algorithm,
+                rsa_key_size: 2048,
+                fips: false,
+                pkcs11_token: None,
+            };
+            let key = Unexportable(PemKey(generate_key(&key_spec).unwrap()));
+            let mut builder = X509Req::builder().unwrap();
+            let mut name = X509Name::builder().unwrap();
+            name.append_entry_by_nid(Nid::COMMONNAME, "uuid").unwrap();
+            builder.set_subject_name(&name.build()).unwrap();
+            builder.set_pubkey(&key.public_key().unwrap()).unwrap();
+            let csr = X509Req::from_pem(&sign_with_backend(builder, &key).unwrap()).unwrap();
+            assert!(csr.verify(&key.public_key().unwrap()).unwrap());
+            let other = PemKey(generate_key(&key_spec).unwrap());
+            assert!(!csr.verify(&other.public_key().unwrap()).unwrap_or(false));
+        }
+    }
+
+    #[test]
+    fn test_der() {
+        for content in [vec![1; 3], vec![2; 200], vec![3; 70000]] {
+            let der = [der_encode(0x04, &content), vec![5]].concat();
+            let (tag, decoded, rest) = der_split(&der).unwrap();
+            assert_eq!((tag, decoded, rest), (0x04, content.as_slice(), &[5][..]));
+        }
+        assert!(der_split(&[0x04, 0x05, 0x00]).is_err());
+        assert!(der_split(&[0x04, 0x85, 0, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_backend_signing_key() {
+        let key_spec = |algorithm| KeySpec {
+            algorithm,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let key = PemKey(generate_key(&key_spec(config::KeyAlgorithm::EcdsaP256)).unwrap());
+        let public_key = key.public_key().unwrap();
+        let signing_key = BackendSigningKey::new(Arc::new(Unexportable(key))).unwrap();
+        let signature = signing_key.sign(b"handshake").unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+        assert!(verifier.verify_oneshot(&signature, b"handshake").unwrap());
+        let ed25519 = PemKey(generate_key(&key_spec(config::KeyAlgorithm::Ed25519)).unwrap());
+        assert!(BackendSigningKey::new(Arc::new(Unexportable(ed25519))).is_err());
+    }
+
+    #[test]
+    fn test_rsa_key_size() {
+        let key_spec = |rsa_key_size| KeySpec {
+            algorithm: config::KeyAlgorithm::Rsa,
+            rsa_key_size,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let (_, private_key) =
+            make_csr("uuid", &key_spec(3072), &CsrAttributes::default()).unwrap();
+        let private_key = PKey::private_key_from_pem(private_key.expose().as_bytes()).unwrap();
+        assert_eq!(private_key.bits(), 3072);
+        assert!(make_csr("uuid", &key_spec(1024), &CsrAttributes::default()).is_err());
+    }
+}
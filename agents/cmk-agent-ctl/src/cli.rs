@@ -2,26 +2,719 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+use std::path::PathBuf;
+use structopt::clap::Shell;
 use structopt::StructOpt;
+use uuid::Uuid;
 
 #[derive(StructOpt)]
-#[structopt(name = "cmk-agent-ctl", about = "Checkmk agent controller.")]
-pub struct Args {
-    #[structopt(help = "Execution mode, should be one of 'register', 'push', 'dump', 'status'")]
-    pub mode: String,
-
-    #[structopt(long, short = "s", parse(from_str))]
+pub struct ServerArgs {
+    #[structopt(
+        long,
+        short = "s",
+        help = "Address of the agent receiver as host:port, the port defaults to 8000, or the absolute path of its unix socket on the same host",
+        parse(from_str)
+    )]
     pub server: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct CredentialsArgs {
+    #[structopt(long, short = "u", parse(from_str))]
+    pub user: Option<String>,
+
+    #[structopt(
+        long,
+        short = "p",
+        requires = "user",
+        help = "Password, prefer --password-stdin or --password-file to keep it out of the process list",
+        parse(from_str)
+    )]
+    pub password: Option<String>,
+
+    #[structopt(
+        long,
+        requires = "user",
+        conflicts_with_all = &["password", "password-file"],
+        help = "Read the password from the first line of stdin"
+    )]
+    pub password_stdin: bool,
+
+    #[structopt(
+        long,
+        requires = "user",
+        conflicts_with = "password",
+        help = "Read the password from a file",
+        parse(from_os_str)
+    )]
+    pub password_file: Option<PathBuf>,
+}
 
+#[derive(StructOpt)]
+pub struct CollectionArgs {
     #[structopt(long, parse(from_str))]
     pub package_name: Option<String>,
 
-    #[structopt(long, short = "u", requires = "password", parse(from_str))]
-    pub user: Option<String>,
+    #[structopt(
+        long,
+        help = "Unix socket of the agent, defaults to /run/<package name>.socket"
+    )]
+    pub agent_socket: Option<String>,
 
-    #[structopt(long, short = "p", requires = "user", parse(from_str))]
-    pub password: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with = "agent-socket",
+        help = "Fetch the agent output from this local TCP port instead of the unix socket"
+    )]
+    pub agent_port: Option<u16>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["agent-socket", "agent-port"],
+        help = "Fetch the agent output from tcp://host:port on this host, e.g. from a legacy agent on tcp://127.0.0.1:6556, or from unix:///path"
+    )]
+    pub data_source: Option<String>,
+
+    #[structopt(
+        long = "section",
+        help = "Only output the given section, may be repeated"
+    )]
+    pub sections: Vec<String>,
+
+    #[structopt(
+        long = "exclude-section",
+        help = "Omit the given section, may be repeated"
+    )]
+    pub exclude_sections: Vec<String>,
+}
+
+#[derive(StructOpt)]
+pub struct TrustArgs {
+    #[structopt(
+        long,
+        help = "Trust the root certificate presented by the agent receiver without confirmation"
+    )]
+    pub trust_cert: bool,
+
+    #[structopt(
+        long,
+        help = "Only trust the agent receiver if its root certificate has this SHA256 fingerprint",
+        conflicts_with = "trust-cert"
+    )]
+    pub fingerprint: Option<String>,
+
+    #[structopt(
+        long,
+        help = "Accept a root certificate which differs from the one trusted for this address before"
+    )]
+    pub retrust: bool,
+}
+
+#[derive(StructOpt)]
+pub struct RegisterArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(flatten)]
+    pub credentials: CredentialsArgs,
 
     #[structopt(long, short = "h", parse(from_str))]
     pub host_name: Option<String>,
+
+    #[structopt(
+        long = "failover-address",
+        help = "Address of a further agent receiver of the same site, tried if the others are unreachable, may be repeated"
+    )]
+    pub failover_addresses: Vec<String>,
+
+    #[structopt(flatten)]
+    pub trust: TrustArgs,
+
+    #[structopt(
+        long,
+        help = "Register with this UUID instead of a new one, e.g. to keep the identity after a reinstallation",
+        parse(try_from_str = Uuid::parse_str)
+    )]
+    pub uuid: Option<Uuid>,
+
+    #[structopt(
+        long,
+        help = "Only pair with the agent receiver, without registering the host or writing state"
+    )]
+    pub dry_run: bool,
+
+    #[structopt(
+        long,
+        help = "Wait until the site accepts or declines the registration, for sites approving new hosts manually",
+        conflicts_with = "dry-run"
+    )]
+    pub wait: bool,
+
+    #[structopt(
+        long,
+        help = "Seconds to wait for the site, defaults to an hour",
+        requires = "wait"
+    )]
+    pub wait_timeout: Option<u64>,
+}
+
+#[derive(StructOpt)]
+pub struct RegisterNewArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(flatten)]
+    pub credentials: CredentialsArgs,
+
+    #[structopt(long, short = "h", parse(from_str))]
+    pub host_name: Option<String>,
+
+    #[structopt(
+        long = "failover-address",
+        help = "Address of a further agent receiver of the same site, tried if the others are unreachable, may be repeated"
+    )]
+    pub failover_addresses: Vec<String>,
+
+    #[structopt(flatten)]
+    pub trust: TrustArgs,
+
+    #[structopt(
+        long,
+        help = "Register with this UUID instead of a new one, e.g. to keep the identity after a reinstallation",
+        parse(try_from_str = Uuid::parse_str)
+    )]
+    pub uuid: Option<Uuid>,
+}
+
+#[derive(StructOpt)]
+pub struct RenewCertificateArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(flatten)]
+    pub credentials: CredentialsArgs,
+}
+
+#[derive(StructOpt)]
+pub struct RotateKeyArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(flatten)]
+    pub credentials: CredentialsArgs,
+
+    #[structopt(
+        long,
+        help = "Go back to the key pair in use before the rotation, if the site does not accept the new one"
+    )]
+    pub revert: bool,
+}
+
+#[derive(StructOpt)]
+pub struct ImportArgs {
+    #[structopt(
+        long,
+        help = "File to read a registration bundle or exported state from, defaults to stdin",
+        parse(from_os_str)
+    )]
+    pub file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "File containing the passphrase for decrypting the input",
+        parse(from_os_str)
+    )]
+    pub passphrase_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct InitArgs {
+    #[structopt(
+        long,
+        help = "File to write the configuration to, defaults to the configuration file in the home directory",
+        parse(from_os_str)
+    )]
+    pub file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["file", "force"],
+        help = "Print the configuration to stdout instead"
+    )]
+    pub stdout: bool,
+
+    #[structopt(
+        long,
+        help = "Write JSON, which cannot contain comments, instead of TOML"
+    )]
+    pub json: bool,
+
+    #[structopt(long, help = "Overwrite an existing configuration file")]
+    pub force: bool,
+}
+
+#[derive(StructOpt)]
+pub struct ExportArgs {
+    #[structopt(
+        long,
+        help = "File to write the exported state to, defaults to stdout",
+        parse(from_os_str)
+    )]
+    pub file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "File containing the passphrase for encrypting the output",
+        parse(from_os_str)
+    )]
+    pub passphrase_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct ExportCredentialsArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(
+        long,
+        help = "Directory to write cert.pem, key.pem and root-cert.pem to, defaults to the current directory",
+        parse(from_os_str)
+    )]
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct CsrExportArgs {
+    #[structopt(
+        long,
+        help = "Use this UUID instead of a new one",
+        parse(try_from_str = Uuid::parse_str)
+    )]
+    pub uuid: Option<Uuid>,
+
+    #[structopt(
+        long,
+        help = "File to write the CSR to, defaults to stdout",
+        parse(from_os_str)
+    )]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct CertImportArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(
+        long,
+        help = "File to read the signed certificate from, defaults to stdin",
+        parse(from_os_str)
+    )]
+    pub file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "File containing the root certificate of the agent receiver",
+        parse(from_os_str)
+    )]
+    pub root_cert: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct RestoreStateArgs {
+    #[structopt(long, help = "List the available backups instead of restoring")]
+    pub list: bool,
+
+    #[structopt(
+        long,
+        help = "Backup to restore, defaults to the newest one",
+        conflicts_with = "list",
+        parse(from_os_str)
+    )]
+    pub backup: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct DeleteArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+
+    #[structopt(flatten)]
+    pub credentials: CredentialsArgs,
+
+    #[structopt(
+        long,
+        help = "Only delete the local registration, without deregistering at the agent receiver"
+    )]
+    pub local_only: bool,
+}
+
+#[derive(StructOpt)]
+pub struct PruneArgs {
+    #[structopt(long, help = "Remove stale registrations without asking")]
+    pub yes: bool,
+}
+
+#[derive(StructOpt)]
+pub struct PushArgs {
+    #[structopt(flatten)]
+    pub collection: CollectionArgs,
+
+    #[structopt(
+        long,
+        help = "Push once and exit (default)",
+        conflicts_with = "push-loop"
+    )]
+    pub once: bool,
+
+    #[structopt(long = "loop", help = "Push periodically")]
+    pub push_loop: bool,
+
+    #[structopt(
+        long,
+        help = "Stop after this many pushes (loop mode)",
+        requires = "push-loop"
+    )]
+    pub count: Option<u64>,
+
+    #[structopt(long, help = "Interval in seconds between pushes (loop mode)")]
+    pub push_interval: Option<u64>,
+}
+
+#[derive(StructOpt)]
+pub struct PushDaemonArgs {
+    #[structopt(flatten)]
+    pub collection: CollectionArgs,
+
+    #[structopt(long, help = "Interval in seconds between pushes")]
+    pub push_interval: Option<u64>,
+}
+
+#[derive(StructOpt)]
+pub struct PullArgs {
+    #[structopt(flatten)]
+    pub collection: CollectionArgs,
+
+    #[structopt(
+        long,
+        help = "Serve with a temporary self-signed certificate instead of the registrations, and without verifying clients, for testing the TLS transport"
+    )]
+    pub demo: bool,
+}
+
+#[derive(StructOpt)]
+pub struct ListenArgs {
+    #[structopt(
+        long = "listen-address",
+        help = "Only listen on this address instead of all interfaces, may be repeated"
+    )]
+    pub listen_addresses: Vec<String>,
+
+    #[structopt(long, help = "TCP port to listen on, defaults to 6556")]
+    pub listen_port: Option<u16>,
+}
+
+#[derive(StructOpt)]
+pub struct DaemonArgs {
+    #[structopt(flatten)]
+    pub collection: CollectionArgs,
+
+    #[structopt(flatten)]
+    pub listen: ListenArgs,
+
+    #[structopt(
+        long,
+        help = "Serve with a temporary self-signed certificate instead of the registrations, and without verifying clients, for testing the TLS transport"
+    )]
+    pub demo: bool,
+}
+
+#[derive(StructOpt)]
+pub struct DumpArgs {
+    #[structopt(flatten)]
+    pub collection: CollectionArgs,
+}
+
+#[derive(StructOpt)]
+pub struct StatusArgs {
+    #[structopt(long, help = "Print status as JSON")]
+    pub json: bool,
+}
+
+#[derive(StructOpt)]
+pub struct TestConnectionArgs {
+    #[structopt(flatten)]
+    pub server: ServerArgs,
+}
+
+#[derive(StructOpt)]
+pub struct StoreSecretArgs {
+    #[structopt(
+        help = "Which secret to store",
+        possible_values = &["credentials", "key-passphrase"]
+    )]
+    pub name: String,
+}
+
+#[derive(StructOpt)]
+pub struct CompletionsArgs {
+    #[structopt(
+        help = "Shell to generate completions for",
+        possible_values = &Shell::variants(),
+        case_insensitive = true
+    )]
+    pub shell: Shell,
+}
+
+#[derive(StructOpt)]
+pub enum Mode {
+    #[structopt(about = "Register with a Checkmk site")]
+    Register(RegisterArgs),
+
+    #[structopt(about = "Register another host and print the registration bundle")]
+    RegisterNew(RegisterNewArgs),
+
+    #[structopt(about = "Renew the certificate of an existing registration")]
+    RenewCertificate(RenewCertificateArgs),
+
+    #[structopt(
+        about = "Replace the key pair of an existing registration, keeping its UUID. The old pair is kept until the site pulled with the new one."
+    )]
+    RotateKey(RotateKeyArgs),
+
+    #[structopt(about = "Import a registration bundle or exported state")]
+    Import(ImportArgs),
+
+    #[structopt(about = "Export the registration state")]
+    Export(ExportArgs),
+
+    #[structopt(
+        about = "Write certificate, private key and root certificate of a registration as PEM files for other tools"
+    )]
+    ExportCredentials(ExportCredentialsArgs),
+
+    #[structopt(about = "Create a CSR for registering without a connection to the agent receiver")]
+    CsrExport(CsrExportArgs),
+
+    #[structopt(about = "Complete an offline registration with the signed certificate")]
+    CertImport(CertImportArgs),
+
+    #[structopt(about = "Restore the registration state from a backup")]
+    RestoreState(RestoreStateArgs),
+
+    #[structopt(about = "Delete a registration", alias = "deregister")]
+    Delete(DeleteArgs),
+
+    #[structopt(
+        about = "Detect registrations rejected or unreachable for too long, and remove them after confirmation"
+    )]
+    Prune(PruneArgs),
+
+    #[structopt(about = "Push monitoring data to all registered sites")]
+    Push(PushArgs),
+
+    #[structopt(about = "Periodically push monitoring data to all registered sites")]
+    PushDaemon(PushDaemonArgs),
+
+    #[structopt(about = "Serve monitoring data via stdin/stdout")]
+    Pull(PullArgs),
+
+    #[structopt(about = "Serve monitoring data via TCP, on port 6556 by default")]
+    Daemon(DaemonArgs),
+
+    #[structopt(about = "Print monitoring data to stdout")]
+    Dump(DumpArgs),
+
+    #[structopt(about = "Show the registration status")]
+    Status(StatusArgs),
+
+    #[structopt(about = "Test the connection to registered sites")]
+    TestConnection(TestConnectionArgs),
+
+    #[structopt(about = "Check the configuration and registration state for errors")]
+    ValidateConfig,
+
+    #[structopt(
+        about = "Write a commented configuration file with all options and their defaults"
+    )]
+    Init(InitArgs),
+
+    #[structopt(
+        about = "Store the credentials or the key passphrase in the keyring, read from stdin or a prompt"
+    )]
+    StoreSecret(StoreSecretArgs),
+
+    #[structopt(about = "Generate shell completions")]
+    Completions(CompletionsArgs),
+}
+
+impl Mode {
+    pub fn server_args(&self) -> Option<&ServerArgs> {
+        match self {
+            Mode::Register(args) => Some(&args.server),
+            Mode::RegisterNew(args) => Some(&args.server),
+            Mode::RenewCertificate(args) => Some(&args.server),
+            Mode::RotateKey(args) => Some(&args.server),
+            Mode::ExportCredentials(args) => Some(&args.server),
+            Mode::CertImport(args) => Some(&args.server),
+            Mode::Delete(args) => Some(&args.server),
+            Mode::TestConnection(args) => Some(&args.server),
+            _ => None,
+        }
+    }
+
+    pub fn listen_args(&self) -> Option<&ListenArgs> {
+        match self {
+            Mode::Daemon(args) => Some(&args.listen),
+            _ => None,
+        }
+    }
+
+    pub fn credentials_args(&self) -> Option<&CredentialsArgs> {
+        match self {
+            Mode::Register(args) => Some(&args.credentials),
+            Mode::RegisterNew(args) => Some(&args.credentials),
+            Mode::RenewCertificate(args) => Some(&args.credentials),
+            Mode::RotateKey(args) => Some(&args.credentials),
+            Mode::Delete(args) => Some(&args.credentials),
+            _ => None,
+        }
+    }
+
+    pub fn collection_args(&self) -> Option<&CollectionArgs> {
+        match self {
+            Mode::Push(args) => Some(&args.collection),
+            Mode::PushDaemon(args) => Some(&args.collection),
+            Mode::Pull(args) => Some(&args.collection),
+            Mode::Daemon(args) => Some(&args.collection),
+            Mode::Dump(args) => Some(&args.collection),
+            _ => None,
+        }
+    }
+
+    pub fn host_name(&self) -> Option<&String> {
+        match self {
+            Mode::Register(args) => args.host_name.as_ref(),
+            Mode::RegisterNew(args) => args.host_name.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn failover_addresses(&self) -> Option<&Vec<String>> {
+        match self {
+            Mode::Register(args) => Some(&args.failover_addresses),
+            Mode::RegisterNew(args) => Some(&args.failover_addresses),
+            _ => None,
+        }
+    }
+
+    pub fn push_interval(&self) -> Option<u64> {
+        match self {
+            Mode::Push(args) => args.push_interval,
+            Mode::PushDaemon(args) => args.push_interval,
+            _ => None,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct LoggingArgs {
+    #[structopt(
+        long,
+        global = true,
+        help = "Log level",
+        possible_values = &["off", "error", "warn", "info", "debug", "trace"],
+        case_insensitive = true
+    )]
+    pub log_level: Option<String>,
+
+    #[structopt(
+        long,
+        short = "v",
+        global = true,
+        parse(from_occurrences),
+        conflicts_with = "log-level",
+        help = "Log more verbosely, -v for debug and -vv for trace"
+    )]
+    pub verbose: u8,
+
+    #[structopt(
+        long,
+        short = "q",
+        global = true,
+        conflicts_with_all = &["log-level", "verbose"],
+        help = "Only log errors"
+    )]
+    pub quiet: bool,
+}
+
+impl LoggingArgs {
+    pub fn log_level(&self) -> Option<String> {
+        match (&self.log_level, self.verbose, self.quiet) {
+            (Some(log_level), _, _) => Some(log_level.to_lowercase()),
+            (None, 0, true) => Some(String::from("error")),
+            (None, 0, false) => None,
+            (None, 1, _) => Some(String::from("debug")),
+            (None, _, _) => Some(String::from("trace")),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct PathArgs {
+    #[structopt(
+        long,
+        global = true,
+        env = "CMK_AGENT_CTL_HOME",
+        help = "Directory for configuration, state and logs",
+        parse(from_os_str)
+    )]
+    pub home_dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "CMK_AGENT_CTL_CONFIG_FILE",
+        help = "Configuration file, defaults to a file in the home directory",
+        parse(from_os_str)
+    )]
+    pub config_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "CMK_AGENT_CTL_STATE_FILE",
+        help = "Registration state file, defaults to a file in the home directory",
+        parse(from_os_str)
+    )]
+    pub state_file: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "CMK_AGENT_CTL_LOG_FILE",
+        help = "Log file, defaults to a file in the home directory",
+        parse(from_os_str)
+    )]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+#[structopt(name = "cmk-agent-ctl", about = "Checkmk agent controller.")]
+pub struct Args {
+    #[structopt(flatten)]
+    pub logging: LoggingArgs,
+
+    #[structopt(flatten)]
+    pub paths: PathArgs,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Print the effective configuration and which layer each value comes from to stderr"
+    )]
+    pub show_origin: bool,
+
+    #[structopt(subcommand)]
+    pub mode: Mode,
 }
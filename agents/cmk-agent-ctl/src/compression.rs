@@ -0,0 +1,152 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::config;
+use std::io::{Read, Result as IoResult};
+use std::ops::RangeInclusive;
+
+const GZIP_LEVELS: RangeInclusive<i32> = 0..=9;
+const GZIP_DEFAULT_LEVEL: i32 = 6;
+const ZSTD_LEVELS: RangeInclusive<i32> = 1..=22;
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+// How pushed agent data is compressed, see push_compression and push_compression_level
+#[derive(Clone, Copy)]
+pub struct Settings {
+    pub algorithm: config::Compression,
+    pub level: Option<i32>,
+}
+
+impl Settings {
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self.algorithm {
+            config::Compression::None => None,
+            config::Compression::Gzip => Some("gzip"),
+            config::Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    // Agent receivers get uncompressed data unless they support the configured compression or
+    // could not tell
+    pub fn supported_by(self, capabilities: Option<&config::Capabilities>) -> Settings {
+        match (self.content_encoding(), capabilities) {
+            (Some(content_encoding), Some(capabilities))
+                if !capabilities
+                    .compression
+                    .iter()
+                    .any(|supported| supported == content_encoding) =>
+            {
+                Settings {
+                    algorithm: config::Compression::None,
+                    level: None,
+                }
+            }
+            _ => self,
+        }
+    }
+
+    // Compresses while the body is read, such that it is never held in memory as a whole
+    pub fn encode<'a>(&self, reader: impl Read + Send + 'a) -> IoResult<Box<dyn Read + Send + 'a>> {
+        Ok(match self.algorithm {
+            config::Compression::None => Box::new(reader),
+            config::Compression::Gzip => {
+                let level = self.level.unwrap_or(GZIP_DEFAULT_LEVEL);
+                Box::new(flate2::read::GzEncoder::new(
+                    reader,
+                    flate2::Compression::new(level.clamp(0, 9) as u32),
+                ))
+            }
+            config::Compression::Zstd => Box::new(zstd::stream::read::Encoder::new(
+                reader,
+                self.level.unwrap_or(ZSTD_DEFAULT_LEVEL),
+            )?),
+        })
+    }
+}
+
+pub fn check_level(algorithm: config::Compression, level: i32) -> Result<(), String> {
+    let levels = match algorithm {
+        config::Compression::None => return Ok(()),
+        config::Compression::Gzip => GZIP_LEVELS,
+        config::Compression::Zstd => ZSTD_LEVELS,
+    };
+    if !levels.contains(&level) {
+        return Err(format!(
+            "Compression level must be between {} and {}",
+            levels.start(),
+            levels.end()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(settings: Settings, data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        settings
+            .encode(data)
+            .unwrap()
+            .read_to_end(&mut compressed)
+            .unwrap();
+        compressed
+    }
+
+    #[test]
+    fn test_compress() {
+        let data = b"<<<check_mk>>>\nVersion: 2.1.0\n".repeat(100);
+
+        let gzip = Settings {
+            algorithm: config::Compression::Gzip,
+            level: None,
+        };
+        let compressed = compress(gzip, &data);
+        assert!(compressed.len() < data.len());
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        let zstd = Settings {
+            algorithm: config::Compression::Zstd,
+            level: Some(19),
+        };
+        let compressed = compress(zstd, &data);
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), data);
+    }
+
+    #[test]
+    fn test_check_level() {
+        assert!(check_level(config::Compression::Gzip, 9).is_ok());
+        assert!(check_level(config::Compression::Gzip, 10).is_err());
+        assert!(check_level(config::Compression::Zstd, 0).is_err());
+        assert!(check_level(config::Compression::None, 100).is_ok());
+    }
+
+    #[test]
+    fn test_supported_by() {
+        let zstd = Settings {
+            algorithm: config::Compression::Zstd,
+            level: Some(19),
+        };
+        let capabilities = |compression: &[&str]| config::Capabilities {
+            compression: compression.iter().map(|c| String::from(*c)).collect(),
+            ..config::Capabilities::default()
+        };
+        assert_eq!(zstd.supported_by(None).content_encoding(), Some("zstd"));
+        assert_eq!(
+            zstd.supported_by(Some(&capabilities(&["gzip", "zstd"])))
+                .content_encoding(),
+            Some("zstd")
+        );
+        assert_eq!(
+            zstd.supported_by(Some(&capabilities(&["gzip"])))
+                .content_encoding(),
+            None
+        );
+    }
+}
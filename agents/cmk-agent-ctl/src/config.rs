@@ -2,98 +2,1705 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use super::cli::Args;
+use super::address;
+use super::cli::{Args, CredentialsArgs};
+use super::crypto;
+use super::error::LoadError;
+use super::secret::{self, Secret};
+use nix::fcntl::{flock, FlockArg};
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::fs::{read_to_string, write};
-use std::io;
-use std::path::Path;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{copy, metadata, read_dir, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize, Deserialize)]
+pub const DEFAULT_ORIGIN: &str = "built-in default";
+pub const DEFAULT_PACKAGE_NAME: &str = "check-mk-agent";
+pub const DEFAULT_PUSH_INTERVAL: u64 = 60;
+pub const DEFAULT_PUSH_TIMEOUT: u64 = 30;
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+pub const DEFAULT_API_RETRIES: u32 = 3;
+pub const DEFAULT_API_RETRY_BACKOFF: u64 = 1;
+pub const DEFAULT_RSA_KEY_SIZE: u32 = 2048;
+pub const DEFAULT_STALE_AFTER: u64 = 7 * 24 * 3600;
+pub const DEFAULT_CERT_EXPIRY_WARNING: u64 = 30 * 24 * 3600;
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0";
+pub const DEFAULT_LISTEN_PORT: u16 = 6556;
+
+// Whether unregistered hosts may still serve plaintext data, see the allow-legacy-pull marker
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LegacyPull {
+    // Allowed as long as the marker exists and there are no registrations
+    Auto,
+    // Never allowed, even if the marker exists, e.g. because it was restored from a backup
+    Never,
+}
+
+// Which certificates the agent receiver is verified against
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsVerify {
+    // The root certificate of the site, which was trusted on registration
+    #[default]
+    Site,
+    // The certificate store of the system, for receivers behind a publicly trusted proxy
+    System,
+}
+
+impl FromStr for TlsVerify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TlsVerify, String> {
+        match s {
+            "site" => Ok(TlsVerify::Site),
+            "system" => Ok(TlsVerify::System),
+            _ => Err(format!("Invalid TLS verification {}", s)),
+        }
+    }
+}
+
+// Lowest TLS version the pull server accepts
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TlsVersion, String> {
+        match s {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("Invalid TLS version {}", s)),
+        }
+    }
+}
+
+// Which algorithms keys, certificates and TLS connections may use
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CryptoPolicy {
+    Default,
+    // Only FIPS approved algorithms, see the fips module
+    Fips,
+}
+
+impl FromStr for CryptoPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CryptoPolicy, String> {
+        match s {
+            "default" => Ok(CryptoPolicy::Default),
+            "fips" => Ok(CryptoPolicy::Fips),
+            _ => Err(format!("Invalid crypto policy {}", s)),
+        }
+    }
+}
+
+// How pushed monitoring data is compressed
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Invalid compression {}", s)),
+        }
+    }
+}
+
+// What happens to pushes coming sooner than min_push_interval after the last one
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PushThrottle {
+    Refuse,
+    Delay,
+}
+
+impl FromStr for PushThrottle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PushThrottle, String> {
+        match s {
+            "refuse" => Ok(PushThrottle::Refuse),
+            "delay" => Ok(PushThrottle::Delay),
+            _ => Err(format!("Invalid push throttle {}", s)),
+        }
+    }
+}
+
+// Type of the private keys generated for registrations
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAlgorithm {
+    Rsa,
+    EcdsaP256,
+    Ed25519,
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<KeyAlgorithm, String> {
+        match s {
+            "rsa" => Ok(KeyAlgorithm::Rsa),
+            "ecdsa_p256" => Ok(KeyAlgorithm::EcdsaP256),
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            _ => Err(format!("Invalid key algorithm {}", s)),
+        }
+    }
+}
+
+impl FromStr for LegacyPull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LegacyPull, String> {
+        match s {
+            "auto" => Ok(LegacyPull::Auto),
+            "never" => Ok(LegacyPull::Never),
+            _ => Err(format!("Invalid legacy pull setting {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     #[serde(default)]
     pub agent_receiver_address: Option<String>,
 
+    #[serde(default)]
+    pub failover_addresses: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub proxy_url: Option<String>,
+
+    #[serde(default)]
+    pub tls_verify: Option<TlsVerify>,
+
+    #[serde(default)]
+    pub tls_verify_hostname: Option<bool>,
+
+    #[serde(default)]
+    pub receiver_cert_fingerprint: Option<String>,
+
     #[serde(default)]
     pub package_name: Option<String>,
 
     #[serde(default)]
-    pub credentials: Option<String>,
+    pub agent_socket: Option<String>,
+
+    #[serde(default)]
+    pub agent_port: Option<u16>,
+
+    #[serde(default)]
+    pub data_source: Option<String>,
+
+    #[serde(default)]
+    pub credentials: Option<Secret>,
 
     #[serde(default)]
     pub root_certificate: Option<String>,
 
+    #[serde(default)]
+    pub root_certificate_path: Option<String>,
+
     #[serde(default)]
     pub host_name: Option<String>,
+
+    #[serde(default)]
+    pub key_algorithm: Option<KeyAlgorithm>,
+
+    #[serde(default)]
+    pub rsa_key_size: Option<u32>,
+
+    #[serde(default)]
+    pub pkcs11_token: Option<String>,
+
+    #[serde(default)]
+    pub csr_attributes: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub push_interval: Option<u64>,
+
+    #[serde(default)]
+    pub push_jitter: Option<u64>,
+
+    #[serde(default)]
+    pub push_timeout: Option<u64>,
+
+    #[serde(default)]
+    pub push_rate_limit: Option<u64>,
+
+    #[serde(default)]
+    pub push_compression: Option<Compression>,
+
+    #[serde(default)]
+    pub push_compression_level: Option<i32>,
+
+    #[serde(default)]
+    pub min_push_interval: Option<u64>,
+
+    #[serde(default)]
+    pub push_throttle: Option<PushThrottle>,
+
+    #[serde(default)]
+    pub push_spool_size: Option<u32>,
+
+    #[serde(default)]
+    pub push_signature: Option<bool>,
+
+    #[serde(default)]
+    pub push_encryption_key_file: Option<String>,
+
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+
+    #[serde(default)]
+    pub request_timeout: Option<u64>,
+
+    #[serde(default)]
+    pub api_retries: Option<u32>,
+
+    #[serde(default)]
+    pub api_retry_backoff: Option<u64>,
+
+    #[serde(default)]
+    pub http_headers: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub stale_after: Option<u64>,
+
+    #[serde(default)]
+    pub mark_stale_on_push: Option<bool>,
+
+    #[serde(default)]
+    pub cert_expiry_warning: Option<u64>,
+
+    #[serde(default)]
+    pub renew_before: Option<u64>,
+
+    #[serde(default)]
+    pub sections: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub exclude_sections: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub listen_addresses: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub listen_port: Option<u16>,
+
+    #[serde(default)]
+    pub only_from: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub legacy_pull: Option<LegacyPull>,
+
+    #[serde(default)]
+    pub crl_dir: Option<String>,
+
+    #[serde(default)]
+    pub tls_min_version: Option<TlsVersion>,
+
+    #[serde(default)]
+    pub tls_cipher_suites: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub tls_session_resumption: Option<bool>,
+
+    #[serde(default)]
+    pub crypto_policy: Option<CryptoPolicy>,
+
+    #[serde(default)]
+    pub tls_key_log: Option<bool>,
+
+    #[serde(default)]
+    pub log_level: Option<String>,
+
+    #[serde(default)]
+    pub key_passphrase_file: Option<String>,
+
+    #[serde(default)]
+    pub use_keyring: Option<bool>,
+}
+
+fn non_empty(values: &[String]) -> Option<Vec<String>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.to_vec())
+    }
+}
+
+fn credentials_from_args(args: &CredentialsArgs) -> io::Result<Option<Secret>> {
+    let user = match &args.user {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let password = if let Some(password) = &args.password {
+        Some(password.clone())
+    } else if args.password_stdin {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        Some(password)
+    } else if let Some(path) = &args.password_file {
+        Some(read_to_string(path)?)
+    } else {
+        None
+    };
+    Ok(password.map(|mut password| {
+        let credentials = format!("{} {}", user, password.trim_end_matches('\n'));
+        secret::wipe(&mut password);
+        Secret::from(credentials)
+    }))
+}
+
+// Files containing private keys must never be left half-written, so write a temporary
+// file next to the target and rename it, which replaces the target atomically.
+pub fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = remove_file(&tmp_path);
+        return result;
+    }
+
+    // Persist the rename itself
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+pub fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "toml")
+}
+
+pub fn drop_in_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut paths: Vec<PathBuf> = read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?
+        .into_iter()
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == "json" || extension == "toml")
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
 }
 
 impl Config {
-    fn empty_config() -> Config {
-        return serde_json::from_str("{}").unwrap();
+    pub fn empty_config() -> Config {
+        serde_json::from_str("{}").unwrap()
     }
 
-    pub fn from_file(path: &Path) -> io::Result<Config> {
-        if path.exists() {
-            return Ok(serde_json::from_str(&read_to_string(path)?)?);
+    pub fn defaults() -> Config {
+        Config {
+            package_name: Some(String::from(DEFAULT_PACKAGE_NAME)),
+            tls_verify: Some(TlsVerify::Site),
+            tls_verify_hostname: Some(true),
+            key_algorithm: Some(KeyAlgorithm::Rsa),
+            rsa_key_size: Some(DEFAULT_RSA_KEY_SIZE),
+            push_interval: Some(DEFAULT_PUSH_INTERVAL),
+            push_timeout: Some(DEFAULT_PUSH_TIMEOUT),
+            push_compression: Some(Compression::None),
+            push_throttle: Some(PushThrottle::Refuse),
+            push_spool_size: Some(0),
+            push_signature: Some(false),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            api_retries: Some(DEFAULT_API_RETRIES),
+            api_retry_backoff: Some(DEFAULT_API_RETRY_BACKOFF),
+            stale_after: Some(DEFAULT_STALE_AFTER),
+            mark_stale_on_push: Some(false),
+            cert_expiry_warning: Some(DEFAULT_CERT_EXPIRY_WARNING),
+            listen_addresses: Some(vec![String::from(DEFAULT_LISTEN_ADDRESS)]),
+            listen_port: Some(DEFAULT_LISTEN_PORT),
+            legacy_pull: Some(LegacyPull::Auto),
+            tls_min_version: Some(TlsVersion::Tls12),
+            tls_session_resumption: Some(true),
+            crypto_policy: Some(CryptoPolicy::Default),
+            tls_key_log: Some(false),
+            log_level: Some(String::from(DEFAULT_LOG_LEVEL)),
+            use_keyring: Some(false),
+            ..Config::empty_config()
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, LoadError> {
+        let serialized = read_to_string(path).map_err(|error| LoadError::from_io(path, error))?;
+        Config::from_str(&serialized, is_toml(path))
+            .map_err(|error| LoadError::Parse(path.to_path_buf(), error.to_string()))?
+            .read_root_certificate(path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    // An inline root certificate takes precedence over one given by path within the same
+    // source. Relative paths are relative to the directory of the configuration file.
+    fn read_root_certificate(mut self, base_dir: &Path) -> Result<Config, LoadError> {
+        if let (None, Some(path)) = (&self.root_certificate, &self.root_certificate_path) {
+            let path = base_dir.join(path);
+            // Not reported as missing, which would count as no configuration at all
+            self.root_certificate =
+                Some(read_to_string(&path).map_err(|error| LoadError::Io(path, error))?);
         }
-        return Ok(Config::empty_config());
+        Ok(self)
+    }
+
+    pub fn field_names() -> Vec<String> {
+        match serde_json::to_value(Config::empty_config()) {
+            Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+            _ => vec![],
+        }
+    }
+
+    // Without a .toml extension, only content not looking like a JSON object is read as TOML
+    fn from_str(serialized: &str, toml: bool) -> io::Result<Config> {
+        if toml || !serialized.trim_start().starts_with('{') {
+            return toml::from_str(serialized)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+        Ok(serde_json::from_str(serialized)?)
     }
 
     pub fn merge_two_configs(loser: Config, winner: Config) -> Config {
-        return Config {
+        Config {
             agent_receiver_address: winner
                 .agent_receiver_address
                 .or(loser.agent_receiver_address),
+            failover_addresses: winner.failover_addresses.or(loser.failover_addresses),
+            proxy_url: winner.proxy_url.or(loser.proxy_url),
+            tls_verify: winner.tls_verify.or(loser.tls_verify),
+            tls_verify_hostname: winner.tls_verify_hostname.or(loser.tls_verify_hostname),
+            receiver_cert_fingerprint: winner
+                .receiver_cert_fingerprint
+                .or(loser.receiver_cert_fingerprint),
             package_name: winner.package_name.or(loser.package_name),
+            agent_socket: winner.agent_socket.or(loser.agent_socket),
+            agent_port: winner.agent_port.or(loser.agent_port),
+            data_source: winner.data_source.or(loser.data_source),
             credentials: winner.credentials.or(loser.credentials),
             root_certificate: winner.root_certificate.or(loser.root_certificate),
+            root_certificate_path: winner.root_certificate_path.or(loser.root_certificate_path),
             host_name: winner.host_name.or(loser.host_name),
+            key_algorithm: winner.key_algorithm.or(loser.key_algorithm),
+            rsa_key_size: winner.rsa_key_size.or(loser.rsa_key_size),
+            pkcs11_token: winner.pkcs11_token.or(loser.pkcs11_token),
+            csr_attributes: winner.csr_attributes.or(loser.csr_attributes),
+            push_interval: winner.push_interval.or(loser.push_interval),
+            push_jitter: winner.push_jitter.or(loser.push_jitter),
+            push_timeout: winner.push_timeout.or(loser.push_timeout),
+            push_rate_limit: winner.push_rate_limit.or(loser.push_rate_limit),
+            push_compression: winner.push_compression.or(loser.push_compression),
+            push_compression_level: winner
+                .push_compression_level
+                .or(loser.push_compression_level),
+            min_push_interval: winner.min_push_interval.or(loser.min_push_interval),
+            push_throttle: winner.push_throttle.or(loser.push_throttle),
+            push_spool_size: winner.push_spool_size.or(loser.push_spool_size),
+            push_signature: winner.push_signature.or(loser.push_signature),
+            push_encryption_key_file: winner
+                .push_encryption_key_file
+                .or(loser.push_encryption_key_file),
+            connect_timeout: winner.connect_timeout.or(loser.connect_timeout),
+            request_timeout: winner.request_timeout.or(loser.request_timeout),
+            api_retries: winner.api_retries.or(loser.api_retries),
+            api_retry_backoff: winner.api_retry_backoff.or(loser.api_retry_backoff),
+            http_headers: winner.http_headers.or(loser.http_headers),
+            stale_after: winner.stale_after.or(loser.stale_after),
+            mark_stale_on_push: winner.mark_stale_on_push.or(loser.mark_stale_on_push),
+            cert_expiry_warning: winner.cert_expiry_warning.or(loser.cert_expiry_warning),
+            renew_before: winner.renew_before.or(loser.renew_before),
+            sections: winner.sections.or(loser.sections),
+            exclude_sections: winner.exclude_sections.or(loser.exclude_sections),
+            listen_addresses: winner.listen_addresses.or(loser.listen_addresses),
+            listen_port: winner.listen_port.or(loser.listen_port),
+            only_from: winner.only_from.or(loser.only_from),
+            legacy_pull: winner.legacy_pull.or(loser.legacy_pull),
+            crl_dir: winner.crl_dir.or(loser.crl_dir),
+            tls_min_version: winner.tls_min_version.or(loser.tls_min_version),
+            tls_cipher_suites: winner.tls_cipher_suites.or(loser.tls_cipher_suites),
+            tls_session_resumption: winner
+                .tls_session_resumption
+                .or(loser.tls_session_resumption),
+            crypto_policy: winner.crypto_policy.or(loser.crypto_policy),
+            tls_key_log: winner.tls_key_log.or(loser.tls_key_log),
+            log_level: winner.log_level.or(loser.log_level),
+            key_passphrase_file: winner.key_passphrase_file.or(loser.key_passphrase_file),
+            use_keyring: winner.use_keyring.or(loser.use_keyring),
+        }
+    }
+
+    pub fn from_env() -> Result<Config, LoadError> {
+        Config::from_vars(|name| env::var(name).ok().filter(|value| !value.is_empty()))?
+            .read_root_certificate(Path::new(""))
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Config, LoadError> {
+        fn parse<T: FromStr>(name: &str, value: Option<String>) -> Result<Option<T>, LoadError> {
+            match value {
+                Some(value) => Ok(Some(value.parse().map_err(|_| {
+                    LoadError::Environment(format!("Invalid {}: {}", name, value))
+                })?)),
+                None => Ok(None),
+            }
+        }
+        let list = |name: &str| {
+            var(name).map(|value| {
+                value
+                    .split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
         };
+        Ok(Config {
+            agent_receiver_address: var("CMK_AGENT_RECEIVER"),
+            failover_addresses: list("CMK_AGENT_FAILOVER_ADDRESSES"),
+            proxy_url: var("CMK_AGENT_PROXY_URL"),
+            tls_verify: parse("CMK_AGENT_TLS_VERIFY", var("CMK_AGENT_TLS_VERIFY"))?,
+            tls_verify_hostname: parse(
+                "CMK_AGENT_TLS_VERIFY_HOSTNAME",
+                var("CMK_AGENT_TLS_VERIFY_HOSTNAME"),
+            )?,
+            receiver_cert_fingerprint: var("CMK_AGENT_RECEIVER_CERT_FINGERPRINT"),
+            package_name: var("CMK_AGENT_PACKAGE_NAME"),
+            agent_socket: var("CMK_AGENT_SOCKET"),
+            agent_port: parse("CMK_AGENT_PORT", var("CMK_AGENT_PORT"))?,
+            data_source: var("CMK_AGENT_DATA_SOURCE"),
+            credentials: match (var("CMK_AGENT_USER"), var("CMK_AGENT_PASSWORD")) {
+                (Some(user), Some(password)) => {
+                    Some(Secret::from(format!("{} {}", user, password)))
+                }
+                _ => None,
+            },
+            root_certificate: var("CMK_AGENT_ROOT_CERTIFICATE"),
+            root_certificate_path: var("CMK_AGENT_ROOT_CERTIFICATE_PATH"),
+            host_name: var("CMK_AGENT_HOSTNAME"),
+            key_algorithm: parse("CMK_AGENT_KEY_ALGORITHM", var("CMK_AGENT_KEY_ALGORITHM"))?,
+            rsa_key_size: parse("CMK_AGENT_RSA_KEY_SIZE", var("CMK_AGENT_RSA_KEY_SIZE"))?,
+            pkcs11_token: var("CMK_AGENT_PKCS11_TOKEN"),
+            csr_attributes: list("CMK_AGENT_CSR_ATTRIBUTES"),
+            push_interval: parse("CMK_AGENT_PUSH_INTERVAL", var("CMK_AGENT_PUSH_INTERVAL"))?,
+            push_jitter: parse("CMK_AGENT_PUSH_JITTER", var("CMK_AGENT_PUSH_JITTER"))?,
+            push_timeout: parse("CMK_AGENT_PUSH_TIMEOUT", var("CMK_AGENT_PUSH_TIMEOUT"))?,
+            push_rate_limit: parse(
+                "CMK_AGENT_PUSH_RATE_LIMIT",
+                var("CMK_AGENT_PUSH_RATE_LIMIT"),
+            )?,
+            push_compression: parse(
+                "CMK_AGENT_PUSH_COMPRESSION",
+                var("CMK_AGENT_PUSH_COMPRESSION"),
+            )?,
+            push_compression_level: parse(
+                "CMK_AGENT_PUSH_COMPRESSION_LEVEL",
+                var("CMK_AGENT_PUSH_COMPRESSION_LEVEL"),
+            )?,
+            min_push_interval: parse(
+                "CMK_AGENT_MIN_PUSH_INTERVAL",
+                var("CMK_AGENT_MIN_PUSH_INTERVAL"),
+            )?,
+            push_throttle: parse("CMK_AGENT_PUSH_THROTTLE", var("CMK_AGENT_PUSH_THROTTLE"))?,
+            push_spool_size: parse(
+                "CMK_AGENT_PUSH_SPOOL_SIZE",
+                var("CMK_AGENT_PUSH_SPOOL_SIZE"),
+            )?,
+            push_signature: parse("CMK_AGENT_PUSH_SIGNATURE", var("CMK_AGENT_PUSH_SIGNATURE"))?,
+            push_encryption_key_file: var("CMK_AGENT_PUSH_ENCRYPTION_KEY_FILE"),
+            connect_timeout: parse(
+                "CMK_AGENT_CONNECT_TIMEOUT",
+                var("CMK_AGENT_CONNECT_TIMEOUT"),
+            )?,
+            request_timeout: parse(
+                "CMK_AGENT_REQUEST_TIMEOUT",
+                var("CMK_AGENT_REQUEST_TIMEOUT"),
+            )?,
+            api_retries: parse("CMK_AGENT_API_RETRIES", var("CMK_AGENT_API_RETRIES"))?,
+            api_retry_backoff: parse(
+                "CMK_AGENT_API_RETRY_BACKOFF",
+                var("CMK_AGENT_API_RETRY_BACKOFF"),
+            )?,
+            http_headers: list("CMK_AGENT_HTTP_HEADERS"),
+            stale_after: parse("CMK_AGENT_STALE_AFTER", var("CMK_AGENT_STALE_AFTER"))?,
+            mark_stale_on_push: parse(
+                "CMK_AGENT_MARK_STALE_ON_PUSH",
+                var("CMK_AGENT_MARK_STALE_ON_PUSH"),
+            )?,
+            cert_expiry_warning: parse(
+                "CMK_AGENT_CERT_EXPIRY_WARNING",
+                var("CMK_AGENT_CERT_EXPIRY_WARNING"),
+            )?,
+            renew_before: parse("CMK_AGENT_RENEW_BEFORE", var("CMK_AGENT_RENEW_BEFORE"))?,
+            sections: list("CMK_AGENT_SECTIONS"),
+            exclude_sections: list("CMK_AGENT_EXCLUDE_SECTIONS"),
+            listen_addresses: list("CMK_AGENT_LISTEN_ADDRESSES"),
+            listen_port: parse("CMK_AGENT_LISTEN_PORT", var("CMK_AGENT_LISTEN_PORT"))?,
+            only_from: list("CMK_AGENT_ONLY_FROM"),
+            legacy_pull: parse("CMK_AGENT_LEGACY_PULL", var("CMK_AGENT_LEGACY_PULL"))?,
+            crl_dir: var("CMK_AGENT_CRL_DIR"),
+            tls_min_version: parse(
+                "CMK_AGENT_TLS_MIN_VERSION",
+                var("CMK_AGENT_TLS_MIN_VERSION"),
+            )?,
+            tls_cipher_suites: list("CMK_AGENT_TLS_CIPHER_SUITES"),
+            tls_session_resumption: parse(
+                "CMK_AGENT_TLS_SESSION_RESUMPTION",
+                var("CMK_AGENT_TLS_SESSION_RESUMPTION"),
+            )?,
+            crypto_policy: parse("CMK_AGENT_CRYPTO_POLICY", var("CMK_AGENT_CRYPTO_POLICY"))?,
+            tls_key_log: parse("CMK_AGENT_TLS_KEY_LOG", var("CMK_AGENT_TLS_KEY_LOG"))?,
+            log_level: var("CMK_AGENT_LOG_LEVEL"),
+            key_passphrase_file: var("CMK_AGENT_KEY_PASSPHRASE_FILE"),
+            use_keyring: parse("CMK_AGENT_USE_KEYRING", var("CMK_AGENT_USE_KEYRING"))?,
+        })
     }
 
-    pub fn from_args(args: Args) -> Config {
-        return Config {
-            agent_receiver_address: args.server,
-            package_name: args.package_name,
-            credentials: if let (Some(u), Some(p)) = (args.user, args.password) {
-                Some(String::from(format!("{} {}", &u, &p)))
-            } else {
-                None
+    pub fn from_args(args: &Args) -> Result<Config, LoadError> {
+        let mode = &args.mode;
+        let collection = mode.collection_args();
+        Ok(Config {
+            agent_receiver_address: mode.server_args().and_then(|args| args.server.clone()),
+            failover_addresses: mode
+                .failover_addresses()
+                .and_then(|addresses| non_empty(addresses)),
+            proxy_url: None,
+            tls_verify: None,
+            tls_verify_hostname: None,
+            receiver_cert_fingerprint: None,
+            package_name: collection.and_then(|args| args.package_name.clone()),
+            agent_socket: collection.and_then(|args| args.agent_socket.clone()),
+            agent_port: collection.and_then(|args| args.agent_port),
+            data_source: collection.and_then(|args| args.data_source.clone()),
+            credentials: match mode.credentials_args() {
+                Some(credentials) => {
+                    credentials_from_args(credentials).map_err(LoadError::Arguments)?
+                }
+                None => None,
             },
             root_certificate: None,
-            host_name: args.host_name,
-        };
+            root_certificate_path: None,
+            host_name: mode.host_name().cloned(),
+            key_algorithm: None,
+            rsa_key_size: None,
+            pkcs11_token: None,
+            csr_attributes: None,
+            push_interval: mode.push_interval(),
+            push_jitter: None,
+            push_timeout: None,
+            push_rate_limit: None,
+            push_compression: None,
+            push_compression_level: None,
+            min_push_interval: None,
+            push_throttle: None,
+            push_spool_size: None,
+            push_signature: None,
+            push_encryption_key_file: None,
+            connect_timeout: None,
+            request_timeout: None,
+            api_retries: None,
+            api_retry_backoff: None,
+            http_headers: None,
+            stale_after: None,
+            mark_stale_on_push: None,
+            cert_expiry_warning: None,
+            renew_before: None,
+            sections: collection.and_then(|args| non_empty(&args.sections)),
+            exclude_sections: collection.and_then(|args| non_empty(&args.exclude_sections)),
+            listen_addresses: mode
+                .listen_args()
+                .and_then(|args| non_empty(&args.listen_addresses)),
+            listen_port: mode.listen_args().and_then(|args| args.listen_port),
+            only_from: None,
+            legacy_pull: None,
+            crl_dir: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            tls_session_resumption: None,
+            crypto_policy: None,
+            tls_key_log: None,
+            log_level: args.logging.log_level(),
+            key_passphrase_file: None,
+            use_keyring: None,
+        })
+    }
+}
+
+// The sources of the configuration, ordered from lowest to highest precedence. Each layer
+// remembers where it came from, such that the origin of every effective value is known.
+pub struct ConfigLayers {
+    layers: Vec<(String, Config)>,
+}
+
+impl ConfigLayers {
+    pub fn new() -> ConfigLayers {
+        ConfigLayers {
+            layers: vec![(String::from(DEFAULT_ORIGIN), Config::defaults())],
+        }
+    }
+
+    pub fn push(&mut self, origin: &str, config: Config) {
+        self.layers.push((String::from(origin), config));
+    }
+
+    pub fn merged(&self) -> Config {
+        self.layers
+            .iter()
+            .fold(Config::empty_config(), |merged, (_, config)| {
+                Config::merge_two_configs(merged, config.clone())
+            })
+    }
+
+    // The layer each field is taken from, None for fields which are not set at all
+    pub fn origins(&self) -> Vec<(String, Option<&str>)> {
+        let values: Vec<(&str, serde_json::Value)> = self
+            .layers
+            .iter()
+            .map(|(origin, config)| {
+                (
+                    origin.as_str(),
+                    serde_json::to_value(config).unwrap_or_default(),
+                )
+            })
+            .collect();
+        Config::field_names()
+            .into_iter()
+            .map(|field| {
+                let origin = values
+                    .iter()
+                    .rev()
+                    .find(|(_, value)| !value[&field].is_null())
+                    .map(|(origin, _)| *origin);
+                (field, origin)
+            })
+            .collect()
+    }
+
+    pub fn origin(&self, field: &str) -> Option<&str> {
+        self.origins()
+            .into_iter()
+            .find(|(name, _)| name == field)
+            .and_then(|(_, origin)| origin)
+    }
+}
+
+// Kept apart from the configuration, such that the latter does not need to be protected
+#[derive(Deserialize)]
+pub struct Secrets {
+    #[serde(default)]
+    pub credentials: Option<Secret>,
+}
+
+fn read_private_file(path: &Path) -> io::Result<String> {
+    if metadata(path)?.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must not be accessible by group or others, run chmod 600 on it",
+                path.display()
+            ),
+        ));
+    }
+    read_to_string(path)
+}
+
+pub fn read_key_passphrase(path: &Path) -> io::Result<Secret> {
+    let mut content = read_private_file(path)?;
+    let key_passphrase = Secret::from(content.trim_end_matches('\n').to_string());
+    secret::wipe(&mut content);
+    Ok(key_passphrase)
+}
+
+pub fn read_push_encryption_key(path: &Path) -> io::Result<Secret> {
+    let key = read_private_file(path)?.trim_end_matches('\n').to_string();
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is empty", path.display()),
+        ));
+    }
+    Ok(Secret::from(key))
+}
+
+impl Secrets {
+    pub fn from_file(path: &Path) -> Result<Secrets, LoadError> {
+        let serialized =
+            read_private_file(path).map_err(|error| LoadError::from_io(path, error))?;
+        serde_json::from_str(&serialized)
+            .map_err(|error| LoadError::Parse(path.to_path_buf(), error.to_string()))
+    }
+
+    pub fn empty_secrets() -> Secrets {
+        Secrets { credentials: None }
+    }
+
+    pub fn into_config(self) -> Config {
+        Config {
+            credentials: self.credentials,
+            ..Config::empty_config()
+        }
+    }
+}
+
+const STATE_VERSION: u64 = 2;
+
+// Each migration upgrades the serialized state by one version, the first one from the
+// unversioned format (version 0). Old state files are rewritten on their next update.
+const STATE_MIGRATIONS: [fn(&mut serde_json::Value); STATE_VERSION as usize] =
+    [migrate_state_v0, migrate_state_v1];
+
+fn migrate_state_v0(_state: &mut serde_json::Value) {
+    // The unversioned format only lacks the version field
+}
+
+// Registrations used to be stored under the address as given, which may lack the port. They
+// are looked up under host:port now. A registration stored under both is left for validate.
+fn migrate_state_v1(state: &mut serde_json::Value) {
+    let server_specs = match state
+        .get_mut("server_specs")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        Some(server_specs) => server_specs,
+        None => return,
+    };
+    let renames: Vec<(String, String)> = server_specs
+        .keys()
+        .filter_map(|address| match address::normalize(address) {
+            Ok(normalized) if normalized != *address => Some((address.clone(), normalized)),
+            _ => None,
+        })
+        .collect();
+    for (address, normalized) in renames {
+        if server_specs.contains_key(&normalized) {
+            continue;
+        }
+        if let Some(server_spec) = server_specs.remove(&address) {
+            server_specs.insert(normalized, server_spec);
+        }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct RegistrationState {
+    #[serde(default)]
+    pub version: u64,
+
     #[serde(default)]
     pub server_specs: HashMap<String, ServerSpec>,
+
+    // If set, private keys are encrypted when writing the state
+    #[serde(skip)]
+    key_passphrase: Option<Secret>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ServerSpec {
     pub uuid: String,
-    pub private_key: String,
+    pub private_key: Secret,
     pub certificate: String,
     pub root_cert: String,
+
+    #[serde(default)]
+    pub settings: ServerSettings,
+
+    // Why the registration is considered stale, see the prune mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stale: Option<String>,
+
+    // Registrations created by older versions have no metadata
+    #[serde(default)]
+    pub metadata: RegistrationMetadata,
+
+    // Kept after rotate-key until the site pulled successfully with the new pair
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_key: Option<KeyPair>,
+
+    // Further agent receivers of the same site, tried in order if the registration's address
+    // is unreachable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failover_addresses: Vec<String>,
+
+    // Discovered on registration and renewal, unknown for agent receivers which cannot tell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+}
+
+impl ServerSpec {
+    // Features of agent receivers with unknown capabilities are used as configured
+    pub fn supports(&self, feature: impl FnOnce(&Capabilities) -> bool) -> bool {
+        self.capabilities.as_ref().is_none_or(feature)
+    }
+}
+
+// The optional features an agent receiver reports, anything it leaves out is unsupported
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    // Content encodings of pushed data besides identity
+    #[serde(default)]
+    pub compression: Vec<String>,
+
+    #[serde(default)]
+    pub registration_status: bool,
+
+    #[serde(default)]
+    pub renewal: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyPair {
+    pub private_key: Secret,
+    pub certificate: String,
+}
+
+// When and how a registration was created
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RegistrationMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registered_at: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+// Settings for a single agent receiver, falling back to the global configuration
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ServerSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push_interval: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sections: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_sections: Option<Vec<String>>,
+}
+
+impl ServerSettings {
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut config = config.clone();
+        config.push_interval = self.push_interval.or(config.push_interval);
+        config.sections = self.sections.clone().or(config.sections);
+        config.exclude_sections = self.exclude_sections.clone().or(config.exclude_sections);
+        config
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegistrationBundle {
+    pub agent_receiver_address: String,
+    pub server_spec: ServerSpec,
+}
+
+impl RegistrationBundle {
+    pub fn to_json(&self) -> io::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Importable {
+    Bundle(Box<RegistrationBundle>),
+    State(RegistrationState),
+}
+
+impl Importable {
+    pub fn from_json(serialized: &str) -> io::Result<Importable> {
+        let importable: serde_json::Value = serde_json::from_str(serialized)?;
+        // Exported states may come from other versions, so they need to be migrated
+        if importable.get("server_specs").is_some() {
+            return Ok(Importable::State(RegistrationState::from_value(
+                importable, None,
+            )?));
+        }
+        Ok(serde_json::from_value(importable)?)
+    }
+
+    pub fn into_server_specs(self) -> HashMap<String, ServerSpec> {
+        match self {
+            Importable::Bundle(bundle) => {
+                let bundle = *bundle;
+                HashMap::from([(bundle.agent_receiver_address, bundle.server_spec)])
+            }
+            Importable::State(reg_state) => reg_state.server_specs,
+        }
+    }
+}
+
+fn private_keys(state: &mut serde_json::Value) -> Vec<(&String, &mut serde_json::Value)> {
+    match state
+        .get_mut("server_specs")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        Some(server_specs) => server_specs
+            .iter_mut()
+            .flat_map(|(address, spec)| {
+                spec.as_object_mut()
+                    .into_iter()
+                    .flat_map(|spec| spec.iter_mut())
+                    .filter_map(move |(name, value)| match name.as_str() {
+                        "private_key" => Some((address, value)),
+                        // Keys kept by rotate-key are encrypted as well
+                        "previous_key" => Some((address, value.get_mut("private_key")?)),
+                        _ => None,
+                    })
+            })
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn encrypt_private_keys(state: &mut serde_json::Value, key_passphrase: &str) -> io::Result<()> {
+    for (address, private_key) in private_keys(state) {
+        if let Some(plaintext) = private_key.as_str() {
+            let encrypted =
+                crypto::encrypt(plaintext.as_bytes(), key_passphrase).map_err(|error| {
+                    io::Error::other(format!(
+                        "Could not encrypt private key for {}: {}",
+                        address, error
+                    ))
+                })?;
+            *private_key = serde_json::to_value(encrypted)?;
+        }
+    }
+    Ok(())
+}
+
+// Encrypted keys are stored as objects instead of PEM strings
+fn decrypt_private_keys(
+    state: &mut serde_json::Value,
+    key_passphrase: Option<&str>,
+) -> io::Result<()> {
+    for (address, private_key) in private_keys(state) {
+        if !private_key.is_object() {
+            continue;
+        }
+        let key_passphrase = key_passphrase.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Private key for {} is encrypted, but no key passphrase is configured",
+                    address
+                ),
+            )
+        })?;
+        let encrypted: crypto::Encrypted = serde_json::from_value(private_key.take())?;
+        let plaintext = crypto::decrypt(&encrypted, key_passphrase)
+            .and_then(|plaintext| Ok(String::from_utf8(plaintext)?))
+            .map_err(|error| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Could not decrypt private key for {}: {}", address, error),
+                )
+            })?;
+        *private_key = serde_json::Value::String(plaintext);
+    }
+    Ok(())
 }
 
 impl RegistrationState {
-    fn empty_state() -> RegistrationState {
-        return serde_json::from_str("{}").unwrap();
+    pub fn empty_state() -> RegistrationState {
+        RegistrationState {
+            version: STATE_VERSION,
+            server_specs: HashMap::new(),
+            key_passphrase: None,
+        }
+    }
+
+    pub fn from_file(
+        path: &Path,
+        key_passphrase: Option<Secret>,
+    ) -> Result<RegistrationState, LoadError> {
+        let serialized = read_to_string(path).map_err(|error| LoadError::from_io(path, error))?;
+        let state = serde_json::from_str(&serialized)
+            .map_err(|error| LoadError::Parse(path.to_path_buf(), error.to_string()))?;
+        Ok(
+            RegistrationState::from_value(state, key_passphrase.as_ref().map(Secret::expose))
+                .map_err(|error| LoadError::Invalid(path.to_path_buf(), error.to_string()))?
+                .with_key_passphrase(key_passphrase),
+        )
+    }
+
+    // Whether reading the state needs the key passphrase, errors are left to from_file
+    pub fn has_encrypted_keys(path: &Path) -> bool {
+        read_to_string(path)
+            .ok()
+            .and_then(|serialized| serde_json::from_str(&serialized).ok())
+            .is_some_and(|mut state| {
+                private_keys(&mut state)
+                    .iter()
+                    .any(|(_, private_key)| private_key.is_object())
+            })
+    }
+
+    pub fn with_key_passphrase(self, key_passphrase: Option<Secret>) -> RegistrationState {
+        RegistrationState {
+            key_passphrase,
+            ..self
+        }
+    }
+
+    pub fn from_value(
+        mut state: serde_json::Value,
+        key_passphrase: Option<&str>,
+    ) -> io::Result<RegistrationState> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let version = match state.get("version") {
+            Some(version) => version
+                .as_u64()
+                .ok_or_else(|| invalid(format!("Invalid state version {}", version)))?,
+            None => 0,
+        };
+        if version > STATE_VERSION {
+            return Err(invalid(format!(
+                "State has version {}, but only versions up to {} are supported",
+                version, STATE_VERSION
+            )));
+        }
+        for migration in &STATE_MIGRATIONS[version as usize..] {
+            migration(&mut state);
+        }
+        state
+            .as_object_mut()
+            .ok_or_else(|| invalid(String::from("State is no JSON object")))?
+            .insert(String::from("version"), STATE_VERSION.into());
+        decrypt_private_keys(&mut state, key_passphrase)?;
+        Ok(serde_json::from_value(state)?)
+    }
+
+    pub fn to_json(&self) -> io::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = serde_json::to_value(self)?;
+        if let Some(key_passphrase) = &self.key_passphrase {
+            encrypt_private_keys(&mut state, key_passphrase.expose())?;
+        }
+        backup_state(path)?;
+        write_atomically(path, &serde_json::to_string(&state)?)
     }
 
-    pub fn from_file(path: &Path) -> io::Result<RegistrationState> {
+    pub fn settings_by_uuid(&self) -> HashMap<String, ServerSettings> {
+        self.server_specs
+            .values()
+            .map(|spec| (spec.uuid.clone(), spec.settings.clone()))
+            .collect()
+    }
+}
+
+const MAX_STATE_BACKUPS: usize = 5;
+
+fn backup_prefix(state_path: &Path) -> OsString {
+    let mut prefix = state_path.file_name().unwrap_or_default().to_os_string();
+    prefix.push(".");
+    prefix
+}
+
+// Newest first
+pub fn state_backups(state_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match state_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = backup_prefix(state_path);
+    let prefix = prefix.to_string_lossy();
+    let mut backups: Vec<PathBuf> = read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy())
+                .is_some_and(|name| name.starts_with(prefix.as_ref()) && name.ends_with(".bak"))
+        })
+        .collect();
+    // The timestamps have a fixed width, so sorting by name sorts by age
+    backups.sort();
+    backups.reverse();
+    Ok(backups)
+}
+
+// The state is copied with its permissions, which protect the private keys
+fn backup_state(state_path: &Path) -> io::Result<()> {
+    if !state_path.exists() {
+        return Ok(());
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0);
+    let mut backup_name = backup_prefix(state_path);
+    backup_name.push(format!("{:013}.bak", timestamp));
+    copy(state_path, state_path.with_file_name(backup_name))?;
+
+    for old_backup in state_backups(state_path)?.iter().skip(MAX_STATE_BACKUPS) {
+        remove_file(old_backup)?;
+    }
+    Ok(())
+}
+
+pub fn restore_state(state_path: &Path, backup_path: &Path) -> io::Result<()> {
+    let content = read_to_string(backup_path)?;
+    // Only check the format, since encrypted private keys cannot be decrypted here
+    serde_json::from_str::<serde_json::Value>(&content)?;
+    backup_state(state_path)?;
+    write_atomically(state_path, &content)
+}
+
+// Advisory lock on the registration state, released when dropped. A separate lock file is
+// used, since the state file itself is replaced on every write.
+pub struct StateLock {
+    _file: File,
+}
+
+impl StateLock {
+    pub fn shared(state_path: &Path) -> io::Result<StateLock> {
+        StateLock::acquire(state_path, FlockArg::LockShared)
+    }
+
+    pub fn exclusive(state_path: &Path) -> io::Result<StateLock> {
+        StateLock::acquire(state_path, FlockArg::LockExclusive)
+    }
+
+    pub fn path(state_path: &Path) -> PathBuf {
+        let mut lock_name = OsString::from(".");
+        lock_name.push(state_path.file_name().unwrap_or_default());
+        lock_name.push(".lock");
+        state_path.with_file_name(lock_name)
+    }
+
+    fn acquire(state_path: &Path, arg: FlockArg) -> io::Result<StateLock> {
+        let path = StateLock::path(state_path);
+        // World-readable, such that modes running as cmk-agent can lock a file created by root
+        let file = match OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(&path)
+        {
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => File::open(&path)?,
+            result => result?,
+        };
+        flock(file.as_raw_fd(), arg).map_err(|error| {
+            io::Error::other(format!("Could not lock {}: {}", path.display(), error))
+        })?;
+        Ok(StateLock { _file: file })
+    }
+}
+
+// Key material of a registration which waits for its certificate to be signed offline
+#[derive(Serialize, Deserialize)]
+pub struct PendingRegistration {
+    pub uuid: String,
+    pub private_key: Secret,
+}
+
+impl PendingRegistration {
+    pub fn from_file(path: &Path) -> io::Result<PendingRegistration> {
+        Ok(serde_json::from_str(&read_to_string(path)?)?)
+    }
+
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, &serde_json::to_string(self)?)
+    }
+}
+
+// Fingerprints of the root certificates trusted so far, by agent receiver address. They are
+// kept when registrations are deleted, such that a changed root is noticed on re-registration.
+#[derive(Serialize, Deserialize, Default)]
+pub struct TrustedRoots {
+    #[serde(default)]
+    pub fingerprints: HashMap<String, String>,
+}
+
+impl TrustedRoots {
+    pub fn from_file(path: &Path) -> io::Result<TrustedRoots> {
+        if path.exists() {
+            return Ok(serde_json::from_str(&read_to_string(path)?)?);
+        }
+        Ok(TrustedRoots::default())
+    }
+
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, &serde_json::to_string(self)?)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RuntimeState {
+    #[serde(default)]
+    pub last_push: Option<i64>,
+
+    #[serde(default)]
+    pub last_pull: Option<i64>,
+
+    // When monitoring data was last pushed successfully, by address
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub pushed_at: HashMap<String, i64>,
+
+    // Since when requests to an agent receiver fail, by address
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub failing_since: HashMap<String, i64>,
+
+    // Until when an agent receiver asked with Retry-After to get no pushes, by address
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub busy_until: HashMap<String, i64>,
+
+    // The failover address which answered last, by address of the registration
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub active_addresses: HashMap<String, String>,
+}
+
+impl RuntimeState {
+    pub fn empty_state() -> RuntimeState {
+        serde_json::from_str("{}").unwrap()
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<RuntimeState> {
         if path.exists() {
             return Ok(serde_json::from_str(&read_to_string(path)?)?);
         }
-        return Ok(RegistrationState::empty_state());
+        Ok(RuntimeState::empty_state())
+    }
+
+    // Readers never see it half-written, but writers have to hold the StateLock of the path
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        write_atomically(path, &serde_json::to_string(self)?)
+    }
+
+    pub fn set_active_address(&mut self, address: &str, active_address: &str) {
+        if active_address == address {
+            self.active_addresses.remove(address);
+        } else {
+            self.active_addresses
+                .insert(String::from(address), String::from(active_address));
+        }
+    }
+
+    // Returns since when the receiver has been failing, None if the request succeeded
+    pub fn record_result(&mut self, address: &str, success: bool, now: i64) -> Option<i64> {
+        if success {
+            self.failing_since.remove(address);
+            return None;
+        }
+        Some(
+            *self
+                .failing_since
+                .entry(String::from(address))
+                .or_insert(now),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    #[test]
+    fn test_config_from_str_json_and_toml() {
+        let json = Config::from_str(r#"{"package_name": "json-agent"}"#, false).unwrap();
+        assert_eq!(json.package_name.as_deref(), Some("json-agent"));
+
+        let toml = Config::from_str(
+            "# Comments are allowed\npackage_name = \"toml-agent\"\nsections = [\"df\"]\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(toml.package_name.as_deref(), Some("toml-agent"));
+        assert_eq!(toml.sections, Some(vec![String::from("df")]));
+    }
+
+    #[test]
+    fn test_config_layers() {
+        let mut layers = ConfigLayers::new();
+        layers.push(
+            "file",
+            Config {
+                package_name: Some(String::from("file-agent")),
+                host_name: Some(String::from("file-host")),
+                ..Config::empty_config()
+            },
+        );
+        layers.push(
+            "command line",
+            Config {
+                host_name: Some(String::from("cli-host")),
+                ..Config::empty_config()
+            },
+        );
+        let merged = layers.merged();
+        assert_eq!(merged.package_name.as_deref(), Some("file-agent"));
+        assert_eq!(merged.host_name.as_deref(), Some("cli-host"));
+        assert_eq!(merged.push_interval, Some(DEFAULT_PUSH_INTERVAL));
+
+        let origins: HashMap<String, Option<&str>> = layers.origins().into_iter().collect();
+        assert_eq!(origins["package_name"], Some("file"));
+        assert_eq!(origins["host_name"], Some("command line"));
+        assert_eq!(origins["push_interval"], Some("built-in default"));
+        assert_eq!(origins["credentials"], None);
+    }
+
+    #[test]
+    fn test_config_from_vars() {
+        let vars = HashMap::from([
+            ("CMK_AGENT_RECEIVER", "server:8000"),
+            ("CMK_AGENT_USER", "automation"),
+            ("CMK_AGENT_PASSWORD", "secret"),
+            ("CMK_AGENT_SECTIONS", "df, mem"),
+        ]);
+        let config =
+            Config::from_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(
+            config.agent_receiver_address.as_deref(),
+            Some("server:8000")
+        );
+        assert_eq!(
+            config.credentials.as_ref().map(Secret::expose),
+            Some("automation secret")
+        );
+        assert_eq!(
+            config.sections,
+            Some(vec![String::from("df"), String::from("mem")])
+        );
+        assert!(config.host_name.is_none());
+    }
+
+    #[test]
+    fn test_server_settings_apply() {
+        let mut config = Config::empty_config();
+        config.push_interval = Some(60);
+        config.sections = Some(vec![String::from("df")]);
+        let settings = ServerSettings {
+            push_interval: Some(300),
+            ..ServerSettings::default()
+        };
+        let applied = settings.apply(&config);
+        assert_eq!(applied.push_interval, Some(300));
+        assert_eq!(applied.sections, Some(vec![String::from("df")]));
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = std::env::temp_dir().join(format!("cmk-agent-ctl-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        write_atomically(&path, "old").unwrap();
+        write_atomically(&path, "new").unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), "new");
+        assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_state_lock() {
+        let dir = std::env::temp_dir().join(format!("cmk-agent-ctl-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        write(&path, "0").unwrap();
+        // Without the lock, both writers would read 0 and the result be 1
+        let writers: Vec<std::thread::JoinHandle<()>> = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let _lock = StateLock::exclusive(&path).unwrap();
+                    let count: u32 = read_to_string(&path).unwrap().parse().unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    write_atomically(&path, &(count + 1).to_string()).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(read_to_string(&path).unwrap(), "2");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = std::env::temp_dir().join(format!("cmk-agent-ctl-load-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        assert!(matches!(
+            RegistrationState::from_file(&path, None),
+            Err(LoadError::Missing(_))
+        ));
+        write(&path, "{").unwrap();
+        assert!(matches!(
+            RegistrationState::from_file(&path, None),
+            Err(LoadError::Parse(..))
+        ));
+        write(&path, r#"{"version": 1000, "server_specs": {}}"#).unwrap();
+        assert!(matches!(
+            RegistrationState::from_file(&path, None),
+            Err(LoadError::Invalid(..))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_registration_state_migration() {
+        let unversioned = serde_json::json!({"server_specs": {}});
+        assert_eq!(
+            RegistrationState::from_value(unversioned, None)
+                .unwrap()
+                .version,
+            STATE_VERSION
+        );
+
+        let newer = serde_json::json!({"version": STATE_VERSION + 1, "server_specs": {}});
+        assert!(RegistrationState::from_value(newer, None).is_err());
+    }
+
+    #[test]
+    fn test_migrate_state_v1() {
+        let mut state = serde_json::json!({"version": 1, "server_specs": {
+            "server": {"uuid": "1"},
+            "[2001:db8::1]": {"uuid": "2"},
+            "other:8001": {"uuid": "3"},
+            "twice": {"uuid": "4"},
+            "twice:8000": {"uuid": "5"},
+            "/run/agent-receiver.sock": {"uuid": "6"}
+        }});
+        migrate_state_v1(&mut state);
+        assert_eq!(
+            state["server_specs"],
+            serde_json::json!({
+                "server:8000": {"uuid": "1"},
+                "[2001:db8::1]:8000": {"uuid": "2"},
+                "other:8001": {"uuid": "3"},
+                "twice": {"uuid": "4"},
+                "twice:8000": {"uuid": "5"},
+                "/run/agent-receiver.sock": {"uuid": "6"}
+            })
+        );
+    }
+
+    #[test]
+    fn test_private_key_encryption() {
+        let mut state = serde_json::json!({"server_specs": {"server:8000": {
+            "private_key": "PEM",
+            "previous_key": {"private_key": "OLD PEM", "certificate": "CERT"}
+        }}});
+        encrypt_private_keys(&mut state, "secret").unwrap();
+        let spec = &state["server_specs"]["server:8000"];
+        assert!(spec["private_key"].is_object());
+        assert!(spec["previous_key"]["private_key"].is_object());
+        assert_eq!(spec["previous_key"]["certificate"], "CERT");
+        assert!(decrypt_private_keys(&mut state.clone(), None).is_err());
+        decrypt_private_keys(&mut state, Some("secret")).unwrap();
+        let spec = &state["server_specs"]["server:8000"];
+        assert_eq!(spec["private_key"], "PEM");
+        assert_eq!(spec["previous_key"]["private_key"], "OLD PEM");
+    }
+
+    #[test]
+    fn test_state_backups() {
+        let dir =
+            std::env::temp_dir().join(format!("cmk-agent-ctl-backups-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        for version in 0..MAX_STATE_BACKUPS + 3 {
+            backup_state(&path).unwrap();
+            write(&path, format!("{{\"version\": {}}}", version)).unwrap();
+            // Backups are named by millisecond
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let backups = state_backups(&path).unwrap();
+        assert_eq!(backups.len(), MAX_STATE_BACKUPS);
+        let version = |path: &Path| -> serde_json::Value {
+            serde_json::from_str(&read_to_string(path).unwrap()).unwrap()
+        };
+        // The first write had nothing to back up yet
+        assert_eq!(version(&backups[0]), serde_json::json!({"version": 6}));
+        assert_eq!(version(&backups[4]), serde_json::json!({"version": 2}));
+
+        restore_state(&path, &backups[4]).unwrap();
+        assert_eq!(version(&path), serde_json::json!({"version": 2}));
+        // The state before restoring is backed up as well
+        let backups = state_backups(&path).unwrap();
+        assert_eq!(backups.len(), MAX_STATE_BACKUPS);
+        assert_eq!(version(&backups[0]), serde_json::json!({"version": 7}));
+
+        let invalid = dir.join("invalid.bak");
+        write(&invalid, "{").unwrap();
+        assert!(restore_state(&path, &invalid).is_err());
+        assert_eq!(version(&path), serde_json::json!({"version": 2}));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub fn to_file(self, path: &Path) -> io::Result<()> {
-        write(path, &serde_json::to_string(&self)?)
+    #[test]
+    fn test_has_encrypted_keys() {
+        let dir =
+            std::env::temp_dir().join(format!("cmk-agent-ctl-encrypted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        assert!(!RegistrationState::has_encrypted_keys(&path));
+        let mut state =
+            serde_json::json!({"server_specs": {"server:8000": {"private_key": "PEM"}}});
+        write(&path, state.to_string()).unwrap();
+        assert!(!RegistrationState::has_encrypted_keys(&path));
+        encrypt_private_keys(&mut state, "secret").unwrap();
+        write(&path, state.to_string()).unwrap();
+        assert!(RegistrationState::has_encrypted_keys(&path));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
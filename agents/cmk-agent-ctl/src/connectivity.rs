@@ -0,0 +1,211 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{address, agent_receiver_api, certs, config, fips, proxy};
+use agent_receiver_api::AgentReceiver;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+fn resolve(address: &str) -> AnyhowResult<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .context(format!("Could not resolve {}", address))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("{} did not resolve to any address", address));
+    }
+    Ok(addrs)
+}
+
+fn connect(addrs: &[SocketAddr], connect_timeout: Duration) -> AnyhowResult<TcpStream> {
+    let mut last_error = anyhow!("No address to connect to");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, connect_timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = anyhow!("Could not connect to {}: {}", addr, error),
+        }
+    }
+    Err(last_error)
+}
+
+// Verifies the same way as the API client, see certs::client
+fn tls_handshake(
+    tcp_stream: TcpStream,
+    address: &str,
+    root_cert: &str,
+    client_options: &certs::ClientOptions,
+) -> AnyhowResult<()> {
+    let tls_verify = client_options.tls_verify;
+    let mut ssl_connector_builder = SslConnector::builder(SslMethod::tls())?;
+    if tls_verify == config::TlsVerify::Site {
+        for cert in certs::root_certs(root_cert)? {
+            ssl_connector_builder.cert_store_mut().add_cert(cert)?;
+        }
+    }
+    ssl_connector_builder.set_verify(SslVerifyMode::PEER);
+    if client_options.fips {
+        fips::restrict_openssl(&mut ssl_connector_builder)?;
+    }
+    if client_options.tls_key_log {
+        certs::log_tls_keys(&mut ssl_connector_builder);
+    }
+    let host = address::parse(address)
+        .map_err(|message| anyhow!(message))?
+        .host;
+    let mut ssl_stream = ssl_connector_builder
+        .build()
+        .configure()?
+        .verify_hostname(client_options.verify_hostname || tls_verify == config::TlsVerify::System)
+        .connect(host, tcp_stream)?;
+    if let Some(expected) = &client_options.receiver_cert_fingerprint {
+        let fingerprint = certs::der_fingerprint(
+            ssl_stream
+                .ssl()
+                .peer_certificate()
+                .ok_or_else(|| anyhow!("The agent receiver sent no certificate"))?
+                .as_ref(),
+        )?;
+        if certs::normalize_fingerprint(&fingerprint) != certs::normalize_fingerprint(expected) {
+            return Err(anyhow!(
+                "Certificate fingerprint {} does not match receiver_cert_fingerprint {}",
+                fingerprint,
+                expected
+            ));
+        }
+    }
+    ssl_stream.shutdown()?;
+    Ok(())
+}
+
+fn check_registration(
+    address: &str,
+    server_spec: &config::ServerSpec,
+    client_options: &certs::ClientOptions,
+) -> AnyhowResult<String> {
+    let status = agent_receiver_api::block_on(agent_receiver_api::Http.registration_status(
+        &agent_receiver_api::base_url(address, server_spec.metadata.api_version.as_deref()),
+        &server_spec.root_cert,
+        &server_spec.uuid,
+        client_options,
+    ))?;
+    Ok(format!(
+        "UUID {} known{}{}{}",
+        server_spec.uuid,
+        status
+            .hostname
+            .map(|hostname| format!(" for host {}", hostname))
+            .unwrap_or_default(),
+        status
+            .status
+            .map(|status| format!(", status: {}", status))
+            .unwrap_or_default(),
+        status
+            .message
+            .map(|message| format!(", {}", message))
+            .unwrap_or_default(),
+    ))
+}
+
+fn step<T>(name: &str, result: AnyhowResult<T>, describe: impl Fn(&T) -> String) -> Option<T> {
+    match result {
+        Ok(value) => {
+            println!("\t{}: OK ({})", name, describe(&value));
+            Some(value)
+        }
+        Err(error) => {
+            println!("\t{}: FAILED ({:#})", name, error);
+            None
+        }
+    }
+}
+
+// Run the checks one after another and stop at the first failure, since each
+// step depends on the previous one.
+pub fn test(
+    address: &str,
+    server_spec: &config::ServerSpec,
+    client_options: &certs::ClientOptions,
+) -> bool {
+    println!("{}", address);
+
+    // Local agent receivers are neither resolved nor behind TLS
+    if address::is_unix_socket(address) {
+        if step(
+            "Unix socket connection",
+            UnixStream::connect(address).context(format!("Could not connect to {}", address)),
+            |_| String::from("connected"),
+        )
+        .is_none()
+        {
+            return false;
+        }
+        return step(
+            "Registration",
+            check_registration(address, server_spec, client_options),
+            String::clone,
+        )
+        .is_some();
+    }
+
+    let tcp_stream = match &client_options.proxy_url {
+        // The proxy resolves the address, so there is nothing to check locally
+        Some(proxy_url) => match step("Proxy tunnel", proxy::tunnel(proxy_url, address), |_| {
+            String::from("connected through proxy")
+        }) {
+            Some(tcp_stream) => tcp_stream,
+            None => return false,
+        },
+        None => {
+            let addrs = match step("DNS resolution", resolve(address), |addrs| {
+                addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            }) {
+                Some(addrs) => addrs,
+                None => return false,
+            };
+
+            match step(
+                "TCP connection",
+                connect(&addrs, client_options.connect_timeout),
+                |stream| match stream.peer_addr() {
+                    Ok(peer) => format!("connected to {}", peer),
+                    Err(_) => String::from("connected"),
+                },
+            ) {
+                Some(tcp_stream) => tcp_stream,
+                None => return false,
+            }
+        }
+    };
+
+    if step(
+        "TLS handshake",
+        tls_handshake(tcp_stream, address, &server_spec.root_cert, client_options),
+        |_| match client_options.tls_verify {
+            config::TlsVerify::Site => {
+                String::from("certificate verified against stored root certificate")
+            }
+            config::TlsVerify::System => {
+                String::from("certificate verified against system certificate store")
+            }
+        },
+    )
+    .is_none()
+    {
+        return false;
+    }
+
+    step(
+        "Registration",
+        check_registration(address, server_spec, client_options),
+        String::clone,
+    )
+    .is_some()
+}
@@ -0,0 +1,125 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use anyhow::{Context, Result as AnyhowResult};
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+
+const PBKDF2_ITERATIONS: usize = 100_000;
+
+#[derive(Serialize, Deserialize)]
+pub struct Encrypted {
+    salt: String,
+    iv: String,
+    tag: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> AnyhowResult<[u8; 32]> {
+    let mut key = [0; 32];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        PBKDF2_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+pub fn encrypt(plaintext: &[u8], passphrase: &str) -> AnyhowResult<Encrypted> {
+    let mut salt = [0; 16];
+    let mut iv = [0; 12];
+    let mut tag = [0; 16];
+    rand_bytes(&mut salt)?;
+    rand_bytes(&mut iv)?;
+
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &derive_key(passphrase, &salt)?,
+        Some(&iv),
+        &[],
+        plaintext,
+        &mut tag,
+    )?;
+
+    Ok(Encrypted {
+        salt: base64::encode_block(&salt),
+        iv: base64::encode_block(&iv),
+        tag: base64::encode_block(&tag),
+        ciphertext: base64::encode_block(&ciphertext),
+    })
+}
+
+// Pushed payloads are sent as salt, IV, ciphertext and tag in a row. The associated data binds
+// them to the UUID of the registration, such that they cannot be replayed for another host.
+pub fn encrypt_payload(plaintext: &[u8], passphrase: &str, uuid: &str) -> AnyhowResult<Vec<u8>> {
+    let mut salt = [0; 16];
+    let mut iv = [0; 12];
+    let mut tag = [0; 16];
+    rand_bytes(&mut salt)?;
+    rand_bytes(&mut iv)?;
+
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &derive_key(passphrase, &salt)?,
+        Some(&iv),
+        uuid.as_bytes(),
+        plaintext,
+        &mut tag,
+    )?;
+
+    Ok([&salt[..], &iv, &ciphertext, &tag].concat())
+}
+
+pub fn decrypt(encrypted: &Encrypted, passphrase: &str) -> AnyhowResult<Vec<u8>> {
+    let salt = base64::decode_block(&encrypted.salt)?;
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &derive_key(passphrase, &salt)?,
+        Some(&base64::decode_block(&encrypted.iv)?),
+        &[],
+        &base64::decode_block(&encrypted.ciphertext)?,
+        &base64::decode_block(&encrypted.tag)?,
+    )
+    .context("Decryption failed, wrong passphrase or corrupted data")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let encrypted = encrypt(b"some data", "passphrase").unwrap();
+        assert_eq!(decrypt(&encrypted, "passphrase").unwrap(), b"some data");
+        assert!(decrypt(&encrypted, "wrong passphrase").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_payload() {
+        let payload = encrypt_payload(b"some data", "passphrase", "uuid").unwrap();
+        assert_eq!(payload.len(), 16 + 12 + 9 + 16);
+        let (salt, rest) = payload.split_at(16);
+        let (iv, rest) = rest.split_at(12);
+        let (ciphertext, tag) = rest.split_at(rest.len() - 16);
+        let decrypt = |passphrase: &str, uuid: &str| {
+            decrypt_aead(
+                Cipher::aes_256_gcm(),
+                &derive_key(passphrase, salt).unwrap(),
+                Some(iv),
+                uuid.as_bytes(),
+                ciphertext,
+                tag,
+            )
+        };
+        assert_eq!(decrypt("passphrase", "uuid").unwrap(), b"some data");
+        assert!(decrypt("passphrase", "other uuid").is_err());
+        assert!(decrypt("wrong passphrase", "uuid").is_err());
+    }
+}
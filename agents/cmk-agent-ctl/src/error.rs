@@ -0,0 +1,169 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use http::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Distinguishes the ways loading the configuration or the registration state can fail,
+// such that callers can tell a file which simply does not exist yet from a broken one
+#[derive(Debug)]
+pub enum LoadError {
+    Missing(PathBuf),
+    PermissionDenied(PathBuf, io::Error),
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, String),
+    Invalid(PathBuf, String),
+    Environment(String),
+    // A value which is well-formed, but does not make sense, with the layer it comes from
+    Value(String, String),
+    Arguments(io::Error),
+    Keyring(String),
+}
+
+impl LoadError {
+    pub fn from_io(path: &Path, error: io::Error) -> LoadError {
+        match error.kind() {
+            io::ErrorKind::NotFound => LoadError::Missing(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => {
+                LoadError::PermissionDenied(path.to_path_buf(), error)
+            }
+            _ => LoadError::Io(path.to_path_buf(), error),
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        matches!(self, LoadError::Missing(_))
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Missing(path) => write!(f, "{} does not exist", path.display()),
+            LoadError::PermissionDenied(path, error) => {
+                write!(f, "Cannot access {}: {}", path.display(), error)
+            }
+            LoadError::Io(path, error) => write!(f, "Error reading {}: {}", path.display(), error),
+            LoadError::Parse(path, message) => {
+                write!(f, "{} is malformed: {}", path.display(), message)
+            }
+            LoadError::Invalid(path, message) => {
+                write!(f, "{} is invalid: {}", path.display(), message)
+            }
+            LoadError::Environment(message) => write!(f, "{}", message),
+            LoadError::Value(origin, message) => {
+                write!(f, "Invalid value in {}: {}", origin, message)
+            }
+            LoadError::Arguments(error) => {
+                write!(f, "Error processing command line arguments: {}", error)
+            }
+            LoadError::Keyring(message) => write!(f, "Error reading the keyring: {}", message),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+// Distinguishes the ways requests to the agent receiver can fail, such that callers can react
+// to rejected credentials differently than to an unreachable receiver
+#[derive(Debug)]
+pub enum ApiError {
+    // The HTTP client could not be set up, e.g. because of a broken root certificate
+    Client(String),
+    Connection(String),
+    Timeout(String),
+    Tls(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    Status(StatusCode, String),
+    // 429 or 503 with Retry-After, the receiver wants no requests for this long
+    Busy(StatusCode, Duration, String),
+    Malformed(String),
+    Incompatible(String),
+}
+
+impl ApiError {
+    pub fn from_status(status: StatusCode, body: String) -> ApiError {
+        match status {
+            StatusCode::UNAUTHORIZED => ApiError::Unauthorized(body),
+            StatusCode::FORBIDDEN => ApiError::Forbidden(body),
+            StatusCode::CONFLICT => ApiError::Conflict(body),
+            _ => ApiError::Status(status, body),
+        }
+    }
+
+    // The receiver does not know or accept the UUID anymore
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Unauthorized(_)
+                | ApiError::Forbidden(_)
+                | ApiError::Status(StatusCode::NOT_FOUND, _)
+        )
+    }
+
+    // The receiver may be back later, as opposed to refusing the request
+    pub fn is_outage(&self) -> bool {
+        match self {
+            ApiError::Connection(_) | ApiError::Timeout(_) | ApiError::Busy(_, _, _) => true,
+            ApiError::Status(status, _) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::Busy(_, retry_after, _) => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    // Following sysexits.h, such that scripts can tell what went wrong
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ApiError::Client(_) => 78,
+            ApiError::Connection(_) | ApiError::Timeout(_) => 69,
+            ApiError::Unauthorized(_) | ApiError::Forbidden(_) => 77,
+            ApiError::Conflict(_) => 65,
+            ApiError::Busy(_, _, _) => 75,
+            ApiError::Tls(_)
+            | ApiError::Status(_, _)
+            | ApiError::Malformed(_)
+            | ApiError::Incompatible(_) => 76,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Client(message) => write!(f, "Error setting up the connection: {}", message),
+            ApiError::Connection(message) => write!(f, "Connection failed: {}", message),
+            ApiError::Timeout(message) => write!(f, "{}", message),
+            ApiError::Tls(message) => write!(f, "TLS error: {}", message),
+            ApiError::Unauthorized(body) => write!(f, "Not authenticated (401): {}", body),
+            ApiError::Forbidden(body) => write!(f, "Not allowed (403): {}", body),
+            ApiError::Conflict(body) => write!(f, "Conflict (409): {}", body),
+            ApiError::Status(status, body) => {
+                write!(f, "Request failed with code {}: {}", status, body)
+            }
+            ApiError::Busy(status, retry_after, body) => write!(
+                f,
+                "Request failed with code {}, retry after {}s: {}",
+                status,
+                retry_after.as_secs(),
+                body
+            ),
+            ApiError::Malformed(message) => write!(f, "Malformed response: {}", message),
+            ApiError::Incompatible(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ApiError {}
@@ -0,0 +1,143 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{certs, config};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKeyRef};
+use openssl::ssl::SslContextBuilder;
+use openssl::x509::{X509Ref, X509};
+
+// crypto_policy fips allows AES-GCM cipher suites with ECDHE over NIST curves, RSA keys of at
+// least 2048 bits, ECDSA keys on NIST curves and SHA-2 signatures
+const OPENSSL_CIPHER_LIST: &str = "ECDHE+AESGCM";
+const OPENSSL_CIPHERSUITES: &str = "TLS_AES_256_GCM_SHA384:TLS_AES_128_GCM_SHA256";
+const OPENSSL_GROUPS: &str = "P-384:P-256";
+const MIN_RSA_KEY_SIZE: u32 = 2048;
+const CURVES: [Nid; 3] = [Nid::X9_62_PRIME256V1, Nid::SECP384R1, Nid::SECP521R1];
+const SIGNATURE_ALGORITHMS: [Nid; 6] = [
+    Nid::SHA256WITHRSAENCRYPTION,
+    Nid::SHA384WITHRSAENCRYPTION,
+    Nid::SHA512WITHRSAENCRYPTION,
+    Nid::ECDSA_WITH_SHA256,
+    Nid::ECDSA_WITH_SHA384,
+    Nid::ECDSA_WITH_SHA512,
+];
+
+pub fn is_enabled(crypto_policy: Option<config::CryptoPolicy>) -> bool {
+    crypto_policy == Some(config::CryptoPolicy::Fips)
+}
+
+// rustls names its suites as in the IANA registry, e.g. TLS13_AES_256_GCM_SHA384
+pub fn is_approved_cipher_suite(name: &str) -> bool {
+    name.contains("_AES_") && name.contains("_GCM_")
+}
+
+pub fn restrict_openssl(builder: &mut SslContextBuilder) -> AnyhowResult<()> {
+    builder.set_cipher_list(OPENSSL_CIPHER_LIST)?;
+    builder.set_ciphersuites(OPENSSL_CIPHERSUITES)?;
+    builder.set_groups_list(OPENSSL_GROUPS)?;
+    Ok(())
+}
+
+pub fn check_key_algorithm(algorithm: config::KeyAlgorithm) -> AnyhowResult<()> {
+    match algorithm {
+        config::KeyAlgorithm::Ed25519 => Err(anyhow!(
+            "Ed25519 keys are not allowed by crypto_policy fips"
+        )),
+        config::KeyAlgorithm::Rsa | config::KeyAlgorithm::EcdsaP256 => Ok(()),
+    }
+}
+
+fn check_key<T: HasPublic>(key: &PKeyRef<T>) -> AnyhowResult<()> {
+    let approved = match key.id() {
+        Id::RSA => key.bits() >= MIN_RSA_KEY_SIZE,
+        Id::EC => key
+            .ec_key()?
+            .group()
+            .curve_name()
+            .is_some_and(|curve| CURVES.contains(&curve)),
+        _ => false,
+    };
+    if !approved {
+        return Err(anyhow!(
+            "Key of type {} with {} bits is not allowed by crypto_policy fips",
+            key_type(key.id()),
+            key.bits()
+        ));
+    }
+    Ok(())
+}
+
+fn key_type(id: Id) -> &'static str {
+    match id {
+        Id::RSA => "RSA",
+        Id::EC => "EC",
+        Id::ED25519 => "Ed25519",
+        Id::DSA => "DSA",
+        _ => "unknown",
+    }
+}
+
+fn check_certificate(cert: &X509Ref) -> AnyhowResult<()> {
+    check_key(cert.public_key()?.as_ref())?;
+    let signature_algorithm = cert.signature_algorithm().object().nid();
+    if !SIGNATURE_ALGORITHMS.contains(&signature_algorithm) {
+        return Err(anyhow!(
+            "Signature algorithm {} is not allowed by crypto_policy fips",
+            signature_algorithm.short_name().unwrap_or("unknown")
+        ));
+    }
+    Ok(())
+}
+
+fn check_server_spec(server_spec: &config::ServerSpec) -> AnyhowResult<()> {
+    check_key(
+        certs::key_backend(&server_spec.private_key)?
+            .public_key()?
+            .as_ref(),
+    )
+    .context("Private key")?;
+    check_certificate(X509::from_pem(server_spec.certificate.as_bytes())?.as_ref())
+        .context("Certificate")?;
+    for root_cert in certs::root_certs(&server_spec.root_cert)? {
+        check_certificate(root_cert.as_ref()).context("Root certificate")?;
+    }
+    Ok(())
+}
+
+pub fn check_state(reg_state: &config::RegistrationState) -> AnyhowResult<()> {
+    for (agent_receiver_address, server_spec) in &reg_state.server_specs {
+        check_server_spec(server_spec).context(format!(
+            "Registration with {} does not comply with crypto_policy fips, register again",
+            agent_receiver_address
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::PKey;
+
+    #[test]
+    fn test_is_approved_cipher_suite() {
+        assert!(is_approved_cipher_suite("TLS13_AES_256_GCM_SHA384"));
+        assert!(is_approved_cipher_suite(
+            "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"
+        ));
+        assert!(!is_approved_cipher_suite("TLS13_CHACHA20_POLY1305_SHA256"));
+    }
+
+    #[test]
+    fn test_check_key() {
+        let rsa = PKey::from_rsa(openssl::rsa::Rsa::generate(2048).unwrap()).unwrap();
+        assert!(check_key(rsa.as_ref()).is_ok());
+        let small_rsa = PKey::from_rsa(openssl::rsa::Rsa::generate(1024).unwrap()).unwrap();
+        assert!(check_key(small_rsa.as_ref()).is_err());
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        assert!(check_key(ed25519.as_ref()).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::secret::{self, Secret};
+use anyhow::{anyhow, Result as AnyhowResult};
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use nix::unistd;
+use std::io::{self, BufRead, Write};
+
+const STDIN_FD: i32 = 0;
+// Large enough for any password, such that reading never reallocates and leaves copies behind
+const LINE_CAPACITY: usize = 1024;
+
+pub fn is_interactive() -> bool {
+    unistd::isatty(STDIN_FD).unwrap_or(false)
+}
+
+// Only the line ending is removed, spaces may be part of a password
+fn read_line() -> AnyhowResult<Secret> {
+    let mut line = String::with_capacity(LINE_CAPACITY);
+    let result = io::stdin().lock().read_line(&mut line);
+    let input = Secret::from(line.trim_end_matches(&['\r', '\n'][..]).to_string());
+    secret::wipe(&mut line);
+    result?;
+    if input.expose().is_empty() {
+        return Err(anyhow!("No input given"));
+    }
+    Ok(input)
+}
+
+pub fn prompt(question: &str) -> AnyhowResult<String> {
+    // Prompts go to stderr, since stdout may be used for output to be processed further
+    eprint!("{}: ", question);
+    io::stderr().flush()?;
+    let answer = read_line()?.expose().trim().to_string();
+    if answer.is_empty() {
+        return Err(anyhow!("No input given"));
+    }
+    Ok(answer)
+}
+
+pub fn prompt_hidden(question: &str) -> AnyhowResult<Secret> {
+    eprint!("{}: ", question);
+    io::stderr().flush()?;
+
+    let original = termios::tcgetattr(STDIN_FD)?;
+    let mut hidden = original.clone();
+    hidden.local_flags.remove(LocalFlags::ECHO);
+    termios::tcsetattr(STDIN_FD, SetArg::TCSANOW, &hidden)?;
+    let input = read_line();
+    termios::tcsetattr(STDIN_FD, SetArg::TCSANOW, &original)?;
+    eprintln!();
+
+    input
+}
@@ -0,0 +1,61 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::secret::{self, Secret};
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// The Secret Service is reached via secret-tool of libsecret, which needs a D-Bus session
+const SECRET_TOOL: &str = "secret-tool";
+const SERVICE: &str = "cmk-agent-ctl";
+
+pub const CREDENTIALS: &str = "credentials";
+pub const KEY_PASSPHRASE: &str = "key-passphrase";
+
+fn secret_tool() -> Command {
+    let mut command = Command::new(SECRET_TOOL);
+    command.stderr(Stdio::inherit());
+    command
+}
+
+pub fn lookup(name: &str) -> AnyhowResult<Option<Secret>> {
+    let output = secret_tool()
+        .args(["lookup", "service", SERVICE, "name", name])
+        .output()
+        .context(format!("Could not run {}", SECRET_TOOL))?;
+    // A missing secret is no error, secret-tool just prints nothing
+    if !output.status.success() && output.stdout.is_empty() {
+        return Ok(None);
+    }
+    let mut value = String::from_utf8(output.stdout)?;
+    let secret = Secret::from(value.trim_end_matches('\n').to_string());
+    secret::wipe(&mut value);
+    Ok(Some(secret))
+}
+
+pub fn store(name: &str, value: &Secret) -> AnyhowResult<()> {
+    let mut child = secret_tool()
+        .args([
+            "store",
+            "--label",
+            &format!("{} {}", SERVICE, name),
+            "service",
+            SERVICE,
+            "name",
+            name,
+        ])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context(format!("Could not run {}", SECRET_TOOL))?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Could not pass the secret to {}", SECRET_TOOL))?
+        .write_all(value.expose().as_bytes())?;
+    if !child.wait()?.success() {
+        return Err(anyhow!("{} failed to store {}", SECRET_TOOL, name));
+    }
+    Ok(())
+}
@@ -2,145 +2,2629 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
+mod address;
 mod agent_receiver_api;
 mod certs;
 mod cli;
+mod compression;
 mod config;
+mod connectivity;
+mod crypto;
+mod error;
+mod fips;
+mod interactive;
+mod keyring;
 mod monitoring_data;
+mod only_from;
+mod paths;
+mod pkcs11;
+mod proxy;
+mod prune;
+mod rate_limit;
+mod reload;
+mod secret;
+mod spool;
+mod stats;
+mod status;
+mod template;
 mod tls_server;
+mod validation;
+use agent_receiver_api::AgentReceiver;
 use anyhow::{anyhow, Context, Result as AnyhowResult};
 use config::RegistrationState;
+use error::{ApiError, LoadError};
+use nix::sys::socket::{self, SockAddr};
 use nix::unistd;
+use rustls::ServerConfig;
+use secret::Secret;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs;
+use std::future::Future;
 use std::io::Result as IoResult;
-use std::io::{self, Write};
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use structopt::clap::Shell;
 use structopt::StructOpt;
 use uuid::Uuid;
 
-use log::{info, LevelFilter};
+use log::{debug, info, warn, LevelFilter};
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 
 const CMK_AGENT_USER: &str = "cmk-agent";
-const HOME_DIR: &str = "/var/lib/cmk-agent";
-// Normally, the config would be expected at /etc/check_mk/, but we
-// need to read it as cmk-agent user, so we use its home directory.
-const CONFIG_FILE: &str = "cmk-agent-ctl-config.json";
-
-const STATE_FILE: &str = "cmk-agent-ctl-state.json";
-const LOG_FILE: &str = "cmk-agent-ctl.log";
-const LEGACY_PULL_FILE: &str = "allow-legacy-pull";
+const KEY_PASSPHRASE_CREDENTIAL: &str = "cmk-agent-ctl-key-passphrase";
+// Sites without ALPN support detect TLS by this marker, so it is sent in any case
 const TLS_ID: &[u8] = b"16";
+const CERT_EXPIRY_SECTION: &str = "cmk_agent_ctl_certificate_expiry";
+// Connections are accepted in separate threads, so the daemon has to poll for reloads
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const REGISTRATION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_REGISTRATION_WAIT_TIMEOUT: u64 = 3600;
+// Peers which stop reading or sending midway must not keep their connection open forever
+const PULL_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+// Every pull collects the monitoring data anew, so the daemon rejects any beyond this
+const MAX_PULL_CONNECTIONS: usize = 10;
+
+struct Pairing {
+    credentials: Secret,
+    host_name: String,
+    bundle: config::RegistrationBundle,
+    // The agent receiver of the site which answered
+    address: String,
+}
+
+fn value_or_prompt(value: Option<String>, question: &str, error: &str) -> AnyhowResult<String> {
+    match value {
+        Some(value) => Ok(value),
+        None if interactive::is_interactive() => interactive::prompt(question),
+        None => Err(anyhow!("{}", error)),
+    }
+}
+
+fn credentials_or_prompt(credentials: Option<Secret>, error: &str) -> AnyhowResult<Secret> {
+    match credentials {
+        Some(credentials) => Ok(credentials),
+        None if interactive::is_interactive() => {
+            let user = interactive::prompt("User")?;
+            let password = interactive::prompt_hidden("Password")?;
+            Ok(Secret::from(format!("{} {}", user, password.expose())))
+        }
+        None => Err(anyhow!("{}", error)),
+    }
+}
+
+fn confirm_root_cert(root_cert: &str, trust: &cli::TrustArgs) -> AnyhowResult<()> {
+    let fingerprint =
+        certs::fingerprint(root_cert).context("Error computing root certificate fingerprint.")?;
+
+    if let Some(expected) = &trust.fingerprint {
+        if certs::normalize_fingerprint(expected) != certs::normalize_fingerprint(&fingerprint) {
+            return Err(anyhow!(
+                "Root certificate fingerprint {} does not match the expected fingerprint {}",
+                fingerprint,
+                expected
+            ));
+        }
+        return Ok(());
+    }
+    if trust.trust_cert {
+        return Ok(());
+    }
+    if !interactive::is_interactive() {
+        return Err(anyhow!(
+            "Refusing to trust unverified root certificate with fingerprint {}, use --trust-cert or --fingerprint",
+            fingerprint
+        ));
+    }
+
+    eprintln!(
+        "The agent receiver presented the root certificate\n\tSubject: {}\n\tSHA256 fingerprint: {}",
+        certs::subject(root_cert).context("Error reading root certificate subject.")?,
+        fingerprint
+    );
+    match interactive::prompt("Do you want to trust this certificate? [y/N]") {
+        Ok(answer) if answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes") => {
+            Ok(())
+        }
+        _ => Err(anyhow!("Root certificate not trusted, aborting")),
+    }
+}
+
+// Trust on first use: once a root certificate was trusted for an address, a different one is
+// only accepted with --retrust, as it may be presented by a man in the middle
+fn check_pinned_root_cert(
+    agent_receiver_address: &str,
+    root_cert: &str,
+    pinned_fingerprint: Option<&str>,
+    trust: &cli::TrustArgs,
+) -> AnyhowResult<()> {
+    let fingerprint =
+        certs::fingerprint(root_cert).context("Error computing root certificate fingerprint.")?;
+    match pinned_fingerprint {
+        Some(pinned) if certs::normalize_fingerprint(pinned) == certs::normalize_fingerprint(&fingerprint) => {
+            Ok(())
+        }
+        Some(pinned) if !trust.retrust => Err(anyhow!(
+            "The root certificate of {} changed from fingerprint {} to {}, use --retrust if this is expected",
+            agent_receiver_address,
+            pinned,
+            fingerprint
+        )),
+        _ => confirm_root_cert(root_cert, trust),
+    }
+}
+
+fn pair(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    trust: &cli::TrustArgs,
+    uuid: Option<Uuid>,
+    trusted_roots: &config::TrustedRoots,
+) -> AnyhowResult<Pairing> {
+    let key_spec = key_spec(&config);
+    let csr_attributes = csr_attributes(&config);
+    let client_options = client_options(&config);
+    let agent_receiver_address = value_or_prompt(
+        config.agent_receiver_address,
+        "Agent receiver address",
+        "Server addresses not specified.",
+    )?;
+    let credentials =
+        credentials_or_prompt(config.credentials, "Missing credentials for registration.")?;
+    let host_name = value_or_prompt(
+        config.host_name,
+        "Host name",
+        "Missing host name for registration",
+    )?;
+
+    let uuid = uuid.unwrap_or_else(Uuid::new_v4).to_string();
+    let failover_addresses: Vec<String> = config
+        .failover_addresses
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|address| *address != agent_receiver_address)
+        .collect();
+    let mut addresses = vec![agent_receiver_address.clone()];
+    addresses.extend(failover_addresses.iter().cloned());
+    let (address, root_cert, api_version) = reach_site(
+        receiver,
+        config.root_certificate.as_ref(),
+        &agent_receiver_address,
+        &addresses,
+        trust,
+        trusted_roots,
+        &client_options,
+    )?;
+    let csr_attributes = certs::CsrAttributes {
+        host_name: Some(host_name.clone()),
+        ..csr_attributes
+    };
+    let (csr, private_key) =
+        certs::make_csr(&uuid, &key_spec, &csr_attributes).context("Error creating CSR.")?;
+    let certificate = agent_receiver_api::block_on(receiver.pairing(
+        &agent_receiver_api::base_url(&address, Some(api_version)),
+        &root_cert,
+        csr,
+        credentials.expose(),
+        &client_options,
+    ))
+    .context(format!("Error pairing with {}", &address))?;
+    certs::check_certificate(&certificate, &private_key, &uuid)
+        .context(format!("Invalid certificate received from {}", &address))?;
+    let capabilities =
+        discover_capabilities(receiver, &address, api_version, &root_cert, &client_options)
+            .unwrap_or_else(|error| {
+                warn!("{:#}", error);
+                None
+            });
+
+    let metadata = config::RegistrationMetadata {
+        registered_at: Some(now()),
+        site: certs::site_name(&root_cert),
+        api_version: Some(String::from(api_version)),
+        user: credentials
+            .expose()
+            .split_whitespace()
+            .next()
+            .map(String::from),
+    };
+    Ok(Pairing {
+        credentials,
+        host_name,
+        bundle: config::RegistrationBundle {
+            agent_receiver_address,
+            server_spec: config::ServerSpec {
+                uuid,
+                private_key,
+                certificate,
+                root_cert,
+                settings: config::ServerSettings::default(),
+                stale: None,
+                metadata,
+                previous_key: None,
+                failover_addresses,
+                capabilities,
+            },
+        },
+        address,
+    })
+}
+
+// Pairing goes through the first agent receiver of the site which can be reached. Whichever
+// one presents the root certificate, it is pinned for the address of the registration.
+fn reach_site(
+    receiver: &impl AgentReceiver,
+    root_certificate: Option<&String>,
+    agent_receiver_address: &str,
+    addresses: &[String],
+    trust: &cli::TrustArgs,
+    trusted_roots: &config::TrustedRoots,
+    client_options: &certs::ClientOptions,
+) -> AnyhowResult<(String, String, &'static str)> {
+    let mut last_error: Option<anyhow::Error> = None;
+    for address in addresses {
+        if let Some(error) = last_error.take() {
+            warn!("{:#}, trying {}", error, address);
+        }
+        let root_cert = match root_certificate {
+            Some(cert) => cert.clone(),
+            // There is no TLS handshake to take the root certificate from
+            None if address::is_unix_socket(address) => {
+                return Err(anyhow!(
+                    "Registering at the unix socket {} needs the root certificate of the site, set root_certificate_path",
+                    address
+                ))
+            }
+            None => match certs::fetch_root_cert(address, client_options) {
+                Ok(root_cert) => {
+                    check_pinned_root_cert(
+                        agent_receiver_address,
+                        &root_cert,
+                        trusted_roots
+                            .fingerprints
+                            .get(agent_receiver_address)
+                            .map(String::as_str),
+                        trust,
+                    )?;
+                    root_cert
+                }
+                Err(error) => {
+                    last_error = Some(error.context(format!(
+                        "Error establishing trust with agent_receiver {}",
+                        address
+                    )));
+                    continue;
+                }
+            },
+        };
+        match negotiate_api_version(receiver, address, &root_cert, client_options) {
+            Ok(api_version) => return Ok((address.clone(), root_cert, api_version)),
+            Err(error) if is_outage(&error) => last_error = Some(error),
+            Err(error) => return Err(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("Server addresses not specified.")))
+}
+
+fn is_outage(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ApiError>()
+        .is_some_and(ApiError::is_outage)
+}
+
+// The address of the registration comes first, unless one of its failover addresses worked
+// last, followed by the others in their configured order
+fn receiver_addresses(
+    agent_receiver_address: &str,
+    server_spec: &config::ServerSpec,
+    active_addresses: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut addresses = vec![String::from(agent_receiver_address)];
+    addresses.extend(server_spec.failover_addresses.iter().cloned());
+    if let Some(active_address) = active_addresses.get(agent_receiver_address) {
+        if let Some(index) = addresses
+            .iter()
+            .position(|address| address == active_address)
+        {
+            let active_address = addresses.remove(index);
+            addresses.insert(0, active_address);
+        }
+    }
+    addresses
+}
+
+fn active_addresses(paths: &paths::Paths) -> HashMap<String, String> {
+    config::RuntimeState::from_file(&paths.runtime_path)
+        .map(|runtime_state| runtime_state.active_addresses)
+        .unwrap_or_default()
+}
+
+fn remember_active_address(paths: &paths::Paths, agent_receiver_address: &str, address: &str) {
+    update_runtime_state(paths, |runtime_state| {
+        runtime_state.set_active_address(agent_receiver_address, address)
+    });
+}
+
+// Only unreachable agent receivers are skipped, the answer of any other one holds for the
+// whole site. Also returns the address which gave the result.
+async fn failover<'a, T, F: Future<Output = Result<T, ApiError>>>(
+    addresses: &'a [String],
+    request: impl Fn(&'a str) -> F,
+) -> (Result<T, ApiError>, &'a str) {
+    for (index, address) in addresses.iter().enumerate() {
+        let result = request(address).await;
+        match (&result, addresses.get(index + 1)) {
+            (Err(error), Some(next)) if error.is_outage() => {
+                warn!("{}: {}, trying {}", address, error, next)
+            }
+            _ => return (result, address),
+        }
+    }
+    (
+        Err(ApiError::Client(String::from("No agent receiver address"))),
+        "",
+    )
+}
+
+fn negotiate_api_version(
+    receiver: &impl AgentReceiver,
+    agent_receiver_address: &str,
+    root_cert: &str,
+    client_options: &certs::ClientOptions,
+) -> AnyhowResult<&'static str> {
+    agent_receiver_api::block_on(receiver.negotiate_api_version(
+        agent_receiver_address,
+        root_cert,
+        client_options,
+    ))
+    .context(format!(
+        "Error negotiating the API version with {}",
+        agent_receiver_address
+    ))
+}
+
+// Registrations work without knowing the capabilities, so callers only warn about errors
+fn discover_capabilities(
+    receiver: &impl AgentReceiver,
+    address: &str,
+    api_version: &str,
+    root_cert: &str,
+    client_options: &certs::ClientOptions,
+) -> AnyhowResult<Option<config::Capabilities>> {
+    agent_receiver_api::block_on(receiver.capabilities(
+        &agent_receiver_api::base_url(address, Some(api_version)),
+        root_cert,
+        client_options,
+    ))
+    .context(format!(
+        "Could not discover the capabilities of {}",
+        address
+    ))
+}
+
+// The API version negotiated on registration, or the first one for older registrations
+fn base_url(agent_receiver_address: &str, server_spec: &config::ServerSpec) -> String {
+    agent_receiver_api::base_url(
+        agent_receiver_address,
+        server_spec.metadata.api_version.as_deref(),
+    )
+}
+
+fn register_host(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    trust: &cli::TrustArgs,
+    uuid: Option<Uuid>,
+    trusted_roots: &config::TrustedRoots,
+) -> AnyhowResult<(config::RegistrationBundle, String)> {
+    let client_options = client_options(&config);
+    let pairing = pair(receiver, config, trust, uuid, trusted_roots)?;
+
+    agent_receiver_api::block_on(receiver.register_with_hostname(
+        &base_url(&pairing.address, &pairing.bundle.server_spec),
+        &pairing.bundle.server_spec.root_cert,
+        pairing.credentials.expose(),
+        &pairing.bundle.server_spec.uuid,
+        &pairing.host_name,
+        &client_options,
+    ))
+    .context(format!("Error registering {}", &pairing.address))?;
+
+    Ok((pairing.bundle, pairing.address))
+}
+
+fn register_dry_run(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    trust: &cli::TrustArgs,
+    uuid: Option<Uuid>,
+    trusted_roots: &config::TrustedRoots,
+) -> AnyhowResult<()> {
+    let pairing = pair(receiver, config, trust, uuid, trusted_roots)?;
+
+    println!(
+        "Root certificate SHA256 fingerprint: {}",
+        certs::fingerprint(&pairing.bundle.server_spec.root_cert)
+            .context("Error computing root certificate fingerprint.")?
+    );
+    println!("Pairing with {} successful", pairing.address);
+    println!(
+        "Dry run: Not registering host {} and not writing registration state",
+        pairing.host_name
+    );
+    Ok(())
+}
 
 fn register(
+    receiver: &impl AgentReceiver,
     config: config::Config,
-    mut reg_state: RegistrationState,
-    path_state_out: &Path,
+    paths: &paths::Paths,
+    register_args: &cli::RegisterArgs,
+) -> AnyhowResult<()> {
+    let trusted_roots = config::TrustedRoots::from_file(&paths.trusted_roots_path)
+        .context("Error reading trusted root certificates.")?;
+    if register_args.dry_run {
+        return register_dry_run(
+            receiver,
+            config,
+            &register_args.trust,
+            register_args.uuid,
+            &trusted_roots,
+        );
+    }
+
+    let client_options = client_options(&config);
+    let key_passphrase = key_passphrase(&config)?;
+    let (bundle, address) = register_host(
+        receiver,
+        config,
+        &register_args.trust,
+        register_args.uuid,
+        &trusted_roots,
+    )?;
+
+    let agent_receiver_address = bundle.agent_receiver_address;
+    let mut server_spec = bundle.server_spec;
+    let server_spec = update_reg_state(paths, key_passphrase, |reg_state| {
+        if let Some(previous) = reg_state.server_specs.get(&agent_receiver_address) {
+            println!(
+                "Replacing existing registration with {}",
+                agent_receiver_address
+            );
+            server_spec.settings = previous.settings.clone();
+        }
+        reg_state
+            .server_specs
+            .insert(agent_receiver_address.clone(), server_spec.clone());
+        Ok(server_spec)
+    })?;
+    pin_root_certs(paths, [(&agent_receiver_address, &server_spec)])?;
+
+    remember_active_address(paths, &agent_receiver_address, &address);
+
+    disallow_legacy_pull(paths)
+        .context("Registration successful, but could not delete marker for legacy pull mode")?;
+
+    if register_args.wait {
+        wait_for_approval(
+            receiver,
+            &agent_receiver_address,
+            &server_spec,
+            &receiver_addresses(
+                &agent_receiver_address,
+                &server_spec,
+                &active_addresses(paths),
+            ),
+            &client_options,
+            Duration::from_secs(
+                register_args
+                    .wait_timeout
+                    .unwrap_or(DEFAULT_REGISTRATION_WAIT_TIMEOUT),
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+// The registration is kept in any case, such that the host can push as soon as the site
+// accepts it later on
+fn wait_for_approval(
+    receiver: &impl AgentReceiver,
+    agent_receiver_address: &str,
+    server_spec: &config::ServerSpec,
+    addresses: &[String],
+    client_options: &certs::ClientOptions,
+    timeout: Duration,
+) -> AnyhowResult<()> {
+    if !server_spec.supports(|capabilities| capabilities.registration_status) {
+        println!(
+            "The agent receiver of {} cannot tell whether the site accepted the registration, not waiting",
+            agent_receiver_address
+        );
+        return Ok(());
+    }
+    let deadline = Instant::now() + timeout;
+    let mut announced = false;
+    loop {
+        let status = agent_receiver_api::block_on(async {
+            failover(addresses, |address| async move {
+                receiver
+                    .registration_status(
+                        &base_url(address, server_spec),
+                        &server_spec.root_cert,
+                        &server_spec.uuid,
+                        client_options,
+                    )
+                    .await
+            })
+            .await
+            .0
+        });
+        match status {
+            Ok(status) => match status.approval() {
+                agent_receiver_api::Approval::Accepted => {
+                    println!("Registration with {} accepted", agent_receiver_address);
+                    return Ok(());
+                }
+                agent_receiver_api::Approval::Declined => {
+                    return Err(anyhow!(
+                        "Registration with {} declined by the site{}, delete it with 'cmk-agent-ctl delete'",
+                        agent_receiver_address,
+                        status
+                            .message
+                            .map(|message| format!(": {}", message))
+                            .unwrap_or_default()
+                    ));
+                }
+                agent_receiver_api::Approval::Pending => {
+                    if !announced {
+                        println!(
+                            "Waiting for the site to accept the registration with {}{}",
+                            agent_receiver_address,
+                            status
+                                .message
+                                .map(|message| format!(" ({})", message))
+                                .unwrap_or_default()
+                        );
+                        announced = true;
+                    }
+                }
+            },
+            Err(error) if prune::is_rejection(&error) => {
+                return Err(error.context(format!(
+                    "Registration with {} is not known to the site anymore",
+                    agent_receiver_address
+                )));
+            }
+            Err(error) => println!("Could not query the registration status: {:#}", error),
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!(
+                "The site did not accept the registration with {} within {} seconds. It is kept, check it later with 'cmk-agent-ctl status'",
+                agent_receiver_address,
+                timeout.as_secs()
+            ));
+        }
+        thread::sleep(remaining.min(REGISTRATION_POLL_INTERVAL));
+    }
+}
+
+// Pinned after writing the state, if this fails, only the next registration has to confirm
+// the root certificate again
+fn pin_root_certs<'a>(
+    paths: &paths::Paths,
+    server_specs: impl IntoIterator<Item = (&'a String, &'a config::ServerSpec)>,
+) -> AnyhowResult<()> {
+    let mut trusted_roots = config::TrustedRoots::from_file(&paths.trusted_roots_path)
+        .context("Error reading trusted root certificates.")?;
+    for (agent_receiver_address, server_spec) in server_specs {
+        trusted_roots.fingerprints.insert(
+            agent_receiver_address.clone(),
+            certs::fingerprint(&server_spec.root_cert)
+                .context("Error computing root certificate fingerprint.")?,
+        );
+    }
+    trusted_roots
+        .to_file(&paths.trusted_roots_path)
+        .context("Error writing trusted root certificates.")
+}
+
+fn renew_certificate(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    reg_state: RegistrationState,
+    paths: &paths::Paths,
+) -> AnyhowResult<()> {
+    let agent_receiver_address = config
+        .agent_receiver_address
+        .clone()
+        .context("Server address not specified.")?;
+    let credentials = credentials_or_prompt(
+        config.credentials.clone(),
+        "Missing credentials for certificate renewal.",
+    )?;
+    let mut renewed = reg_state
+        .server_specs
+        .get(&agent_receiver_address)
+        .cloned()
+        .context(format!(
+            "No registration with {} found",
+            &agent_receiver_address
+        ))?;
+    renew(
+        receiver,
+        &config,
+        paths,
+        &agent_receiver_address,
+        &mut renewed,
+        credentials.expose(),
+    )?;
+
+    update_reg_state(paths, key_passphrase(&config)?, |reg_state| {
+        take_over_renewal(
+            registration(reg_state, &agent_receiver_address, &renewed.uuid)?,
+            renewed,
+        );
+        Ok(())
+    })
+}
+
+fn rotate_key(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    reg_state: RegistrationState,
+    paths: &paths::Paths,
+    revert: bool,
+) -> AnyhowResult<()> {
+    let agent_receiver_address = config
+        .agent_receiver_address
+        .clone()
+        .context("Server address not specified.")?;
+    let mut renewed = reg_state
+        .server_specs
+        .get(&agent_receiver_address)
+        .cloned()
+        .context(format!(
+            "No registration with {} found",
+            &agent_receiver_address
+        ))?;
+    if revert {
+        return update_reg_state(paths, key_passphrase(&config)?, |reg_state| {
+            let server_spec = registration(reg_state, &agent_receiver_address, &renewed.uuid)?;
+            let previous_key = server_spec.previous_key.take().context(format!(
+                "No key rotation to revert for {}",
+                &agent_receiver_address
+            ))?;
+            server_spec.private_key = previous_key.private_key;
+            server_spec.certificate = previous_key.certificate;
+            Ok(())
+        });
+    }
+
+    let credentials = credentials_or_prompt(
+        config.credentials.clone(),
+        "Missing credentials for key rotation.",
+    )?;
+    renew(
+        receiver,
+        &config,
+        paths,
+        &agent_receiver_address,
+        &mut renewed,
+        credentials.expose(),
+    )?;
+    update_reg_state(paths, key_passphrase(&config)?, |reg_state| {
+        let server_spec = registration(reg_state, &agent_receiver_address, &renewed.uuid)?;
+        let current_key = config::KeyPair {
+            private_key: server_spec.private_key.clone(),
+            certificate: server_spec.certificate.clone(),
+        };
+        take_over_renewal(server_spec, renewed);
+        // Rotating again before a pull keeps the pair that is known to work
+        server_spec.previous_key.get_or_insert(current_key);
+        Ok(())
+    })
+}
+
+// Other invocations may have changed the state during the requests to the agent receiver.
+// Registrations which were replaced meanwhile are left alone.
+fn registration<'a>(
+    reg_state: &'a mut RegistrationState,
+    agent_receiver_address: &str,
+    uuid: &str,
+) -> AnyhowResult<&'a mut config::ServerSpec> {
+    reg_state
+        .server_specs
+        .get_mut(agent_receiver_address)
+        .filter(|server_spec| server_spec.uuid == uuid)
+        .context(format!(
+            "Registration with {} was deleted or replaced meanwhile",
+            agent_receiver_address
+        ))
+}
+
+// Only takes over what renew changed, such that settings and the like changed meanwhile are kept
+fn take_over_renewal(server_spec: &mut config::ServerSpec, renewed: config::ServerSpec) {
+    server_spec.private_key = renewed.private_key;
+    server_spec.certificate = renewed.certificate;
+    server_spec.stale = renewed.stale;
+    server_spec.metadata.api_version = renewed.metadata.api_version;
+    server_spec.capabilities = renewed.capabilities;
+}
+
+// Once the site pulled with the new key pair, the one from before the rotation is not needed
+fn finish_key_rotation(
+    config: &config::Config,
+    paths: &paths::Paths,
+    uuid: &str,
+) -> AnyhowResult<()> {
+    let _lock = config::StateLock::exclusive(&paths.state_path)?;
+    let mut reg_state = load_reg_state(&paths.state_path, key_passphrase(config)?)?;
+    let mut finished = false;
+    for (agent_receiver_address, server_spec) in reg_state.server_specs.iter_mut() {
+        if server_spec.uuid == uuid && server_spec.previous_key.take().is_some() {
+            info!("Finished key rotation for {}", agent_receiver_address);
+            finished = true;
+        }
+    }
+    if finished {
+        reg_state
+            .to_file(&paths.state_path)
+            .context("Error writing registration state.")?;
+    }
+    Ok(())
+}
+
+fn renew(
+    receiver: &impl AgentReceiver,
+    config: &config::Config,
+    paths: &paths::Paths,
+    agent_receiver_address: &str,
+    server_spec: &mut config::ServerSpec,
+    credentials: &str,
+) -> AnyhowResult<()> {
+    let (csr, private_key) = certs::make_csr(
+        &server_spec.uuid,
+        &key_spec(config),
+        &csr_attributes(config),
+    )
+    .context("Error creating CSR.")?;
+    let addresses = receiver_addresses(
+        agent_receiver_address,
+        server_spec,
+        &active_addresses(paths),
+    );
+    let (client_options, root_cert) = (&client_options(config), &server_spec.root_cert);
+    let (result, address) = agent_receiver_api::block_on(async {
+        Ok(failover(&addresses, |address| {
+            let csr = csr.clone();
+            async move {
+                // The site may have been updated since the registration
+                let api_version = receiver
+                    .negotiate_api_version(address, root_cert, client_options)
+                    .await?;
+                let certificate = receiver
+                    .pairing(
+                        &agent_receiver_api::base_url(address, Some(api_version)),
+                        root_cert,
+                        csr,
+                        credentials,
+                        client_options,
+                    )
+                    .await?;
+                Ok((api_version, certificate))
+            }
+        })
+        .await)
+    })?;
+    let (api_version, certificate) = result.context(format!("Error pairing with {}", address))?;
+    certs::check_certificate(&certificate, &private_key, &server_spec.uuid)
+        .context(format!("Invalid certificate received from {}", address))?;
+    remember_active_address(paths, agent_receiver_address, address);
+    // The site may have been updated since the registration, also with respect to its features
+    match discover_capabilities(receiver, address, api_version, root_cert, client_options) {
+        Ok(capabilities) => server_spec.capabilities = capabilities,
+        Err(error) => warn!("{:#}", error),
+    }
+
+    // Only replace key and certificate together, the old pair stays valid until here
+    server_spec.private_key = private_key;
+    server_spec.certificate = certificate;
+    server_spec.stale = None;
+    server_spec.metadata.api_version = Some(String::from(api_version));
+    Ok(())
+}
+
+// The state is re-read under the lock, such that concurrent changes are not lost
+fn renew_expiring_certificates(
+    receiver: &impl AgentReceiver,
+    config: &config::Config,
+    paths: &paths::Paths,
+) -> AnyhowResult<()> {
+    let renew_before = match config.renew_before {
+        Some(renew_before) => renew_before,
+        None => return Ok(()),
+    };
+    let credentials = config
+        .credentials
+        .as_ref()
+        .map(Secret::expose)
+        .context("Cannot renew certificates automatically without credentials")?;
+    let reg_state = get_reg_state(
+        &paths.state_path,
+        key_passphrase_for_reading(config, &paths.state_path)?,
+    )?;
+
+    let now = now();
+    let mut renewed = vec![];
+    for (agent_receiver_address, server_spec) in &reg_state.server_specs {
+        let not_after = match certs::validity(&server_spec.certificate) {
+            Ok(validity) => validity.not_after,
+            Err(_) => continue,
+        };
+        if !certs::expires_within(not_after, now, renew_before) {
+            continue;
+        }
+        if !server_spec.supports(|capabilities| capabilities.renewal) {
+            warn!(
+                "The agent receiver of {} does not support certificate renewal, the certificate expires at {}",
+                agent_receiver_address,
+                certs::format_timestamp(not_after)
+            );
+            continue;
+        }
+        let mut server_spec = server_spec.clone();
+        match renew(
+            receiver,
+            config,
+            paths,
+            agent_receiver_address,
+            &mut server_spec,
+            credentials,
+        ) {
+            Ok(()) => {
+                info!("Renewed certificate for {}", agent_receiver_address);
+                renewed.push((agent_receiver_address.clone(), server_spec));
+            }
+            Err(error) => warn!(
+                "Could not renew certificate for {}: {:?}",
+                agent_receiver_address, error
+            ),
+        }
+    }
+    if renewed.is_empty() {
+        return Ok(());
+    }
+    update_reg_state(paths, key_passphrase(config)?, |reg_state| {
+        for (agent_receiver_address, renewed) in renewed {
+            match registration(reg_state, &agent_receiver_address, &renewed.uuid) {
+                Ok(server_spec) => take_over_renewal(server_spec, renewed),
+                Err(error) => warn!("{}", error),
+            }
+        }
+        Ok(())
+    })
+}
+
+// Renewing in the background keeps pushing and serving going. The daemon modes pick up the
+// written state through their state watcher and keep the shared configuration up to date.
+fn spawn_renewal(config: Arc<Mutex<config::Config>>, paths: &paths::Paths) {
+    let paths = paths.clone();
+    thread::spawn(move || loop {
+        let config = match config.lock() {
+            Ok(config) => config.clone(),
+            Err(_) => return,
+        };
+        if let Err(error) = renew_expiring_certificates(&agent_receiver_api::Http, &config, &paths)
+        {
+            warn!("Error renewing certificates: {:?}", error);
+        }
+        thread::sleep(RENEWAL_CHECK_INTERVAL);
+    });
+}
+
+fn watch_state(paths: &paths::Paths) -> Option<reload::StateWatcher> {
+    match reload::StateWatcher::new(&paths.state_path) {
+        Ok(state_watcher) => Some(state_watcher),
+        Err(error) => {
+            warn!(
+                "Could not watch {}, changes require a SIGHUP: {}",
+                paths.state_path.display(),
+                error
+            );
+            None
+        }
+    }
+}
+
+fn register_new(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    register_new_args: &cli::RegisterNewArgs,
+) -> AnyhowResult<()> {
+    // Registrations for other hosts are not pinned, they are trusted by importing them
+    let (bundle, _) = register_host(
+        receiver,
+        config,
+        &register_new_args.trust,
+        register_new_args.uuid,
+        &config::TrustedRoots::default(),
+    )?;
+    println!(
+        "{}",
+        bundle
+            .to_json()
+            .context("Error serializing registration bundle.")?
+    );
+    Ok(())
+}
+
+fn csr_export(
+    config: &config::Config,
+    paths: &paths::Paths,
+    uuid: Option<Uuid>,
+    path_out: Option<&Path>,
+) -> AnyhowResult<()> {
+    if paths.pending_path.exists() {
+        eprintln!("Replacing the pending offline registration");
+    }
+    let uuid = uuid.unwrap_or_else(Uuid::new_v4).to_string();
+    let (csr, private_key) = certs::make_csr(&uuid, &key_spec(config), &csr_attributes(config))
+        .context("Error creating CSR.")?;
+
+    config::PendingRegistration { uuid, private_key }
+        .to_file(&paths.pending_path)
+        .context("Error writing pending registration.")?;
+    write_output(path_out, &csr)
+}
+
+fn cert_import(
+    config: config::Config,
+    paths: &paths::Paths,
+    cert_import_args: &cli::CertImportArgs,
+) -> AnyhowResult<()> {
+    let key_passphrase = key_passphrase(&config)?;
+    let agent_receiver_address = value_or_prompt(
+        config.agent_receiver_address,
+        "Agent receiver address",
+        "Server addresses not specified.",
+    )?;
+    let pending = config::PendingRegistration::from_file(&paths.pending_path)
+        .context("No pending offline registration found, run csr-export first.")?;
+    let certificate = read_input(cert_import_args.file.as_deref())?;
+    let root_cert = fs::read_to_string(&cert_import_args.root_cert).context(format!(
+        "Error reading {}.",
+        cert_import_args.root_cert.display()
+    ))?;
+
+    certs::check_certificate(&certificate, &pending.private_key, &pending.uuid)
+        .context("Certificate does not match the pending registration.")?;
+    certs::fingerprint(&root_cert).context("Error parsing root certificate.")?;
+
+    // Offline registrations never talk to the receiver, so there is no user or API version
+    let metadata = config::RegistrationMetadata {
+        registered_at: Some(now()),
+        site: certs::site_name(&root_cert),
+        api_version: None,
+        user: None,
+    };
+    let server_spec = config::ServerSpec {
+        uuid: pending.uuid,
+        private_key: pending.private_key,
+        certificate,
+        root_cert,
+        settings: config::ServerSettings::default(),
+        stale: None,
+        metadata,
+        previous_key: None,
+        failover_addresses: config.failover_addresses.clone().unwrap_or_default(),
+        capabilities: None,
+    };
+    update_reg_state(paths, key_passphrase, |reg_state| {
+        reg_state
+            .server_specs
+            .insert(agent_receiver_address.clone(), server_spec.clone());
+        Ok(())
+    })?;
+    pin_root_certs(paths, [(&agent_receiver_address, &server_spec)])?;
+    fs::remove_file(&paths.pending_path).context("Error removing pending registration.")?;
+
+    disallow_legacy_pull(paths)
+        .context("Import successful, but could not delete marker for legacy pull mode")?;
+    Ok(())
+}
+
+fn restore_state(
+    paths: &paths::Paths,
+    restore_state_args: &cli::RestoreStateArgs,
+) -> AnyhowResult<()> {
+    let backups =
+        config::state_backups(&paths.state_path).context("Error listing state backups.")?;
+    if restore_state_args.list {
+        for backup in backups {
+            println!("{}", backup.display());
+        }
+        return Ok(());
+    }
+
+    let backup = match &restore_state_args.backup {
+        Some(backup) => backup.clone(),
+        None => backups
+            .into_iter()
+            .next()
+            .context("No state backup found.")?,
+    };
+    let _lock = config::StateLock::exclusive(&paths.state_path)
+        .context("Error locking registration state.")?;
+    config::restore_state(&paths.state_path, &backup)
+        .context(format!("Error restoring state from {}.", backup.display()))?;
+    println!("Restored registration state from {}", backup.display());
+    Ok(())
+}
+
+fn read_input(path: Option<&Path>) -> AnyhowResult<String> {
+    match path {
+        Some(path) => {
+            fs::read_to_string(path).context(format!("Error reading {}.", path.display()))
+        }
+        None => {
+            let mut input = String::new();
+            io::stdin()
+                .read_to_string(&mut input)
+                .context("Error reading from stdin.")?;
+            Ok(input)
+        }
+    }
+}
+
+fn write_output(path: Option<&Path>, output: &str) -> AnyhowResult<()> {
+    match path {
+        // The output may contain private keys, so don't make it world-readable
+        Some(path) => fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(output.as_bytes()))
+            .context(format!("Error writing {}.", path.display())),
+        None => {
+            println!("{}", output);
+            Ok(())
+        }
+    }
+}
+
+fn read_passphrase(path: Option<&Path>) -> AnyhowResult<Option<Secret>> {
+    match path {
+        Some(path) => {
+            let mut content = fs::read_to_string(path)
+                .context(format!("Error reading passphrase from {}.", path.display()))?;
+            let passphrase = Secret::from(content.trim_end_matches('\n').to_string());
+            secret::wipe(&mut content);
+            Ok(Some(passphrase))
+        }
+        None => Ok(None),
+    }
+}
+
+fn import(
+    config: &config::Config,
+    paths: &paths::Paths,
+    path_in: Option<&Path>,
+    passphrase: Option<Secret>,
+) -> AnyhowResult<()> {
+    let mut serialized = read_input(path_in)?;
+    if let Ok(encrypted) = serde_json::from_str::<crypto::Encrypted>(&serialized) {
+        let passphrase = passphrase.context("Input is encrypted, but no passphrase given.")?;
+        serialized = String::from_utf8(crypto::decrypt(&encrypted, passphrase.expose())?)
+            .context("Decrypted input is not valid UTF-8.")?;
+    }
+    let importable = config::Importable::from_json(&serialized)
+        .context("Error parsing registration bundle or exported state.")?;
+
+    let server_specs = importable.into_server_specs();
+    update_reg_state(paths, key_passphrase(config)?, |reg_state| {
+        reg_state.server_specs.extend(server_specs.clone());
+        Ok(())
+    })?;
+    pin_root_certs(paths, &server_specs)?;
+
+    disallow_legacy_pull(paths)
+        .context("Import successful, but could not delete marker for legacy pull mode")?;
+    Ok(())
+}
+
+fn export(
+    reg_state: RegistrationState,
+    path_out: Option<&Path>,
+    passphrase: Option<Secret>,
+) -> AnyhowResult<()> {
+    let serialized = reg_state
+        .to_json()
+        .context("Error serializing registration state.")?;
+    let output = match passphrase {
+        Some(passphrase) => serde_json::to_string(&crypto::encrypt(
+            serialized.as_bytes(),
+            passphrase.expose(),
+        )?)?,
+        None => serialized,
+    };
+    write_output(path_out, &output)
+}
+
+// Existing files are not overwritten, they might have looser permissions than the key needs
+fn export_credentials(
+    config: &config::Config,
+    reg_state: &RegistrationState,
+    dir: Option<&Path>,
+) -> AnyhowResult<()> {
+    let agent_receiver_address = config
+        .agent_receiver_address
+        .as_ref()
+        .context("Server address not specified.")?;
+    let server_spec = reg_state
+        .server_specs
+        .get(agent_receiver_address)
+        .context(format!(
+            "No registration with {} found",
+            agent_receiver_address
+        ))?;
+    if pkcs11::is_uri(server_spec.private_key.expose()) {
+        return Err(anyhow!(
+            "The private key of this registration is kept in a PKCS#11 token and cannot be exported"
+        ));
+    }
+    let dir = dir.unwrap_or_else(|| Path::new("."));
+    for (name, content, mode) in [
+        ("cert.pem", server_spec.certificate.as_str(), 0o644),
+        ("key.pem", server_spec.private_key.expose(), 0o600),
+        ("root-cert.pem", server_spec.root_cert.as_str(), 0o644),
+    ] {
+        let path = dir.join(name);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .context(format!("Error writing {}.", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+fn delete(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    reg_state: RegistrationState,
+    paths: &paths::Paths,
+    local_only: bool,
+) -> AnyhowResult<()> {
+    let client_options = client_options(&config);
+    let key_passphrase = key_passphrase(&config)?;
+    let agent_receiver_address = config
+        .agent_receiver_address
+        .context("Server address not specified.")?;
+
+    let server_spec = reg_state
+        .server_specs
+        .get(&agent_receiver_address)
+        .context(format!(
+            "No registration with {} found",
+            &agent_receiver_address
+        ))?;
+
+    if !local_only {
+        let credentials = credentials_or_prompt(
+            config.credentials,
+            "Missing credentials for deregistration.",
+        )?;
+        let addresses = receiver_addresses(
+            &agent_receiver_address,
+            server_spec,
+            &active_addresses(paths),
+        );
+        let client_options = &client_options;
+        let credentials = credentials.expose();
+        agent_receiver_api::block_on(async {
+            failover(&addresses, |address| async move {
+                receiver
+                    .unregister(
+                        &base_url(address, server_spec),
+                        &server_spec.root_cert,
+                        credentials,
+                        &server_spec.uuid,
+                        client_options,
+                    )
+                    .await
+            })
+            .await
+            .0
+        })
+        .context(format!(
+            "Error deregistering from {}, use --local-only to only delete the local registration",
+            &agent_receiver_address
+        ))?;
+    }
+
+    // A registration which replaced this one meanwhile is kept
+    let remaining = update_reg_state(paths, key_passphrase, |reg_state| {
+        if reg_state
+            .server_specs
+            .get(&agent_receiver_address)
+            .is_some_and(|current| current.uuid == server_spec.uuid)
+        {
+            reg_state.server_specs.remove(&agent_receiver_address);
+        }
+        Ok(reg_state.server_specs.len())
+    })?;
+    if let Err(error) = spool::Spool::new(&paths.spool_dir, &server_spec.uuid).discard() {
+        warn!("Could not discard spooled monitoring data: {}", error);
+    }
+    update_stats(paths, |stats| {
+        stats.receivers.remove(&agent_receiver_address);
+    });
+    update_runtime_state(paths, |runtime_state| {
+        runtime_state
+            .active_addresses
+            .remove(&agent_receiver_address);
+    });
+
+    if remaining == 0 && config.legacy_pull != Some(config::LegacyPull::Never) {
+        allow_legacy_pull(paths).context(
+            "Deleted last registration, but could not restore marker for legacy pull mode",
+        )?;
+    }
+    Ok(())
+}
+
+// With a single receiver, the agent output is streamed to it, and collected again if the push
+// has to be repeated. Several receivers share the output collected once instead of running the
+// agent for each of them, as does the spool, which needs the data after a failed push.
+enum MonitoringDataSource<'a> {
+    Stream {
+        config: &'a config::Config,
+        connected: Mutex<Option<Box<dyn Read + Send>>>,
+    },
+    Shared(Arc<[u8]>),
+}
+
+impl<'a> MonitoringDataSource<'a> {
+    fn new(config: &'a config::Config, shared: bool) -> IoResult<MonitoringDataSource<'a>> {
+        Ok(if shared {
+            MonitoringDataSource::Shared(monitoring_data::fetch(config)?.into())
+        } else {
+            // Connect right away, such that a missing agent is reported as before
+            MonitoringDataSource::Stream {
+                config,
+                connected: Mutex::new(Some(monitoring_data::connect(config)?)),
+            }
+        })
+    }
+
+    fn open(&self) -> IoResult<Box<dyn Read + Send>> {
+        match self {
+            MonitoringDataSource::Stream { config, connected } => {
+                match connected
+                    .lock()
+                    .ok()
+                    .and_then(|mut connected| connected.take())
+                {
+                    Some(reader) => Ok(reader),
+                    None => monitoring_data::connect(config),
+                }
+            }
+            MonitoringDataSource::Shared(mon_data) => {
+                Ok(Box::new(io::Cursor::new(Arc::clone(mon_data))))
+            }
+        }
+    }
+}
+
+// Spooled payloads are pushed oldest first once the receiver takes data again, and kept if it
+// becomes unreachable in between. Returns how long the receiver asked to be left alone, if it did.
+async fn replay_spool(
+    receiver: &impl AgentReceiver,
+    agent_receiver_address: &str,
+    server_spec: &config::ServerSpec,
+    spool: &spool::Spool,
+    push_options: &agent_receiver_api::PushOptions,
+    client_options: &certs::ClientOptions,
+) -> Option<Duration> {
+    let entries = match spool.entries() {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!(
+                "Could not read spooled monitoring data for {}: {}",
+                agent_receiver_address, error
+            );
+            return None;
+        }
+    };
+    let mut replayed = 0;
+    let mut retry_after = None;
+    for entry in &entries {
+        let result = receiver
+            .agent_data(
+                &base_url(agent_receiver_address, server_spec),
+                server_spec,
+                || -> IoResult<Box<dyn Read + Send>> { Ok(Box::new(spool::Spool::open(entry)?)) },
+                Some(entry.collected_at),
+                push_options,
+                client_options,
+            )
+            .await;
+        match result {
+            Ok(_) => replayed += 1,
+            Err(error) if error.is_outage() => {
+                warn!(
+                    "Stopped pushing spooled monitoring data to {}: {}",
+                    agent_receiver_address, error
+                );
+                retry_after = error.retry_after();
+                break;
+            }
+            Err(error) => warn!(
+                "Dropping spooled monitoring data collected at {} for {}: {}",
+                entry.collected_at, agent_receiver_address, error
+            ),
+        }
+        if let Err(error) = spool::Spool::remove(entry) {
+            warn!(
+                "Could not remove spooled monitoring data for {}: {}",
+                agent_receiver_address, error
+            );
+            break;
+        }
+    }
+    if replayed > 0 {
+        println!(
+            "{}: Pushed {} spooled payloads of {}",
+            agent_receiver_address,
+            replayed,
+            entries.len()
+        );
+    }
+    retry_after
+}
+
+fn push(
+    receiver: &impl AgentReceiver,
+    config: &config::Config,
+    reg_state: &config::RegistrationState,
+    paths: &paths::Paths,
+) -> AnyhowResult<()> {
+    push_to(
+        receiver,
+        config,
+        &reg_state.server_specs.iter().collect::<Vec<_>>(),
+        paths,
+    )
+}
+
+// Receivers which asked for a break with Retry-After are left out until it is over. So are
+// receivers which got data less than min_push_interval ago, or the push waits for the interval
+// to pass.
+fn throttle<'a>(
+    config: &config::Config,
+    server_specs: &[(&'a String, &'a config::ServerSpec)],
+    paths: &paths::Paths,
+) -> AnyhowResult<Vec<(&'a String, &'a config::ServerSpec)>> {
+    let runtime_state = match config::RuntimeState::from_file(&paths.runtime_path) {
+        Ok(runtime_state) => runtime_state,
+        Err(error) => {
+            warn!(
+                "Could not read runtime state, not throttling pushes: {}",
+                error
+            );
+            return Ok(server_specs.to_vec());
+        }
+    };
+    let now = now();
+
+    let mut ready = vec![];
+    for (agent_receiver_address, server_spec) in server_specs {
+        match runtime_state
+            .busy_until
+            .get(agent_receiver_address.as_str())
+        {
+            Some(busy_until) if *busy_until > now => eprintln!(
+                "{}: Not pushing, the agent receiver asked to wait until {}",
+                agent_receiver_address,
+                certs::format_timestamp(*busy_until)
+            ),
+            _ => ready.push((*agent_receiver_address, *server_spec)),
+        }
+    }
+    if ready.is_empty() && !server_specs.is_empty() {
+        return Err(anyhow!(
+            "Not pushing, the agent receivers asked to wait before the next push"
+        ));
+    }
+    let server_specs = &ready[..];
+
+    let min_push_interval = config.min_push_interval.unwrap_or(0) as i64;
+    if min_push_interval == 0 {
+        return Ok(ready);
+    }
+    let pushed_at = runtime_state.pushed_at;
+    let remaining = |address: &str| {
+        pushed_at
+            .get(address)
+            .map(|pushed_at| pushed_at + min_push_interval - now)
+            .filter(|remaining| *remaining > 0)
+    };
+
+    if config.push_throttle == Some(config::PushThrottle::Delay) {
+        if let Some(delay) = server_specs
+            .iter()
+            .filter_map(|(address, _)| remaining(address))
+            .max()
+        {
+            println!(
+                "Delaying push by {}s to keep min_push_interval of {}s",
+                delay, min_push_interval
+            );
+            thread::sleep(Duration::from_secs(delay as u64));
+        }
+        return Ok(server_specs.to_vec());
+    }
+
+    let mut allowed = vec![];
+    for (agent_receiver_address, server_spec) in server_specs {
+        match remaining(agent_receiver_address) {
+            Some(remaining) => {
+                warn!(
+                    "Not pushing to {}, which got data less than min_push_interval ago",
+                    agent_receiver_address
+                );
+                eprintln!(
+                    "{}: Not pushing, the last push was less than {}s ago, next push possible in {}s",
+                    agent_receiver_address, min_push_interval, remaining
+                );
+            }
+            None => allowed.push((*agent_receiver_address, *server_spec)),
+        }
+    }
+    if allowed.is_empty() && !server_specs.is_empty() {
+        return Err(anyhow!(
+            "Refusing to push more often than every {} seconds, see min_push_interval",
+            min_push_interval
+        ));
+    }
+    Ok(allowed)
+}
+
+fn push_to(
+    receiver: &impl AgentReceiver,
+    config: &config::Config,
+    server_specs: &[(&String, &config::ServerSpec)],
+    paths: &paths::Paths,
+) -> AnyhowResult<()> {
+    let server_specs = &throttle(config, server_specs, paths)?;
+    let spool_size = config.push_spool_size.unwrap_or(0) as usize;
+    let source = MonitoringDataSource::new(config, server_specs.len() > 1 || spool_size > 0)
+        .context("Error collecting monitoring data")?;
+    let collected_at = now();
+    let expiry_section =
+        expiry_section(config, &certificate_expiries(server_specs.iter().copied()));
+
+    // Push to all sites concurrently, even if one of them fails
+    let active_addresses = active_addresses(paths);
+    let client_options = client_options(config);
+    let push_options = agent_receiver_api::PushOptions {
+        timeout: push_timeout(config),
+        compression: push_compression(config),
+        sign: config.push_signature == Some(true),
+        encryption_key: push_encryption_key(config)?,
+        rate_limit: config.push_rate_limit.map(rate_limit::RateLimit::new),
+    };
+    let results = agent_receiver_api::block_on(async {
+        Ok(futures_util::future::join_all(server_specs.iter().map(
+            |(agent_receiver_address, server_spec)| {
+                let settings = server_spec.settings.apply(config);
+                let (source, expiry_section) = (&source, &expiry_section);
+                let mon_data = move || -> IoResult<Box<dyn Read + Send>> {
+                    Ok(Box::new(
+                        monitoring_data::SectionFilter::new(source.open()?, &settings)
+                            .chain(io::Cursor::new(expiry_section.clone())),
+                    ))
+                };
+                let (client_options, push_options) = (&client_options, &push_options);
+                let spool = spool::Spool::new(&paths.spool_dir, &server_spec.uuid);
+                let addresses =
+                    receiver_addresses(agent_receiver_address, server_spec, &active_addresses);
+                async move {
+                    let started = Instant::now();
+                    let mon_data = &mon_data;
+                    let (result, address) = failover(&addresses, |address| async move {
+                        receiver
+                            .agent_data(
+                                &base_url(address, server_spec),
+                                server_spec,
+                                mon_data,
+                                None,
+                                push_options,
+                                client_options,
+                            )
+                            .await
+                    })
+                    .await;
+                    let latency = started.elapsed();
+                    let address = String::from(address);
+                    let mut retry_after = result.as_ref().err().and_then(ApiError::retry_after);
+                    match &result {
+                        Ok(_) if spool_size > 0 => {
+                            retry_after = replay_spool(
+                                receiver,
+                                &address,
+                                server_spec,
+                                &spool,
+                                push_options,
+                                client_options,
+                            )
+                            .await
+                        }
+                        Err(error) if spool_size > 0 && error.is_outage() => {
+                            match mon_data()
+                                .and_then(|payload| spool.store(collected_at, payload, spool_size))
+                            {
+                                Ok(()) => info!(
+                                    "Spooled monitoring data for {} until it is reachable again",
+                                    agent_receiver_address
+                                ),
+                                Err(error) => warn!(
+                                    "Could not spool monitoring data for {}: {}",
+                                    agent_receiver_address, error
+                                ),
+                            }
+                        }
+                        _ => {}
+                    }
+                    (result, retry_after, latency, address)
+                }
+            },
+        ))
+        .await)
+    })?;
+
+    let mut failed = vec![];
+    let mut errors = HashMap::new();
+    let mut retry_afters = HashMap::new();
+    let mut latencies = HashMap::new();
+    let mut answered = HashMap::new();
+    for ((agent_receiver_address, _), (result, retry_after, latency, address)) in
+        server_specs.iter().zip(results)
+    {
+        if let Some(retry_after) = retry_after {
+            retry_afters.insert(agent_receiver_address.as_str(), retry_after);
+        }
+        latencies.insert(agent_receiver_address.as_str(), latency);
+        match result {
+            Ok(message) => {
+                println!("{}: {}", agent_receiver_address, message);
+                answered.insert(agent_receiver_address.as_str(), address);
+            }
+            Err(error) => {
+                warn!(
+                    "Error pushing monitoring data to {}: {}",
+                    agent_receiver_address, error
+                );
+                eprintln!(
+                    "{}: Error pushing monitoring data: {}",
+                    agent_receiver_address, error
+                );
+                failed.push(agent_receiver_address.as_str());
+                errors.insert(agent_receiver_address.as_str(), anyhow::Error::from(error));
+            }
+        }
+    }
+
+    let now = now();
+    update_stats(paths, |stats| {
+        for (agent_receiver_address, _) in server_specs {
+            let outcome = match errors.get(agent_receiver_address.as_str()) {
+                Some(error) => Err(error.to_string()),
+                None => Ok(latencies[agent_receiver_address.as_str()]),
+            };
+            stats.record(agent_receiver_address, outcome, now);
+        }
+    });
+    let mut stale = vec![];
+    update_runtime_state(paths, |runtime_state| {
+        if failed.len() < server_specs.len() {
+            runtime_state.last_push = Some(now);
+        }
+        for (agent_receiver_address, server_spec) in server_specs {
+            let error = errors.get(agent_receiver_address.as_str());
+            let failing_since =
+                runtime_state.record_result(agent_receiver_address, error.is_none(), now);
+            if error.is_none() {
+                runtime_state
+                    .pushed_at
+                    .insert(agent_receiver_address.to_string(), now);
+            }
+            if let Some(address) = answered.get(agent_receiver_address.as_str()) {
+                runtime_state.set_active_address(agent_receiver_address, address);
+            }
+            match retry_afters.get(agent_receiver_address.as_str()) {
+                Some(retry_after) => runtime_state.busy_until.insert(
+                    agent_receiver_address.to_string(),
+                    now + retry_after.as_secs() as i64,
+                ),
+                None => runtime_state
+                    .busy_until
+                    .remove(agent_receiver_address.as_str()),
+            };
+            if server_spec.stale.is_some() {
+                continue;
+            }
+            if let Some(reason) =
+                prune::stale_reason(error, failing_since, now, stale_after(config))
+            {
+                warn!(
+                    "Registration with {} looks stale, consider running prune: {}",
+                    agent_receiver_address, reason
+                );
+                stale.push((agent_receiver_address.to_string(), reason));
+            }
+        }
+    });
+    if config.mark_stale_on_push == Some(true) && !stale.is_empty() {
+        if let Err(error) = mark_stale(config, paths, stale) {
+            warn!("Could not mark stale registrations: {:?}", error);
+        }
+    }
+
+    // With a single failure, its error decides the exit code
+    if let [agent_receiver_address] = failed[..] {
+        if let Some(error) = errors.remove(agent_receiver_address) {
+            return Err(error.context(format!(
+                "Pushing monitoring data failed for {}",
+                agent_receiver_address
+            )));
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "Pushing monitoring data failed for {}",
+            failed.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn client_options(config: &config::Config) -> certs::ClientOptions {
+    certs::ClientOptions {
+        proxy_url: config.proxy_url.clone(),
+        tls_verify: config.tls_verify.unwrap_or_default(),
+        verify_hostname: config.tls_verify_hostname != Some(false),
+        receiver_cert_fingerprint: config.receiver_cert_fingerprint.clone(),
+        fips: fips::is_enabled(config.crypto_policy),
+        tls_key_log: tls_key_log(config),
+        connect_timeout: Duration::from_secs(
+            config
+                .connect_timeout
+                .unwrap_or(config::DEFAULT_CONNECT_TIMEOUT),
+        ),
+        request_timeout: Duration::from_secs(
+            config
+                .request_timeout
+                .unwrap_or(config::DEFAULT_REQUEST_TIMEOUT),
+        ),
+        retries: config.api_retries.unwrap_or(config::DEFAULT_API_RETRIES),
+        retry_backoff: Duration::from_secs(
+            config
+                .api_retry_backoff
+                .unwrap_or(config::DEFAULT_API_RETRY_BACKOFF),
+        ),
+        http_headers: config.http_headers.clone().unwrap_or_default(),
+    }
+}
+
+// Only in effect if SSLKEYLOGFILE is set as well
+fn tls_key_log(config: &config::Config) -> bool {
+    config.tls_key_log == Some(true) && env::var_os(certs::KEY_LOG_FILE_VAR).is_some()
+}
+
+fn key_spec(config: &config::Config) -> certs::KeySpec {
+    certs::KeySpec {
+        algorithm: config.key_algorithm.unwrap_or(config::KeyAlgorithm::Rsa),
+        rsa_key_size: config.rsa_key_size.unwrap_or(config::DEFAULT_RSA_KEY_SIZE),
+        fips: fips::is_enabled(config.crypto_policy),
+        pkcs11_token: config.pkcs11_token.clone(),
+    }
+}
+
+fn csr_attributes(config: &config::Config) -> certs::CsrAttributes {
+    certs::CsrAttributes {
+        host_name: config.host_name.clone(),
+        attributes: config.csr_attributes.clone().unwrap_or_default(),
+    }
+}
+
+fn stale_after(config: &config::Config) -> u64 {
+    config.stale_after.unwrap_or(config::DEFAULT_STALE_AFTER)
+}
+
+fn cert_expiry_warning(config: &config::Config) -> u64 {
+    config
+        .cert_expiry_warning
+        .unwrap_or(config::DEFAULT_CERT_EXPIRY_WARNING)
+}
+
+// Certificates which cannot be parsed are reported by the status mode instead
+fn certificate_expiries<'a>(
+    server_specs: impl Iterator<Item = (&'a String, &'a config::ServerSpec)>,
+) -> Vec<(String, i64)> {
+    server_specs
+        .filter_map(|(agent_receiver_address, server_spec)| {
+            certs::validity(&server_spec.certificate)
+                .ok()
+                .map(|validity| (agent_receiver_address.to_string(), validity.not_after))
+        })
+        .collect()
+}
+
+fn warn_about_expiries(config: &config::Config, cert_expiries: &[(String, i64)]) -> Vec<String> {
+    let now = now();
+    cert_expiries
+        .iter()
+        .filter(|(_, not_after)| {
+            certs::expires_within(*not_after, now, cert_expiry_warning(config))
+        })
+        .map(|(agent_receiver_address, not_after)| {
+            warn!(
+                "Certificate for {} expires at {}, run renew-certificate",
+                agent_receiver_address,
+                certs::format_timestamp(*not_after)
+            );
+            format!("{}\t{}", agent_receiver_address, not_after)
+        })
+        .collect()
+}
+
+// Expiring certificates are reported to the site as well, such that they show up in the
+// monitoring before the TLS connections break
+fn expiry_section(config: &config::Config, cert_expiries: &[(String, i64)]) -> Vec<u8> {
+    monitoring_data::section(
+        CERT_EXPIRY_SECTION,
+        &warn_about_expiries(config, cert_expiries),
+    )
+}
+
+// Pushing does not hold the state lock, so the state is re-read under the lock
+fn mark_stale(
+    config: &config::Config,
+    paths: &paths::Paths,
+    stale: Vec<(String, String)>,
+) -> AnyhowResult<()> {
+    let _lock = config::StateLock::exclusive(&paths.state_path)?;
+    let mut reg_state = load_reg_state(&paths.state_path, key_passphrase(config)?)?;
+    for (agent_receiver_address, reason) in stale {
+        if let Some(server_spec) = reg_state.server_specs.get_mut(&agent_receiver_address) {
+            server_spec.stale = Some(reason);
+        }
+    }
+    reg_state
+        .to_file(&paths.state_path)
+        .context("Error writing registration state.")
+}
+
+fn prune(
+    receiver: &impl AgentReceiver,
+    config: config::Config,
+    mut reg_state: config::RegistrationState,
+    paths: &paths::Paths,
+    yes: bool,
+) -> AnyhowResult<()> {
+    let now = now();
+    let active_addresses = active_addresses(paths);
+    let mut outcomes = vec![];
+    // The agent receivers are asked before the runtime state is locked for recording the results
+    let mut results = vec![];
+    for (agent_receiver_address, server_spec) in &reg_state.server_specs {
+        // Agent receivers without registration status cannot tell whether registrations are
+        // stale
+        if !server_spec.supports(|capabilities| capabilities.registration_status) {
+            continue;
+        }
+        let addresses = receiver_addresses(agent_receiver_address, server_spec, &active_addresses);
+        let client_options = &client_options(&config);
+        let started = Instant::now();
+        let result = agent_receiver_api::block_on(async {
+            let (result, address) = failover(&addresses, |address| async move {
+                receiver
+                    .registration_status(
+                        &base_url(address, server_spec),
+                        &server_spec.root_cert,
+                        &server_spec.uuid,
+                        client_options,
+                    )
+                    .await
+            })
+            .await;
+            result.map(|_| String::from(address))
+        });
+        outcomes.push((
+            agent_receiver_address.clone(),
+            match &result {
+                Ok(_) => Ok(started.elapsed()),
+                Err(error) => Err(error.to_string()),
+            },
+        ));
+        results.push((agent_receiver_address.clone(), result));
+    }
+    update_runtime_state(paths, |runtime_state| {
+        for (agent_receiver_address, result) in results {
+            let error = match result {
+                Ok(address) => {
+                    runtime_state.set_active_address(&agent_receiver_address, &address);
+                    None
+                }
+                Err(error) => Some(error),
+            };
+            let failing_since =
+                runtime_state.record_result(&agent_receiver_address, error.is_none(), now);
+            let server_spec = match reg_state.server_specs.get_mut(&agent_receiver_address) {
+                Some(server_spec) => server_spec,
+                None => continue,
+            };
+            match prune::stale_reason(error.as_ref(), failing_since, now, stale_after(&config)) {
+                Some(reason) => server_spec.stale = Some(reason),
+                // A receiver which works again is not stale anymore
+                None if error.is_none() => server_spec.stale = None,
+                None => {}
+            }
+        }
+    });
+    update_stats(paths, |stats| {
+        for (agent_receiver_address, outcome) in outcomes {
+            stats.record(&agent_receiver_address, outcome, now);
+        }
+    });
+
+    let stale: Vec<(&String, &String)> = reg_state
+        .server_specs
+        .iter()
+        .filter_map(|(address, server_spec)| Some((address, server_spec.stale.as_ref()?)))
+        .collect();
+    if stale.is_empty() {
+        println!("No stale registrations");
+    }
+    for (address, reason) in &stale {
+        println!("{}: {}", address, reason);
+    }
+
+    let remove = !stale.is_empty()
+        && (yes
+            || (interactive::is_interactive()
+                && interactive::prompt(&format!(
+                    "Remove {} stale registration(s)? [y/N]",
+                    stale.len()
+                ))
+                .is_ok_and(|answer| answer.eq_ignore_ascii_case("y"))));
+    // Only the registrations checked above are changed, whatever else happened to the state
+    // meanwhile is kept
+    let checked: Vec<(String, String, Option<String>)> = reg_state
+        .server_specs
+        .iter()
+        .map(|(address, server_spec)| {
+            let (uuid, stale) = (server_spec.uuid.clone(), server_spec.stale.clone());
+            (address.clone(), uuid, stale)
+        })
+        .collect();
+    update_reg_state(paths, key_passphrase(&config)?, |current| {
+        for (address, uuid, stale) in checked {
+            let server_spec = match registration(current, &address, &uuid) {
+                Ok(server_spec) => server_spec,
+                Err(_) => continue,
+            };
+            // The receivers do not know the UUIDs anymore or cannot be reached, so only the
+            // local registrations are removed
+            if remove && stale.is_some() {
+                current.server_specs.remove(&address);
+            } else {
+                server_spec.stale = stale;
+            }
+        }
+        Ok(())
+    })?;
+    if remove {
+        println!("Removed stale registrations");
+    } else if !stale.is_empty() {
+        println!("Kept stale registrations, use --yes to remove them");
+    }
+    Ok(())
+}
+
+fn push_interval(config: &config::Config) -> Duration {
+    Duration::from_secs(
+        config
+            .push_interval
+            .unwrap_or(config::DEFAULT_PUSH_INTERVAL),
+    )
+}
+
+fn push_timeout(config: &config::Config) -> Duration {
+    Duration::from_secs(config.push_timeout.unwrap_or(config::DEFAULT_PUSH_TIMEOUT))
+}
+
+fn push_compression(config: &config::Config) -> compression::Settings {
+    compression::Settings {
+        algorithm: config.push_compression.unwrap_or(config::Compression::None),
+        level: config.push_compression_level,
+    }
+}
+
+// Unless configured, the jitter is a tenth of the interval
+fn push_jitter(config: &config::Config, interval: Duration) -> Duration {
+    match config.push_jitter {
+        Some(push_jitter) => Duration::from_secs(push_jitter),
+        None => interval / 10,
+    }
+}
+
+fn push_loop(
+    receiver: &impl AgentReceiver,
+    mut config: config::Config,
+    mut reg_state: config::RegistrationState,
+    paths: &paths::Paths,
+    reload_config: impl Fn() -> AnyhowResult<(config::Config, config::RegistrationState)>,
+    count: Option<u64>,
+) -> AnyhowResult<()> {
+    reload::install_handler().context("Could not install handler for SIGHUP.")?;
+    let state_watcher = watch_state(paths);
+    let shared_config = Arc::new(Mutex::new(config.clone()));
+    spawn_renewal(Arc::clone(&shared_config), paths);
+
+    let mut pushes = 0;
+    let mut next_pushes: HashMap<String, Instant> = HashMap::new();
+    loop {
+        let state_changed = state_watcher
+            .as_ref()
+            .is_some_and(|state_watcher| state_watcher.changed());
+        if reload::requested() || state_changed {
+            match reload_config() {
+                Ok((new_config, new_reg_state)) => {
+                    config = new_config;
+                    reg_state = new_reg_state;
+                    if let Ok(mut shared_config) = shared_config.lock() {
+                        *shared_config = config.clone();
+                    }
+                    info!("Reloaded configuration and registration state");
+                }
+                Err(error) => warn!(
+                    "Error reloading, keeping previous configuration: {:?}",
+                    error
+                ),
+            }
+        }
+
+        // Every site is pushed to in its own interval
+        let start = Instant::now();
+        let due: Vec<(&String, &config::ServerSpec)> = reg_state
+            .server_specs
+            .iter()
+            .filter(|(address, _)| next_pushes.get(*address).is_none_or(|next| *next <= start))
+            .collect();
+        // Without registrations, every round counts, so --loop --count still terminates
+        if !due.is_empty() || reg_state.server_specs.is_empty() {
+            if let Err(error) = push_to(receiver, &config, &due, paths) {
+                warn!("{:?}", error);
+            }
+            let busy_until = config::RuntimeState::from_file(&paths.runtime_path)
+                .map(|runtime_state| runtime_state.busy_until)
+                .unwrap_or_default();
+            for (address, server_spec) in due {
+                // Pushing more often would only be refused
+                let interval = push_interval(&server_spec.settings.apply(&config))
+                    .max(Duration::from_secs(config.min_push_interval.unwrap_or(0)));
+                // Spread the load on the agent receivers if many hosts were started simultaneously
+                let mut next_push =
+                    start + interval + agent_receiver_api::jitter(push_jitter(&config, interval));
+                // Receivers asking for a longer break than the interval get it
+                if let Some(busy_until) = busy_until.get(address) {
+                    let wait = Duration::from_secs((busy_until - now()).max(0) as u64);
+                    next_push = next_push.max(Instant::now() + wait);
+                }
+                next_pushes.insert(address.clone(), next_push);
+            }
+            pushes += 1;
+            if count.is_some_and(|count| pushes >= count) {
+                return Ok(());
+            }
+        }
+
+        let next_push = reg_state
+            .server_specs
+            .keys()
+            .filter_map(|address| next_pushes.get(address))
+            .min()
+            .copied()
+            .unwrap_or_else(|| start + push_interval(&config));
+        agent_receiver_api::idle(next_push.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn dump(config: config::Config) -> AnyhowResult<()> {
+    let mon_data =
+        monitoring_data::collect(&config).context("Error collecting monitoring data.")?;
+    io::stdout()
+        .write_all(&mon_data)
+        .context("Error writing monitoring data to stdout.")?;
+
+    Ok(())
+}
+
+fn status(
+    config: &config::Config,
+    reg_state: config::RegistrationState,
+    paths: &paths::Paths,
+    json: bool,
 ) -> AnyhowResult<()> {
-    let agent_receiver_address = config
-        .agent_receiver_address
-        .context("Server addresses not specified.")?;
-    let credentials = config
-        .credentials
-        .context("Missing credentials for registration.")?;
-    let host_name = config
-        .host_name
-        .context("Missing host name for registration")?;
+    // Without the runtime state, only the times of the last push and pull are missing
+    let runtime_state =
+        config::RuntimeState::from_file(&paths.runtime_path).unwrap_or_else(|error| {
+            eprintln!(
+                "Could not read runtime state from {}: {}",
+                paths.runtime_path.display(),
+                error
+            );
+            config::RuntimeState::empty_state()
+        });
+    let stats = stats::Stats::from_file(&paths.stats_path).unwrap_or_else(|error| {
+        eprintln!(
+            "Could not read request statistics from {}: {}",
+            paths.stats_path.display(),
+            error
+        );
+        stats::Stats::default()
+    });
+    warn_about_expiries(config, &certificate_expiries(reg_state.server_specs.iter()));
+    let status = status::Status::new(
+        &reg_state,
+        &runtime_state,
+        cert_expiry_warning(config),
+        now(),
+        is_legacy_pull(config, paths, &reg_state),
+        &[&paths.state_path, &paths.config_path],
+        CMK_AGENT_USER,
+    )
+    .with_requests(&stats);
+    println!(
+        "{}",
+        if json {
+            status.to_json()?
+        } else {
+            status.to_text()
+        }
+    );
+    Ok(())
+}
 
-    let uuid = Uuid::new_v4().to_string();
-    // TODO: what if registration_state.contains_key(agent_receiver_address) (already registered)?
-    let root_cert = match &config.root_certificate {
-        Some(cert) => cert.clone(),
-        None => certs::fetch_root_cert(&agent_receiver_address)
-            .context(format!("Error establishing trust with agent_receiver."))?,
+fn test_connection(
+    config: config::Config,
+    reg_state: config::RegistrationState,
+) -> AnyhowResult<()> {
+    let server_specs: Vec<(&String, &config::ServerSpec)> = match &config.agent_receiver_address {
+        Some(address) => vec![(
+            address,
+            reg_state
+                .server_specs
+                .get(address)
+                .context(format!("No registration with {} found", address))?,
+        )],
+        None => reg_state.server_specs.iter().collect(),
     };
+    if server_specs.is_empty() {
+        return Err(anyhow!("No registrations to test"));
+    }
+
+    let client_options = client_options(&config);
+    let mut success = true;
+    for (address, server_spec) in server_specs {
+        // Failover addresses have to work as well, once the others fail
+        for address in std::iter::once(address).chain(&server_spec.failover_addresses) {
+            success &= connectivity::test(address, server_spec, &client_options);
+        }
+    }
+    if success {
+        Ok(())
+    } else {
+        Err(anyhow!("Connection test failed"))
+    }
+}
 
-    let (csr, private_key) = certs::make_csr(&uuid).context(format!("Error creating CSR."))?;
-    let certificate =
-        agent_receiver_api::pairing(&agent_receiver_address, &root_cert, csr, &credentials)
-            .context(format!("Error pairing with {}", &agent_receiver_address))?;
+// What serving a pull connection needs to know about the registrations
+struct PullState {
+    settings_by_uuid: HashMap<String, config::ServerSettings>,
+    root_certs_by_uuid: HashMap<String, String>,
+    cert_expiries: Vec<(String, i64)>,
+    pending_rotations: HashSet<String>,
+    // Serving a registration of the demo mode, which leaves the real state alone
+    demo: bool,
+}
 
-    agent_receiver_api::register_with_hostname(
-        &agent_receiver_address,
-        &root_cert,
-        &credentials,
-        &uuid,
-        &host_name,
-    )
-    .context(format!("Error registering {}", &agent_receiver_address))?;
+impl PullState {
+    fn new(reg_state: &config::RegistrationState) -> PullState {
+        PullState {
+            settings_by_uuid: reg_state.settings_by_uuid(),
+            root_certs_by_uuid: reg_state
+                .server_specs
+                .values()
+                .map(|spec| (spec.uuid.clone(), spec.root_cert.clone()))
+                .collect(),
+            cert_expiries: certificate_expiries(reg_state.server_specs.iter()),
+            pending_rotations: reg_state
+                .server_specs
+                .values()
+                .filter(|spec| spec.previous_key.is_some())
+                .map(|spec| spec.uuid.clone())
+                .collect(),
+            demo: false,
+        }
+    }
+}
+
+// A registration which only exists in memory, such that the TLS transport can be tested without
+// an agent receiver. Clients are not verified, there is no site which issued their certificates.
+fn demo(config: &config::Config) -> AnyhowResult<(PullState, Arc<ServerConfig>)> {
+    let uuid = Uuid::new_v4().to_string();
+    let (certificate, private_key) = certs::make_self_signed(&uuid, &key_spec(config))
+        .context("Error creating demo certificate.")?;
+    let message = format!(
+        "Demo mode, serving as {} with certificate fingerprint {}",
+        uuid,
+        certs::fingerprint(&certificate)?
+    );
+    info!("{}", message);
+    eprintln!("{}", message);
 
+    let mut reg_state = RegistrationState::empty_state();
     reg_state.server_specs.insert(
-        agent_receiver_address,
+        String::from("demo"),
         config::ServerSpec {
             uuid,
             private_key,
+            root_cert: certificate.clone(),
             certificate,
-            root_cert,
+            settings: config::ServerSettings::default(),
+            stale: None,
+            metadata: config::RegistrationMetadata::default(),
+            previous_key: None,
+            failover_addresses: vec![],
+            capabilities: None,
         },
     );
+    let pull_state = PullState {
+        demo: true,
+        ..PullState::new(&reg_state)
+    };
+    let tls_config =
+        tls_server::demo_tls_config(reg_state, config).context("Could not initialize TLS.")?;
+    Ok((pull_state, tls_config))
+}
 
-    reg_state.to_file(path_state_out).unwrap();
+fn serve_tls<S: Read + Write>(
+    stream: &mut S,
+    tls_config: Arc<ServerConfig>,
+    config: &config::Config,
+    pull_state: &PullState,
+    paths: &paths::Paths,
+) -> AnyhowResult<()> {
+    stream.write_all(TLS_ID)?;
+    stream.flush()?;
 
-    disallow_legacy_pull()
-        .context("Registration successful, but could not delete marker for legacy pull mode")?;
+    let mut tls_connection =
+        tls_server::tls_connection(tls_config).context("Could not initialize TLS.")?;
+    while tls_connection.is_handshaking() {
+        tls_connection
+            .complete_io(stream)
+            .context("TLS handshake failed.")?;
+    }
+    match tls_connection.alpn_protocol() {
+        Some(protocol) => debug!(
+            "Site negotiated protocol {}",
+            String::from_utf8_lossy(protocol)
+        ),
+        None => debug!("Site did not negotiate a protocol via ALPN"),
+    }
+    // The site is identified the same way as the certificate was chosen
+    let uuid = match tls_connection.sni_hostname() {
+        Some(uuid) if pull_state.root_certs_by_uuid.contains_key(uuid) => Some(uuid),
+        _ if pull_state.root_certs_by_uuid.len() == 1 => pull_state
+            .root_certs_by_uuid
+            .keys()
+            .next()
+            .map(String::as_str),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("Cannot tell which registration the connection is for"))?
+    .to_string();
+    let root_cert = pull_state
+        .root_certs_by_uuid
+        .get(&uuid)
+        .ok_or_else(|| anyhow!("Unknown registration {}", uuid))?;
+    if !pull_state.demo {
+        tls_server::verify_client_issuer(&tls_connection, root_cert).context(format!(
+            "Client certificate was not issued by the site of registration {}",
+            uuid
+        ))?;
+    }
+    let config = match pull_state.settings_by_uuid.get(&uuid) {
+        Some(settings) => settings.apply(config),
+        None => config.clone(),
+    };
+    let mut tls_stream = tls_server::tls_stream(&mut tls_connection, stream);
+
+    let mut mon_data =
+        monitoring_data::collect(&config).context("Error collecting monitoring data.")?;
+    mon_data.extend_from_slice(&expiry_section(&config, &pull_state.cert_expiries));
+    tls_stream.write_all(&mon_data)?;
+    tls_stream.flush()?;
+
+    if pull_state.demo {
+        return Ok(());
+    }
+    update_runtime_state(paths, |runtime_state| runtime_state.last_pull = Some(now()));
+    if pull_state.pending_rotations.contains(&uuid) {
+        if let Err(error) = finish_key_rotation(&config, paths, &uuid) {
+            warn!("Could not finish key rotation for {}: {:?}", uuid, error);
+        }
+    }
+    disallow_legacy_pull(paths).context("Just provided agent data via TLS, but legacy pull mode is still allowed, and could not delete marker")?;
     Ok(())
 }
 
-fn push(config: config::Config, reg_state: config::RegistrationState) -> AnyhowResult<()> {
-    let mon_data = monitoring_data::collect(config.package_name)
-        .context("Error collecting monitoring data")?;
+// Without only_from, connections from anywhere are accepted
+fn allowed_networks(config: &config::Config) -> AnyhowResult<Option<Vec<only_from::Network>>> {
+    match &config.only_from {
+        Some(only_from) => {
+            Ok(Some(only_from::parse(only_from).map_err(|error| {
+                anyhow!("Invalid only_from: {}", error)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
 
-    for (agent_receiver_address, server_spec) in reg_state.server_specs.iter() {
-        let message =
-            agent_receiver_api::agent_data(agent_receiver_address, &server_spec.uuid, &mon_data)
-                .context(format!("Error pushing monitoring data."))?;
-        println!("{}", message);
+// Only socket activated connections have a peer, not local pipes
+fn stdin_peer() -> Option<IpAddr> {
+    match socket::getpeername(0) {
+        Ok(SockAddr::Inet(addr)) => Some(addr.to_std().ip()),
+        _ => None,
     }
+}
 
-    Ok(())
+fn pull(
+    config: config::Config,
+    reg_state: config::RegistrationState,
+    paths: &paths::Paths,
+    demo_mode: bool,
+) -> AnyhowResult<()> {
+    if let (Some(networks), Some(peer)) = (allowed_networks(&config)?, stdin_peer()) {
+        if !only_from::is_allowed(&networks, peer) {
+            warn!(
+                "Rejected connection from {}, which is not in only_from",
+                peer
+            );
+            return Err(anyhow!("Connection from {} is not allowed", peer));
+        }
+    }
+
+    if demo_mode {
+        let (pull_state, tls_config) = demo(&config)?;
+        return serve_tls(
+            &mut tls_server::IoStream::new(),
+            tls_config,
+            &config,
+            &pull_state,
+            paths,
+        );
+    }
+
+    if is_legacy_pull(&config, paths, &reg_state) {
+        return dump(config);
+    }
+
+    let pull_state = PullState::new(&reg_state);
+    let tls_config =
+        tls_server::tls_config(reg_state, &config).context("Could not initialize TLS.")?;
+    serve_tls(
+        &mut tls_server::IoStream::new(),
+        tls_config,
+        &config,
+        &pull_state,
+        paths,
+    )
 }
 
-fn dump(config: config::Config) -> AnyhowResult<()> {
-    let mon_data = monitoring_data::collect(config.package_name)
-        .context("Error collecting monitoring data.")?;
-    io::stdout()
-        .write_all(&mon_data)
-        .context("Error writing monitoring data to stdout.")?;
+fn handle_pull_connection(
+    mut stream: TcpStream,
+    tls_config: Option<Arc<ServerConfig>>,
+    config: &config::Config,
+    pull_state: &PullState,
+    paths: &paths::Paths,
+) -> AnyhowResult<()> {
+    stream
+        .set_read_timeout(Some(PULL_CONNECTION_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(PULL_CONNECTION_TIMEOUT)))
+        .context("Could not set connection timeouts.")?;
+    match tls_config {
+        Some(tls_config) => serve_tls(&mut stream, tls_config, config, pull_state, paths),
+        None => {
+            let mon_data =
+                monitoring_data::collect(config).context("Error collecting monitoring data.")?;
+            stream
+                .write_all(&mon_data)
+                .context("Error writing monitoring data.")
+        }
+    }
+}
 
-    Ok(())
+// Counts the pull connections being served, each one holds a ConnectionSlot until it is done
+#[derive(Clone)]
+struct ConnectionSlots {
+    active: Arc<AtomicUsize>,
+    max: usize,
 }
 
-fn status(_config: config::Config) -> AnyhowResult<()> {
-    Err(anyhow!("Status mode not yet implemented"))
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlots {
+    fn new(max: usize) -> ConnectionSlots {
+        ConnectionSlots {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    fn acquire(&self) -> Option<ConnectionSlot> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < self.max).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(&self.active)))
+    }
 }
 
-fn pull(config: config::Config, reg_state: config::RegistrationState) -> AnyhowResult<()> {
-    if is_legacy_pull(&reg_state) {
-        return dump(config);
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn pull_tls_config(
+    config: &config::Config,
+    reg_state: config::RegistrationState,
+    paths: &paths::Paths,
+) -> AnyhowResult<Option<Arc<ServerConfig>>> {
+    if is_legacy_pull(config, paths, &reg_state) {
+        return Ok(None);
+    }
+    Ok(Some(
+        tls_server::tls_config(reg_state, config).context("Could not initialize TLS.")?,
+    ))
+}
+
+// Every address gets its own listener thread, which hands the connections over
+fn listen(config: &config::Config) -> AnyhowResult<mpsc::Receiver<io::Result<TcpStream>>> {
+    let port = config.listen_port.unwrap_or(config::DEFAULT_LISTEN_PORT);
+    let default_addresses = vec![String::from(config::DEFAULT_LISTEN_ADDRESS)];
+    let (sender, receiver) = mpsc::channel();
+    for address in config
+        .listen_addresses
+        .as_ref()
+        .unwrap_or(&default_addresses)
+    {
+        let listener = TcpListener::bind((address.as_str(), port))
+            .context(format!("Could not bind to {} on port {}", address, port))?;
+        info!(
+            "Listening for pull connections on {}",
+            listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| format!("{}:{}", address, port))
+        );
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if sender.send(stream).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    Ok(receiver)
+}
+
+fn daemon(
+    mut config: config::Config,
+    reg_state: config::RegistrationState,
+    paths: &paths::Paths,
+    reload_config: impl Fn() -> AnyhowResult<(config::Config, config::RegistrationState)>,
+    demo_mode: bool,
+) -> AnyhowResult<()> {
+    let (mut pull_state, mut tls_config) = if demo_mode {
+        let (pull_state, tls_config) = demo(&config)?;
+        (Arc::new(pull_state), Some(tls_config))
+    } else {
+        (
+            Arc::new(PullState::new(&reg_state)),
+            pull_tls_config(&config, reg_state, paths)?,
+        )
+    };
+    let mut networks = allowed_networks(&config)?;
+    reload::install_handler().context("Could not install handler for SIGHUP.")?;
+    // Certificates renewed by other invocations are picked up without a SIGHUP. The demo mode
+    // has nothing to renew or reload.
+    let state_watcher = if demo_mode { None } else { watch_state(paths) };
+    let shared_config = Arc::new(Mutex::new(config.clone()));
+    if !demo_mode {
+        spawn_renewal(Arc::clone(&shared_config), paths);
     }
 
-    let mut stream = tls_server::IoStream::new();
+    // Changes of the listen addresses or port only take effect after a restart
+    let connections = listen(&config)?;
+    let slots = ConnectionSlots::new(MAX_PULL_CONNECTIONS);
 
-    stream.write(TLS_ID).unwrap();
-    stream.flush().unwrap();
+    loop {
+        let stream = match connections.recv_timeout(RELOAD_POLL_INTERVAL) {
+            Ok(stream) => Some(stream),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
 
-    let mut tls_connection =
-        tls_server::tls_connection(reg_state).context("Could not initialize TLS.")?;
-    let mut tls_stream = tls_server::tls_stream(&mut tls_connection, &mut stream);
+        // Connections which are already being served keep their copy of the old configuration
+        let state_changed = state_watcher
+            .as_ref()
+            .is_some_and(|state_watcher| state_watcher.changed());
+        if state_changed {
+            info!("Registration state changed");
+        }
+        let reload_requested = reload::requested();
+        if reload_requested && demo_mode {
+            warn!("Ignoring reload request in demo mode");
+        } else if reload_requested || state_changed {
+            match reload_config().and_then(|(new_config, new_reg_state)| {
+                let new_pull_state = PullState::new(&new_reg_state);
+                let new_tls_config = pull_tls_config(&new_config, new_reg_state, paths)?;
+                Ok((
+                    allowed_networks(&new_config)?,
+                    new_config,
+                    new_pull_state,
+                    new_tls_config,
+                ))
+            }) {
+                Ok((new_networks, new_config, new_pull_state, new_tls_config)) => {
+                    networks = new_networks;
+                    config = new_config;
+                    if let Ok(mut shared_config) = shared_config.lock() {
+                        *shared_config = config.clone();
+                    }
+                    pull_state = Arc::new(new_pull_state);
+                    tls_config = new_tls_config;
+                    info!("Reloaded configuration and registration state");
+                }
+                Err(error) => warn!(
+                    "Error reloading, keeping previous configuration: {:?}",
+                    error
+                ),
+            }
+        }
+
+        let stream = match stream {
+            Some(Ok(stream)) => stream,
+            None => continue,
+            Some(Err(error)) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Some(Err(error)) => {
+                warn!("Error accepting connection: {}", error);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| String::from("unknown peer"));
+        // Rejected before the TLS handshake, such that nothing is revealed to the peer
+        if let Some(networks) = &networks {
+            if !stream
+                .peer_addr()
+                .is_ok_and(|addr| only_from::is_allowed(networks, addr.ip()))
+            {
+                warn!(
+                    "Rejected connection from {}, which is not in only_from",
+                    peer
+                );
+                continue;
+            }
+        }
+        let slot = match slots.acquire() {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "Rejected connection from {}, already serving {} connections",
+                    peer, MAX_PULL_CONNECTIONS
+                );
+                continue;
+            }
+        };
+        let tls_config = tls_config.clone();
+        let config = config.clone();
+        let pull_state = Arc::clone(&pull_state);
+        let paths = paths.clone();
 
-    let mon_data = monitoring_data::collect(config.package_name)
-        .context("Error collecting monitoring data.")?;
-    tls_stream.write_all(&mon_data).unwrap();
-    tls_stream.flush().unwrap();
+        thread::spawn(move || {
+            let _slot = slot;
+            if let Err(error) =
+                handle_pull_connection(stream, tls_config, &config, &pull_state, &paths)
+            {
+                warn!("Error serving {}: {:?}", peer, error);
+            }
+        });
+    }
 
-    disallow_legacy_pull().context("Just provided agent data via TLS, but legacy pull mode is still allowed, and could not delete marker")?;
     Ok(())
 }
 
-fn is_legacy_pull(reg_state: &config::RegistrationState) -> bool {
-    if !Path::new(HOME_DIR).join(LEGACY_PULL_FILE).exists() {
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn update_stats(paths: &paths::Paths, update: impl FnOnce(&mut stats::Stats)) {
+    let result = config::StateLock::exclusive(&paths.stats_path).and_then(|_lock| {
+        let mut stats = stats::Stats::from_file(&paths.stats_path)?;
+        update(&mut stats);
+        stats.to_file(&paths.stats_path)
+    });
+    if let Err(error) = result {
+        warn!("Could not update request statistics: {}", error);
+    }
+}
+
+// Pushes, pulls and renewals run concurrently, none of them may lose what another one recorded
+fn update_runtime_state(paths: &paths::Paths, update: impl FnOnce(&mut config::RuntimeState)) {
+    let result = config::StateLock::exclusive(&paths.runtime_path).and_then(|_lock| {
+        let mut runtime_state = config::RuntimeState::from_file(&paths.runtime_path)?;
+        update(&mut runtime_state);
+        runtime_state.to_file(&paths.runtime_path)
+    });
+    if let Err(error) = result {
+        warn!("Could not update runtime state: {}", error);
+    }
+}
+
+fn is_legacy_pull(
+    config: &config::Config,
+    paths: &paths::Paths,
+    reg_state: &config::RegistrationState,
+) -> bool {
+    if !paths.legacy_pull_path.exists() {
+        return false;
+    }
+    if config.legacy_pull == Some(config::LegacyPull::Never) {
+        warn!(
+            "Ignoring {}, legacy pull mode is disabled by configuration",
+            paths.legacy_pull_path.display()
+        );
         return false;
     }
     if !reg_state.server_specs.is_empty() {
@@ -149,34 +2633,253 @@ fn is_legacy_pull(reg_state: &config::RegistrationState) -> bool {
     true
 }
 
-fn disallow_legacy_pull() -> IoResult<()> {
-    let legacy_pull_marker = Path::new(HOME_DIR).join(LEGACY_PULL_FILE);
-    if !legacy_pull_marker.exists() {
+fn allow_legacy_pull(paths: &paths::Paths) -> IoResult<()> {
+    fs::write(&paths.legacy_pull_path, "")
+}
+
+fn disallow_legacy_pull(paths: &paths::Paths) -> IoResult<()> {
+    if !paths.legacy_pull_path.exists() {
         return Ok(());
     }
 
-    fs::remove_file(legacy_pull_marker)
+    fs::remove_file(&paths.legacy_pull_path)
+}
+
+// A missing file only means that nothing has been configured or registered yet, whereas
+// any other error points to a broken installation and is fatal
+fn or_default_if_missing<T>(
+    result: Result<T, LoadError>,
+    default: impl FnOnce() -> T,
+) -> Result<T, LoadError> {
+    match result {
+        Err(error) if error.is_missing() => Ok(default()),
+        result => result,
+    }
+}
+
+fn get_configuration_layers(
+    paths: &paths::Paths,
+    args: &cli::Args,
+) -> Result<config::ConfigLayers, LoadError> {
+    let mut layers = config::ConfigLayers::new();
+    layers.push(
+        &paths.config_path.display().to_string(),
+        or_default_if_missing(
+            config::Config::from_file(&paths.config_path),
+            config::Config::empty_config,
+        )?,
+    );
+    // Drop-ins are layered in lexical order, such that later files override earlier ones
+    for path in config::drop_in_paths(&paths.drop_in_dir)
+        .map_err(|error| LoadError::from_io(&paths.drop_in_dir, error))?
+    {
+        layers.push(
+            &path.display().to_string(),
+            config::Config::from_file(&path)?,
+        );
+    }
+    // Only read the secrets if they are actually needed
+    if args.mode.credentials_args().is_some() {
+        layers.push(
+            &paths.secrets_path.display().to_string(),
+            or_default_if_missing(
+                config::Secrets::from_file(&paths.secrets_path),
+                config::Secrets::empty_secrets,
+            )?
+            .into_config(),
+        );
+    }
+    let environment = config::Config::from_env()?;
+    // The keyring takes the place of the secrets file, but is only asked if enabled
+    if args.mode.credentials_args().is_some()
+        && environment.use_keyring.or(layers.merged().use_keyring) == Some(true)
+    {
+        let credentials = keyring::lookup(keyring::CREDENTIALS)
+            .map_err(|error| LoadError::Keyring(format!("{:#}", error)))?;
+        layers.push(
+            "keyring",
+            config::Config {
+                credentials,
+                ..config::Config::empty_config()
+            },
+        );
+    }
+    layers.push("environment", environment);
+    layers.push("command line", config::Config::from_args(args)?);
+    Ok(layers)
+}
+
+fn get_configuration(paths: &paths::Paths, args: &cli::Args) -> Result<config::Config, LoadError> {
+    merged_configuration(&get_configuration_layers(paths, args)?)
+}
+
+// Addresses are normalized before anything looks up registrations by them
+fn merged_configuration(layers: &config::ConfigLayers) -> Result<config::Config, LoadError> {
+    let mut config = layers.merged();
+    if let Some(agent_receiver_address) = &config.agent_receiver_address {
+        config.agent_receiver_address = Some(address::normalize(agent_receiver_address).map_err(
+            |message| {
+                LoadError::Value(
+                    layers
+                        .origin("agent_receiver_address")
+                        .unwrap_or_default()
+                        .to_string(),
+                    message,
+                )
+            },
+        )?);
+    }
+    if let Some(failover_addresses) = &config.failover_addresses {
+        config.failover_addresses = Some(
+            failover_addresses
+                .iter()
+                .map(|failover_address| address::normalize(failover_address))
+                .collect::<Result<Vec<String>, String>>()
+                .map_err(|message| {
+                    LoadError::Value(
+                        layers
+                            .origin("failover_addresses")
+                            .unwrap_or_default()
+                            .to_string(),
+                        message,
+                    )
+                })?,
+        );
+    }
+    // Tokens only keep P-256 keys, which are used unless another algorithm is configured
+    if config.pkcs11_token.is_some() {
+        match layers.origin("key_algorithm") {
+            None | Some(config::DEFAULT_ORIGIN) => {
+                config.key_algorithm = Some(config::KeyAlgorithm::EcdsaP256)
+            }
+            Some(origin) if config.key_algorithm != Some(config::KeyAlgorithm::EcdsaP256) => {
+                return Err(LoadError::Value(
+                    origin.to_string(),
+                    String::from("pkcs11_token needs key_algorithm ecdsa_p256"),
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(config)
+}
+
+fn show_origin(layers: &config::ConfigLayers) {
+    let merged = serde_json::to_value(layers.merged()).unwrap_or_default();
+    for (field, origin) in layers.origins() {
+        match origin {
+            // Never print credentials, not even for debugging
+            Some(origin) if field == "credentials" => {
+                eprintln!("{} = <hidden> ({})", field, origin)
+            }
+            Some(origin) => eprintln!("{} = {} ({})", field, merged[&field], origin),
+            None => eprintln!("{} is not set", field),
+        }
+    }
+}
+
+fn load_reg_state(
+    path: &Path,
+    key_passphrase: Option<Secret>,
+) -> Result<config::RegistrationState, LoadError> {
+    let empty_state =
+        config::RegistrationState::empty_state().with_key_passphrase(key_passphrase.clone());
+    or_default_if_missing(
+        config::RegistrationState::from_file(path, key_passphrase),
+        || empty_state,
+    )
+}
+
+fn get_reg_state(
+    path: &Path,
+    key_passphrase: Option<Secret>,
+) -> AnyhowResult<config::RegistrationState> {
+    let _lock = config::StateLock::shared(path)?;
+    Ok(load_reg_state(path, key_passphrase)?)
+}
+
+// The lock is only held while the state is read, changed and written, never during prompts or
+// requests to the agent receiver, such that other invocations are not blocked by them
+fn update_reg_state<T>(
+    paths: &paths::Paths,
+    key_passphrase: Option<Secret>,
+    update: impl FnOnce(&mut RegistrationState) -> AnyhowResult<T>,
+) -> AnyhowResult<T> {
+    let _lock = config::StateLock::exclusive(&paths.state_path)
+        .context("Error locking registration state.")?;
+    let mut reg_state = load_reg_state(&paths.state_path, key_passphrase)
+        .context("Error while obtaining registration state.")?;
+    let result = update(&mut reg_state)?;
+    reg_state
+        .to_file(&paths.state_path)
+        .context("Error writing registration state.")?;
+    Ok(result)
+}
+
+// A passphrase file configured explicitly comes first, then the keyring if enabled, and
+// finally a systemd credential
+fn key_passphrase(config: &config::Config) -> AnyhowResult<Option<Secret>> {
+    if config.key_passphrase_file.is_none() && config.use_keyring == Some(true) {
+        if let Some(key_passphrase) = keyring::lookup(keyring::KEY_PASSPHRASE)
+            .context("Error reading key passphrase from the keyring.")?
+        {
+            return Ok(Some(key_passphrase));
+        }
+    }
+    let path = match &config.key_passphrase_file {
+        Some(path) => PathBuf::from(path),
+        None => match env::var_os("CREDENTIALS_DIRECTORY") {
+            Some(dir) if Path::new(&dir).join(KEY_PASSPHRASE_CREDENTIAL).exists() => {
+                Path::new(&dir).join(KEY_PASSPHRASE_CREDENTIAL)
+            }
+            _ => return Ok(None),
+        },
+    };
+    Ok(Some(config::read_key_passphrase(&path).context(
+        format!("Error reading key passphrase from {}.", path.display()),
+    )?))
+}
+
+// Looking up the passphrase may ask the keyring, which is only worth it if it is needed for
+// reading the state. Writing the state looks it up in any case, to encrypt new keys.
+fn key_passphrase_for_reading(
+    config: &config::Config,
+    state_path: &Path,
+) -> AnyhowResult<Option<Secret>> {
+    if config::RegistrationState::has_encrypted_keys(state_path) {
+        key_passphrase(config)
+    } else {
+        Ok(None)
+    }
 }
 
-fn get_configuration(path_config: &Path, args: cli::Args) -> io::Result<config::Config> {
-    return Ok(config::Config::merge_two_configs(
-        config::Config::from_file(path_config)?,
-        config::Config::from_args(args),
-    ));
+fn push_encryption_key(config: &config::Config) -> AnyhowResult<Option<Secret>> {
+    match &config.push_encryption_key_file {
+        Some(path) => Ok(Some(
+            config::read_push_encryption_key(Path::new(path))
+                .context(format!("Error reading push encryption key from {}.", path))?,
+        )),
+        None => Ok(None),
+    }
 }
 
-fn get_reg_state(path: &Path) -> io::Result<config::RegistrationState> {
-    return Ok(config::RegistrationState::from_file(path)?);
+fn log_level(config: &config::Config) -> AnyhowResult<LevelFilter> {
+    match &config.log_level {
+        Some(log_level) => log_level
+            .parse()
+            .map_err(|_| anyhow!("Invalid log level: {}", log_level)),
+        None => Ok(LevelFilter::Info),
+    }
 }
 
-fn init_logging(path: &Path) -> AnyhowResult<()> {
+fn init_logging(path: &Path, level: LevelFilter) -> AnyhowResult<()> {
     let logfile = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{l} - {m}\n")))
         .build(path)?;
 
     let config = Config::builder()
         .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Info))?;
+        .build(Root::builder().appender("logfile").build(level))?;
 
     log4rs::init_config(config)?;
 
@@ -190,7 +2893,7 @@ fn ensure_home_directory(path: &Path) -> io::Result<()> {
     Ok(())
 }
 
-fn sanitize_home_dir_ownership(paths: [&Path; 4], user: &str) -> AnyhowResult<()> {
+fn sanitize_home_dir_ownership(paths: &[PathBuf], user: &str) -> AnyhowResult<()> {
     if !unistd::Uid::current().is_root() {
         return Ok(());
     }
@@ -209,49 +2912,645 @@ fn sanitize_home_dir_ownership(paths: [&Path; 4], user: &str) -> AnyhowResult<()
     Ok(())
 }
 
+fn validate_config(paths: &paths::Paths, args: &cli::Args) -> AnyhowResult<()> {
+    let drop_in_paths = config::drop_in_paths(&paths.drop_in_dir)
+        .context("Error listing drop-in configuration files.")?;
+    let mut config_paths = vec![paths.config_path.as_path()];
+    config_paths.extend(drop_in_paths.iter().map(PathBuf::as_path));
+
+    // Without a usable configuration, encrypted private keys cannot be checked
+    let key_passphrase = get_configuration(paths, args).ok().and_then(|config| {
+        key_passphrase_for_reading(&config, &paths.state_path)
+            .ok()
+            .flatten()
+    });
+    let report = validation::validate(
+        &config_paths,
+        &paths.secrets_path,
+        &paths.state_path,
+        key_passphrase.as_ref().map(Secret::expose),
+    );
+    if !report.is_ok() {
+        return Err(anyhow!("Validation failed: {}", report.summary()));
+    }
+    println!("Validation passed: {}", report.summary());
+    Ok(())
+}
+
+fn init(paths: &paths::Paths, args: &cli::Args, init_args: &cli::InitArgs) -> AnyhowResult<()> {
+    let toml = !init_args.json;
+    let rendered = template::render(toml);
+    if init_args.stdout {
+        print!("{}", rendered);
+        return Ok(());
+    }
+
+    // An explicitly given configuration file is the one to initialize
+    let path = match (&init_args.file, &args.paths.config_file) {
+        (Some(file), _) => file.clone(),
+        (None, Some(config_file)) => config_file.clone(),
+        (None, None) => paths::default_config_path(&paths.home_dir, toml),
+    };
+    if path.exists() && !init_args.force {
+        return Err(anyhow!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        ));
+    }
+    fs::write(&path, rendered).context(format!("Error writing {}", path.display()))?;
+    println!("Wrote configuration to {}", path.display());
+
+    let json_path = paths::default_config_path(&paths.home_dir, false);
+    if toml && args.paths.config_file.is_none() && json_path.exists() && json_path != path {
+        eprintln!(
+            "Note: {} exists as well and takes precedence",
+            json_path.display()
+        );
+    }
+    Ok(())
+}
+
+fn store_secret(name: &str) -> AnyhowResult<()> {
+    let value = if !interactive::is_interactive() {
+        let mut value = String::new();
+        io::stdin().read_line(&mut value)?;
+        let secret = Secret::from(value.trim_end_matches('\n').to_string());
+        secret::wipe(&mut value);
+        secret
+    } else if name == keyring::CREDENTIALS {
+        credentials_or_prompt(None, "Missing credentials.")?
+    } else {
+        interactive::prompt_hidden("Key passphrase")?
+    };
+    if value.expose().is_empty() {
+        return Err(anyhow!("Refusing to store an empty {}", name));
+    }
+    if name == keyring::CREDENTIALS && !value.expose().contains(' ') {
+        return Err(anyhow!("Credentials must have the form \"user password\""));
+    }
+    keyring::store(name, &value)?;
+    println!("Stored {} in the keyring", name);
+    Ok(())
+}
+
+fn completions(shell: Shell) -> AnyhowResult<()> {
+    cli::Args::clap().gen_completions_to("cmk-agent-ctl", shell, &mut io::stdout());
+    Ok(())
+}
+
 fn main() -> AnyhowResult<()> {
-    let state_path = Path::new(HOME_DIR).join(STATE_FILE);
-    let config_path = Path::new(HOME_DIR).join(CONFIG_FILE);
-    let log_path = Path::new(HOME_DIR).join(LOG_FILE);
+    let args = cli::Args::from_args();
+    // Completions are generated at build time, where the home directory may not exist
+    if let cli::Mode::Completions(completions_args) = &args.mode {
+        return completions(completions_args.shell);
+    }
+
+    let paths = paths::Paths::new(&args.paths);
+
+    // Validation has to work when the configuration cannot be loaded
+    if let cli::Mode::ValidateConfig = &args.mode {
+        return validate_config(&paths, &args);
+    }
 
     // TODO: Decide: Check if running as cmk-agent or root, and abort otherwise?
-    ensure_home_directory(Path::new(HOME_DIR))
+    ensure_home_directory(&paths.home_dir)
         .context("Cannot go on: Missing cmk-agent home directory and failed to create it.")?;
 
-    if let Err(error) = init_logging(&log_path).context("Failed to initialize logging") {
+    // Initializing must not depend on an existing configuration, which may be broken
+    if let cli::Mode::Init(init_args) = &args.mode {
+        return init(&paths, &args, init_args);
+    }
+    if let cli::Mode::StoreSecret(store_secret_args) = &args.mode {
+        return store_secret(&store_secret_args.name);
+    }
+
+    let layers =
+        get_configuration_layers(&paths, &args).context("Error while obtaining configuration.")?;
+    if args.show_origin {
+        show_origin(&layers);
+    }
+    let config = merged_configuration(&layers).context("Error while obtaining configuration.")?;
+
+    if let Err(error) = log_level(&config)
+        .and_then(|level| init_logging(&paths.log_path, level))
+        .context("Failed to initialize logging")
+    {
         println!("Error: {:?}", error)
     };
     info!("Starting cmk-agent-ctl");
+    if tls_key_log(&config) {
+        warn!(
+            "Writing TLS session keys to {}, anybody who can read it can decrypt captured traffic",
+            env::var(certs::KEY_LOG_FILE_VAR).unwrap_or_default()
+        );
+    }
 
-    let args = cli::Args::from_args();
-    let mode = String::from(&args.mode);
-
-    let config =
-        get_configuration(&config_path, args).context("Error while obtaining configuration.")?;
-    let reg_state =
-        get_reg_state(&state_path).context("Error while obtaining registration state.")?;
-
-    let result = match mode.as_str() {
-        "dump" => dump(config),
-        "register" => register(config, reg_state, &state_path),
-        "push" => push(config, reg_state),
-        "status" => status(config),
-        "pull" => pull(config, reg_state),
-        _ => Err(anyhow!("Invalid mode: {}", mode)),
+    // Modes changing the state re-read it under the lock when they write it, see
+    // update_reg_state
+    let reg_state = get_reg_state(
+        &paths.state_path,
+        key_passphrase_for_reading(&config, &paths.state_path)?,
+    )
+    .context("Error while obtaining registration state.")?;
+    // Registrations violating crypto_policy fips can still be inspected and deleted
+    let connects = matches!(
+        args.mode,
+        cli::Mode::RenewCertificate(_)
+            | cli::Mode::RotateKey(_)
+            | cli::Mode::Push(_)
+            | cli::Mode::PushDaemon(_)
+            | cli::Mode::Pull(_)
+            | cli::Mode::Daemon(_)
+            | cli::Mode::TestConnection(_)
+    );
+    if connects && fips::is_enabled(config.crypto_policy) {
+        fips::check_state(&reg_state)?;
+    }
+
+    let reload_config = || -> AnyhowResult<(config::Config, RegistrationState)> {
+        let config =
+            get_configuration(&paths, &args).context("Error while obtaining configuration.")?;
+        let reg_state = get_reg_state(
+            &paths.state_path,
+            key_passphrase_for_reading(&config, &paths.state_path)?,
+        )
+        .context("Error while obtaining registration state.")?;
+        if fips::is_enabled(config.crypto_policy) {
+            fips::check_state(&reg_state)?;
+        }
+        Ok((config, reg_state))
     };
 
-    if let Err(error) = sanitize_home_dir_ownership(
-        [Path::new(HOME_DIR), &state_path, &config_path, &log_path],
-        CMK_AGENT_USER,
-    )
-    .context(format!(
-        "Failed to set ownership of {} to {}",
-        HOME_DIR, CMK_AGENT_USER
-    )) {
+    let receiver = &agent_receiver_api::Http;
+    let result = match &args.mode {
+        cli::Mode::Dump(_) => dump(config),
+        cli::Mode::Register(register_args) => register(receiver, config, &paths, register_args),
+        cli::Mode::RegisterNew(register_new_args) => {
+            register_new(receiver, config, register_new_args)
+        }
+        cli::Mode::RenewCertificate(_) => renew_certificate(receiver, config, reg_state, &paths),
+        cli::Mode::RotateKey(rotate_key_args) => {
+            rotate_key(receiver, config, reg_state, &paths, rotate_key_args.revert)
+        }
+        cli::Mode::Import(import_args) => read_passphrase(import_args.passphrase_file.as_deref())
+            .and_then(|passphrase| {
+                import(&config, &paths, import_args.file.as_deref(), passphrase)
+            }),
+        cli::Mode::Export(export_args) => read_passphrase(export_args.passphrase_file.as_deref())
+            .and_then(|passphrase| export(reg_state, export_args.file.as_deref(), passphrase)),
+        cli::Mode::ExportCredentials(export_credentials_args) => {
+            export_credentials(&config, &reg_state, export_credentials_args.dir.as_deref())
+        }
+        cli::Mode::CsrExport(csr_export_args) => csr_export(
+            &config,
+            &paths,
+            csr_export_args.uuid,
+            csr_export_args.file.as_deref(),
+        ),
+        cli::Mode::CertImport(cert_import_args) => cert_import(config, &paths, cert_import_args),
+        cli::Mode::RestoreState(restore_state_args) => restore_state(&paths, restore_state_args),
+        cli::Mode::Delete(delete_args) => {
+            delete(receiver, config, reg_state, &paths, delete_args.local_only)
+        }
+        cli::Mode::Prune(prune_args) => prune(receiver, config, reg_state, &paths, prune_args.yes),
+        cli::Mode::Push(push_args) => {
+            if push_args.once || !push_args.push_loop {
+                push(receiver, &config, &reg_state, &paths)
+            } else {
+                push_loop(
+                    receiver,
+                    config,
+                    reg_state,
+                    &paths,
+                    reload_config,
+                    push_args.count,
+                )
+            }
+        }
+        cli::Mode::PushDaemon(_) => {
+            push_loop(receiver, config, reg_state, &paths, reload_config, None)
+        }
+        cli::Mode::TestConnection(_) => test_connection(config, reg_state),
+        cli::Mode::Status(status_args) => status(&config, reg_state, &paths, status_args.json),
+        cli::Mode::Pull(pull_args) => pull(config, reg_state, &paths, pull_args.demo),
+        cli::Mode::Daemon(daemon_args) => {
+            daemon(config, reg_state, &paths, reload_config, daemon_args.demo)
+        }
+        cli::Mode::ValidateConfig => validate_config(&paths, &args),
+        cli::Mode::Init(init_args) => init(&paths, &args, init_args),
+        cli::Mode::StoreSecret(store_secret_args) => store_secret(&store_secret_args.name),
+        cli::Mode::Completions(completions_args) => completions(completions_args.shell),
+    };
+
+    if let Err(error) = sanitize_home_dir_ownership(&paths.owned_by_agent_user(), CMK_AGENT_USER)
+        .context(format!(
+            "Failed to set ownership of {} to {}",
+            paths.home_dir.display(),
+            CMK_AGENT_USER
+        ))
+    {
         info!("{:?}", error)
     };
 
     // TODO: At least in pull and dump mode, we can't just pass an error here,
     // because the fetcher will receive the error as agent output.
+    if let Err(error) = &result {
+        if let Some(api_error) = error.downcast_ref::<ApiError>() {
+            eprintln!("Error: {:?}", error);
+            process::exit(api_error.exit_code());
+        }
+    }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent_receiver_api::mock::Mock;
+    use std::os::unix::net::UnixListener;
+
+    const ADDRESS: &str = "localhost:8000";
+
+    // Every test gets a home directory of its own, since they run concurrently
+    fn test_paths(name: &str) -> paths::Paths {
+        let home_dir =
+            env::temp_dir().join(format!("cmk-agent-ctl-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&home_dir);
+        fs::create_dir_all(&home_dir).unwrap();
+        paths::Paths::new(&cli::PathArgs {
+            home_dir: Some(home_dir),
+            config_file: None,
+            state_file: None,
+            log_file: None,
+        })
+    }
+
+    fn test_config(mock: &Mock, paths: &paths::Paths) -> config::Config {
+        let mut config = config::Config::empty_config();
+        config.agent_receiver_address = Some(String::from(ADDRESS));
+        config.credentials = Some(Secret::from(String::from("automation secret")));
+        config.host_name = Some(String::from("myhost"));
+        config.root_certificate = Some(mock.root_cert.clone());
+        config.key_algorithm = Some(config::KeyAlgorithm::EcdsaP256);
+        config.agent_socket = Some(
+            paths
+                .home_dir
+                .join("agent.sock")
+                .to_string_lossy()
+                .into_owned(),
+        );
+        config
+    }
+
+    fn register_with(mock: &Mock, config: &config::Config, paths: &paths::Paths, args: &[&str]) {
+        register(
+            mock,
+            config.clone(),
+            paths,
+            &cli::RegisterArgs::from_iter(args),
+        )
+        .unwrap();
+    }
+
+    fn server_spec(paths: &paths::Paths) -> config::ServerSpec {
+        load_reg_state(&paths.state_path, None)
+            .unwrap()
+            .server_specs
+            .remove(ADDRESS)
+            .unwrap()
+    }
+
+    // Answers as many connections to the agent socket as given
+    fn serve_agent(paths: &paths::Paths, connections: usize) -> thread::JoinHandle<()> {
+        let listener = UnixListener::bind(paths.home_dir.join("agent.sock")).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let _ = stream
+                    .unwrap()
+                    .write_all(b"<<<check_mk>>>\nVersion: test\n");
+            }
+        })
+    }
+
+    #[test]
+    fn test_register() {
+        let mock = Mock::new();
+        let paths = test_paths("register");
+        register_with(&mock, &test_config(&mock, &paths), &paths, &["register"]);
+
+        let server_spec = server_spec(&paths);
+        assert_eq!(
+            *mock.registered.borrow(),
+            vec![(server_spec.uuid.clone(), String::from("myhost"))]
+        );
+        assert!(certs::check_certificate(
+            &server_spec.certificate,
+            &server_spec.private_key,
+            &server_spec.uuid
+        )
+        .is_ok());
+        assert_eq!(server_spec.metadata.site.as_deref(), Some("mock"));
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_connection_slots() {
+        let slots = ConnectionSlots::new(2);
+        let first = slots.acquire().unwrap();
+        let second = slots.clone().acquire().unwrap();
+        assert!(slots.acquire().is_none());
+        drop(first);
+        assert!(slots.acquire().is_some());
+        drop(second);
+        assert_eq!(slots.active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_pkcs11_key_algorithm() {
+        let token = config::Config {
+            pkcs11_token: Some(String::from(
+                "pkcs11:token=agent?module-path=/usr/lib/softhsm/libsofthsm2.so",
+            )),
+            ..config::Config::empty_config()
+        };
+        let mut layers = config::ConfigLayers::new();
+        layers.push("file", token.clone());
+        assert_eq!(
+            merged_configuration(&layers).unwrap().key_algorithm,
+            Some(config::KeyAlgorithm::EcdsaP256)
+        );
+
+        let mut layers = config::ConfigLayers::new();
+        layers.push("file", token);
+        layers.push(
+            "command line",
+            config::Config {
+                key_algorithm: Some(config::KeyAlgorithm::Rsa),
+                ..config::Config::empty_config()
+            },
+        );
+        match merged_configuration(&layers) {
+            Err(LoadError::Value(origin, message)) => {
+                assert_eq!(origin, "command line");
+                assert!(message.contains("pkcs11_token") && message.contains("key_algorithm"));
+            }
+            _ => panic!("rsa keys were accepted for a token"),
+        }
+    }
+
+    #[test]
+    fn test_update_stats() {
+        let paths = Arc::new(test_paths("update-stats"));
+        // Updates running concurrently do not lose each other's outcomes
+        let updates: Vec<thread::JoinHandle<()>> = (0..4)
+            .map(|_| {
+                let paths = paths.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        update_stats(&paths, |stats| {
+                            stats.record(ADDRESS, Err(String::from("refused")), now())
+                        });
+                    }
+                })
+            })
+            .collect();
+        for update in updates {
+            update.join().unwrap();
+        }
+        let stats = stats::Stats::from_file(&paths.stats_path).unwrap();
+        assert_eq!(stats.receivers[ADDRESS].consecutive_failures, 40);
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mock = Mock::new();
+        let paths = test_paths("throttle");
+        let mut config = test_config(&mock, &paths);
+        register_with(&mock, &config, &paths, &["register"]);
+        let (address, server_spec) = (String::from(ADDRESS), server_spec(&paths));
+        let server_specs = [(&address, &server_spec)];
+        assert_eq!(throttle(&config, &server_specs, &paths).unwrap().len(), 1);
+
+        config.min_push_interval = Some(2);
+        update_runtime_state(&paths, |runtime_state| {
+            runtime_state.pushed_at.insert(address.clone(), now() - 1);
+        });
+        let error = throttle(&config, &server_specs, &paths).err().unwrap();
+        assert!(format!("{}", error).contains("min_push_interval"));
+
+        config.push_throttle = Some(config::PushThrottle::Delay);
+        let started = Instant::now();
+        assert_eq!(throttle(&config, &server_specs, &paths).unwrap().len(), 1);
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        // Retry-After is kept however pushes are throttled
+        update_runtime_state(&paths, |runtime_state| {
+            runtime_state.busy_until.insert(address.clone(), now() + 60);
+        });
+        assert!(throttle(&config, &server_specs, &paths).is_err());
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_fail_over() {
+        let mock = Mock::new();
+        let paths = test_paths("fail-over");
+        let mut config = test_config(&mock, &paths);
+        config.failover_addresses = Some(vec![String::from("standby:8000")]);
+        let active_address = || {
+            config::RuntimeState::from_file(&paths.runtime_path)
+                .unwrap()
+                .active_addresses
+                .remove(ADDRESS)
+        };
+        *mock.unreachable.borrow_mut() = vec![String::from(ADDRESS)];
+        register_with(&mock, &config, &paths, &["register"]);
+        assert_eq!(
+            server_spec(&paths).failover_addresses,
+            vec![String::from("standby:8000")]
+        );
+        assert_eq!(active_address().as_deref(), Some("standby:8000"));
+
+        let reg_state = load_reg_state(&paths.state_path, None).unwrap();
+        let agent = serve_agent(&paths, 2);
+        push(&mock, &config, &reg_state, &paths).unwrap();
+        assert_eq!(active_address().as_deref(), Some("standby:8000"));
+        *mock.unreachable.borrow_mut() = vec![String::from("standby:8000")];
+        push(&mock, &config, &reg_state, &paths).unwrap();
+        agent.join().unwrap();
+        assert_eq!(mock.pushed.borrow().len(), 2);
+        assert_eq!(active_address(), None);
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mock = Mock::new();
+        let paths = test_paths("capabilities");
+        let mut config = test_config(&mock, &paths);
+        let capabilities = config::Capabilities {
+            compression: vec![String::from("gzip")],
+            registration_status: false,
+            renewal: false,
+        };
+        *mock.capabilities.borrow_mut() = Some(capabilities.clone());
+        // Not waiting for a status the agent receiver cannot report
+        *mock.status.borrow_mut() = Some(String::from("declined"));
+        register_with(&mock, &config, &paths, &["register", "--wait"]);
+        assert_eq!(server_spec(&paths).capabilities, Some(capabilities));
+
+        config.renew_before = Some(400 * 24 * 3600);
+        renew_expiring_certificates(&mock, &config, &paths).unwrap();
+        assert_eq!(mock.pairings.get(), 1);
+
+        // Renewing explicitly discovers them again
+        *mock.capabilities.borrow_mut() = None;
+        renew_certificate(
+            &mock,
+            config.clone(),
+            load_reg_state(&paths.state_path, None).unwrap(),
+            &paths,
+        )
+        .unwrap();
+        assert_eq!(mock.pairings.get(), 2);
+        assert_eq!(server_spec(&paths).capabilities, None);
+        renew_expiring_certificates(&mock, &config, &paths).unwrap();
+        assert_eq!(mock.pairings.get(), 3);
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_register_wait_unlocked() {
+        let mock = Mock::new();
+        let paths = test_paths("register-wait-unlocked");
+        *mock.status.borrow_mut() = Some(String::from("accepted"));
+        // Other modes can change the state while waiting for the site
+        let state_path = paths.state_path.clone();
+        *mock.on_status.borrow_mut() = Some(Box::new(move || {
+            let (sender, receiver) = mpsc::channel();
+            let state_path = state_path.clone();
+            thread::spawn(move || {
+                let lock = config::StateLock::exclusive(&state_path);
+                sender.send(lock.is_ok()).unwrap();
+            });
+            assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+        }));
+        register_with(
+            &mock,
+            &test_config(&mock, &paths),
+            &paths,
+            &["register", "--wait"],
+        );
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_register_declined() {
+        let mock = Mock::new();
+        let paths = test_paths("register-declined");
+        *mock.status.borrow_mut() = Some(String::from("declined"));
+        let result = register(
+            &mock,
+            test_config(&mock, &paths),
+            &paths,
+            &cli::RegisterArgs::from_iter(["register", "--wait"]),
+        );
+        assert!(format!("{}", result.unwrap_err()).contains("declined"));
+        // Kept for deleting it at the site as well
+        assert!(load_reg_state(&paths.state_path, None)
+            .unwrap()
+            .server_specs
+            .contains_key(ADDRESS));
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_delete() {
+        let mock = Mock::new();
+        let paths = test_paths("delete");
+        let config = test_config(&mock, &paths);
+        register_with(&mock, &config, &paths, &["register"]);
+        let uuid = server_spec(&paths).uuid;
+
+        delete(
+            &mock,
+            config,
+            load_reg_state(&paths.state_path, None).unwrap(),
+            &paths,
+            false,
+        )
+        .unwrap();
+        assert_eq!(*mock.unregistered.borrow(), vec![uuid]);
+        assert!(load_reg_state(&paths.state_path, None)
+            .unwrap()
+            .server_specs
+            .is_empty());
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_push_spools_while_receiver_is_down() {
+        let mock = Mock::new();
+        let paths = test_paths("push-spool");
+        let mut config = test_config(&mock, &paths);
+        register_with(&mock, &config, &paths, &["register"]);
+        config.push_spool_size = Some(5);
+        let reg_state = load_reg_state(&paths.state_path, None).unwrap();
+        let spool = spool::Spool::new(&paths.spool_dir, &server_spec(&paths).uuid);
+        let agent = serve_agent(&paths, 2);
+
+        mock.fail_next(ApiError::Connection(String::from("Connection refused")));
+        assert!(push(&mock, &config, &reg_state, &paths).is_err());
+        assert_eq!(spool.entries().unwrap().len(), 1);
+
+        push(&mock, &config, &reg_state, &paths).unwrap();
+        agent.join().unwrap();
+        let pushed = mock.pushed.borrow();
+        // The current data goes first, the spooled data says when it was collected
+        assert_eq!(pushed.len(), 2);
+        assert_eq!(pushed[0].1, None);
+        assert!(pushed[1].1.is_some());
+        assert!(pushed
+            .iter()
+            .all(|(_, _, data)| data.starts_with("<<<check_mk>>>\nVersion: test\n")));
+        assert!(spool.entries().unwrap().is_empty());
+
+        let stats = stats::Stats::from_file(&paths.stats_path).unwrap();
+        let requests = stats.receivers.values().next().unwrap();
+        assert_eq!(requests.consecutive_failures, 0);
+        assert!(requests
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("Connection refused"));
+        assert_eq!(requests.latencies.len(), 1);
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+
+    #[test]
+    fn test_renew_expiring_certificates() {
+        let mock = Mock::new();
+        let paths = test_paths("renew");
+        let mut config = test_config(&mock, &paths);
+        register_with(&mock, &config, &paths, &["register"]);
+        let before = server_spec(&paths);
+
+        // Certificates of the mock are valid for a year
+        config.renew_before = Some(400 * 24 * 3600);
+        renew_expiring_certificates(&mock, &config, &paths).unwrap();
+        let after = server_spec(&paths);
+        assert_eq!(mock.pairings.get(), 2);
+        assert_eq!(after.uuid, before.uuid);
+        assert_ne!(after.certificate, before.certificate);
+        assert!(
+            certs::check_certificate(&after.certificate, &after.private_key, &after.uuid).is_ok()
+        );
+
+        // Nothing to do with certificates which are still valid for long
+        config.renew_before = Some(24 * 3600);
+        renew_expiring_certificates(&mock, &config, &paths).unwrap();
+        assert_eq!(mock.pairings.get(), 2);
+        fs::remove_dir_all(&paths.home_dir).unwrap();
+    }
+}
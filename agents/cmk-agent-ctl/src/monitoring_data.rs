@@ -2,12 +2,279 @@
 // This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
 // conditions defined in the file COPYING, which is part of this source code package.
 
-use std::io::{Read, Result as IoResult};
+use super::{address, config};
+use std::io::{self, BufRead, BufReader, Read, Result as IoResult};
+use std::net::TcpStream;
 use std::os::unix::net::UnixStream;
 
-pub fn collect(package_name: Option<String>) -> IoResult<Vec<u8>> {
+pub fn collect(config: &config::Config) -> IoResult<Vec<u8>> {
+    Ok(filter(fetch(config)?, config))
+}
+
+fn socket_path(config: &config::Config) -> String {
+    match &config.agent_socket {
+        Some(agent_socket) => agent_socket.clone(),
+        None => format!(
+            "/run/{}.socket",
+            config
+                .package_name
+                .as_deref()
+                .unwrap_or(config::DEFAULT_PACKAGE_NAME)
+        ),
+    }
+}
+
+fn connect_socket(path: &str) -> IoResult<Box<dyn Read + Send>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+// Where the agent output is read from if data_source is set
+#[derive(PartialEq, Debug)]
+pub enum DataSource {
+    Tcp { host: String, port: u16 },
+    Socket(String),
+}
+
+// The agent output is read without encryption, so TCP sources have to be on this host
+pub fn parse_data_source(data_source: &str) -> Result<DataSource, String> {
+    if let Some(path) = data_source.strip_prefix("unix://") {
+        if !path.starts_with('/') {
+            return Err(format!(
+                "Data source {} needs an absolute path, as in unix:///run/check-mk-agent.socket",
+                data_source
+            ));
+        }
+        return Ok(DataSource::Socket(String::from(path)));
+    }
+    let host_port = data_source.strip_prefix("tcp://").ok_or_else(|| {
+        format!(
+            "Data source {} must be tcp://host:port or unix:///path",
+            data_source
+        )
+    })?;
+    let has_port = match host_port.strip_prefix('[') {
+        Some(bracketed) => bracketed.contains("]:"),
+        None => host_port.contains(':'),
+    };
+    if !has_port {
+        return Err(format!(
+            "Data source {} lacks the port, as in tcp://127.0.0.1:6556",
+            data_source
+        ));
+    }
+    let address = address::parse(host_port)?;
+    if !(address.host == "localhost" || address.ip().is_some_and(|ip| ip.is_loopback())) {
+        return Err(format!(
+            "Data source {} is not on this host, only localhost and loopback addresses are allowed",
+            data_source
+        ));
+    }
+    Ok(DataSource::Tcp {
+        host: String::from(address.host),
+        port: address.port,
+    })
+}
+
+// A data source takes precedence over a configured TCP port, which takes precedence over the
+// unix socket
+pub fn connect(config: &config::Config) -> IoResult<Box<dyn Read + Send>> {
+    if let Some(data_source) = &config.data_source {
+        return match parse_data_source(data_source)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?
+        {
+            DataSource::Tcp { host, port } => {
+                Ok(Box::new(TcpStream::connect((host.as_str(), port))?))
+            }
+            DataSource::Socket(path) => connect_socket(&path),
+        };
+    }
+    Ok(match config.agent_port {
+        Some(port) => Box::new(TcpStream::connect(("localhost", port))?),
+        None => connect_socket(&socket_path(config))?,
+    })
+}
+
+pub fn fetch(config: &config::Config) -> IoResult<Vec<u8>> {
     let mut mondata: Vec<u8> = vec![];
-    let package_name = package_name.unwrap_or(String::from("check-mk-agent"));
-    UnixStream::connect(format!("/run/{}.socket", package_name))?.read_to_end(&mut mondata)?;
-    return Ok(mondata);
+    connect(config)?.read_to_end(&mut mondata)?;
+    Ok(mondata)
+}
+
+pub fn filter(mondata: Vec<u8>, config: &config::Config) -> Vec<u8> {
+    if config.sections.is_none() && config.exclude_sections.is_none() {
+        return mondata;
+    }
+    let mut filtered = vec![];
+    // Reading from a slice does not fail
+    let _ = SectionFilter::new(&mondata[..], config).read_to_end(&mut filtered);
+    filtered
+}
+
+// Sections reporting on the controller itself, which are never filtered
+pub fn section(name: &str, lines: &[String]) -> Vec<u8> {
+    if lines.is_empty() {
+        return vec![];
+    }
+    let mut section = format!("<<<{}:sep(0)>>>\n", name);
+    for line in lines {
+        section.push_str(line);
+        section.push('\n');
+    }
+    section.into_bytes()
+}
+
+fn section_name(line: &[u8]) -> Option<&[u8]> {
+    // Piggyback headers (<<<<host>>>>) are no section headers
+    if line.starts_with(b"<<<<") {
+        return None;
+    }
+    let header = line.strip_prefix(b"<<<")?.strip_suffix(b">>>")?;
+    // Strip section options, as in <<<name:sep(0)>>>
+    Some(header.split(|byte| *byte == b':').next().unwrap_or(header))
+}
+
+// Drops unwanted sections line by line while the agent output is read, such that large
+// outputs can be passed on without holding them in memory
+pub struct SectionFilter<R> {
+    reader: BufReader<R>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    in_wanted_section: bool,
+    line: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> SectionFilter<R> {
+    pub fn new(reader: R, config: &config::Config) -> SectionFilter<R> {
+        SectionFilter {
+            reader: BufReader::new(reader),
+            include: config.sections.clone(),
+            exclude: config.exclude_sections.clone(),
+            in_wanted_section: true,
+            line: vec![],
+            position: 0,
+        }
+    }
+
+    fn is_wanted(&self, name: &[u8]) -> bool {
+        let contains = |names: &[String]| names.iter().any(|s| s.as_bytes() == name);
+        self.include.as_deref().is_none_or(contains)
+            && !self.exclude.as_deref().is_some_and(contains)
+    }
+
+    // The next line of a wanted section, or an empty one at the end of the output
+    fn next_line(&mut self) -> IoResult<()> {
+        loop {
+            self.line.clear();
+            self.position = 0;
+            if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(());
+            }
+            let trimmed = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+            if let Some(name) = section_name(trimmed) {
+                self.in_wanted_section = self.is_wanted(name);
+            }
+            if self.in_wanted_section {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for SectionFilter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.position == self.line.len() {
+            self.next_line()?;
+        }
+        let remaining = &self.line[self.position..];
+        let length = remaining.len().min(buf.len());
+        buf[..length].copy_from_slice(&remaining[..length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_sections(
+        mondata: Vec<u8>,
+        include: Option<&[String]>,
+        exclude: Option<&[String]>,
+    ) -> Vec<u8> {
+        let config = config::Config {
+            sections: include.map(<[String]>::to_vec),
+            exclude_sections: exclude.map(<[String]>::to_vec),
+            ..config::Config::empty_config()
+        };
+        filter(mondata, &config)
+    }
+
+    const MONDATA: &[u8] =
+        b"<<<check_mk>>>\nVersion: 2.1\n<<<df:sep(0)>>>\n/ 42\n<<<logwatch>>>\nfoo\n";
+
+    #[test]
+    fn test_filter_sections_include() {
+        assert_eq!(
+            filter_sections(MONDATA.to_vec(), Some(&[String::from("df")]), None),
+            b"<<<df:sep(0)>>>\n/ 42\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_data_source() {
+        assert_eq!(
+            parse_data_source("tcp://127.0.0.1:6556"),
+            Ok(DataSource::Tcp {
+                host: String::from("127.0.0.1"),
+                port: 6556
+            })
+        );
+        assert_eq!(
+            parse_data_source("tcp://[::1]:6556"),
+            Ok(DataSource::Tcp {
+                host: String::from("::1"),
+                port: 6556
+            })
+        );
+        assert_eq!(
+            parse_data_source("unix:///run/check-mk-agent.socket"),
+            Ok(DataSource::Socket(String::from(
+                "/run/check-mk-agent.socket"
+            )))
+        );
+        assert!(parse_data_source("tcp://localhost").is_err());
+        assert!(parse_data_source("tcp://10.0.0.1:6556").is_err());
+        assert!(parse_data_source("unix://agent.socket").is_err());
+        assert!(parse_data_source("127.0.0.1:6556").is_err());
+    }
+
+    #[test]
+    fn test_connect_data_source() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = config::Config {
+            data_source: Some(format!(
+                "tcp://127.0.0.1:{}",
+                listener.local_addr().unwrap().port()
+            )),
+            // Ignored in favor of the data source
+            agent_port: Some(1),
+            ..config::Config::empty_config()
+        };
+        let agent = std::thread::spawn(move || {
+            use std::io::Write;
+            let _ = listener.accept().unwrap().0.write_all(MONDATA);
+        });
+        assert_eq!(fetch(&config).unwrap(), MONDATA);
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn test_filter_sections_exclude() {
+        assert_eq!(
+            filter_sections(MONDATA.to_vec(), None, Some(&[String::from("logwatch")])),
+            b"<<<check_mk>>>\nVersion: 2.1\n<<<df:sep(0)>>>\n/ 42\n"
+        );
+    }
 }
@@ -0,0 +1,111 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// A network in CIDR notation, a bare address is a network of its own
+#[derive(Debug, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_len: u32,
+}
+
+fn bits(address: IpAddr) -> (u128, u32) {
+    match address {
+        IpAddr::V4(address) => (u128::from(u32::from(address)), 32),
+        IpAddr::V6(address) => (u128::from(address), 128),
+    }
+}
+
+// Dual-stack listeners report IPv4 peers as IPv4-mapped IPv6 addresses
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        address => address,
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address = canonical(
+            address
+                .parse::<IpAddr>()
+                .map_err(|_| format!("Invalid address in {}", s))?,
+        );
+        let (_, max_prefix_len) = bits(address);
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length in {}", s))?,
+            None => max_prefix_len,
+        };
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = canonical(address);
+        if self.address.is_ipv4() != address.is_ipv4() {
+            return false;
+        }
+        // Shifting out all 128 bits would overflow
+        if self.prefix_len == 0 {
+            return true;
+        }
+        let (network, len) = bits(self.address);
+        let (address, _) = bits(address);
+        (network ^ address) >> (len - self.prefix_len) == 0
+    }
+}
+
+pub fn parse(only_from: &[String]) -> Result<Vec<Network>, String> {
+    only_from.iter().map(|network| network.parse()).collect()
+}
+
+pub fn is_allowed(networks: &[Network], address: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(only_from: &[&str], address: &str) -> bool {
+        let only_from: Vec<String> = only_from.iter().map(|s| s.to_string()).collect();
+        is_allowed(&parse(&only_from).unwrap(), address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        assert!(allowed(&["10.0.0.0/8"], "10.1.2.3"));
+        assert!(!allowed(&["10.0.0.0/8"], "11.1.2.3"));
+        assert!(allowed(&["192.168.1.5"], "192.168.1.5"));
+        assert!(!allowed(&["192.168.1.5"], "192.168.1.6"));
+        assert!(allowed(&["0.0.0.0/0"], "8.8.8.8"));
+        assert!(allowed(&["10.0.0.0/8"], "::ffff:10.0.0.1"));
+        assert!(allowed(&["fd00::/8"], "fd12::1"));
+        assert!(!allowed(&["fd00::/8"], "10.0.0.1"));
+        assert!(!allowed(&[], "127.0.0.1"));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("fd00::/129".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{cli, config, spool};
+use std::path::{Path, PathBuf};
+
+const HOME_DIR: &str = "/var/lib/cmk-agent";
+// Normally, the config would be expected at /etc/check_mk/, but we
+// need to read it as cmk-agent user, so we use its home directory.
+const CONFIG_FILE: &str = "cmk-agent-ctl-config.json";
+const CONFIG_FILE_TOML: &str = "cmk-agent-ctl-config.toml";
+const CONFIG_DROP_IN_DIR: &str = "cmk-agent-ctl.d";
+const SECRETS_FILE: &str = "cmk-agent-ctl-secrets.json";
+
+const STATE_FILE: &str = "cmk-agent-ctl-state.json";
+const RUNTIME_FILE: &str = "cmk-agent-ctl-runtime.json";
+const STATS_FILE: &str = "cmk-agent-ctl-stats.json";
+const PENDING_FILE: &str = "cmk-agent-ctl-pending.json";
+const TRUSTED_ROOTS_FILE: &str = "cmk-agent-ctl-trusted-roots.json";
+const LOG_FILE: &str = "cmk-agent-ctl.log";
+const SPOOL_DIR: &str = "spool";
+const LEGACY_PULL_FILE: &str = "allow-legacy-pull";
+
+#[derive(Clone)]
+pub struct Paths {
+    pub home_dir: PathBuf,
+    pub config_path: PathBuf,
+    pub drop_in_dir: PathBuf,
+    pub secrets_path: PathBuf,
+    pub state_path: PathBuf,
+    pub runtime_path: PathBuf,
+    pub stats_path: PathBuf,
+    pub pending_path: PathBuf,
+    pub trusted_roots_path: PathBuf,
+    pub log_path: PathBuf,
+    pub legacy_pull_path: PathBuf,
+    pub spool_dir: PathBuf,
+}
+
+pub fn default_config_path(home_dir: &Path, toml: bool) -> PathBuf {
+    home_dir.join(if toml { CONFIG_FILE_TOML } else { CONFIG_FILE })
+}
+
+// The JSON configuration takes precedence, such that existing setups keep working
+fn config_path(home_dir: &Path) -> PathBuf {
+    let json_path = home_dir.join(CONFIG_FILE);
+    let toml_path = home_dir.join(CONFIG_FILE_TOML);
+    if !json_path.exists() && toml_path.exists() {
+        return toml_path;
+    }
+    json_path
+}
+
+impl Paths {
+    pub fn new(args: &cli::PathArgs) -> Paths {
+        let home_dir = args
+            .home_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(HOME_DIR));
+        Paths {
+            config_path: args
+                .config_file
+                .clone()
+                .unwrap_or_else(|| config_path(&home_dir)),
+            drop_in_dir: home_dir.join(CONFIG_DROP_IN_DIR),
+            secrets_path: home_dir.join(SECRETS_FILE),
+            state_path: args
+                .state_file
+                .clone()
+                .unwrap_or_else(|| home_dir.join(STATE_FILE)),
+            runtime_path: home_dir.join(RUNTIME_FILE),
+            stats_path: home_dir.join(STATS_FILE),
+            pending_path: home_dir.join(PENDING_FILE),
+            trusted_roots_path: home_dir.join(TRUSTED_ROOTS_FILE),
+            log_path: args
+                .log_file
+                .clone()
+                .unwrap_or_else(|| home_dir.join(LOG_FILE)),
+            legacy_pull_path: home_dir.join(LEGACY_PULL_FILE),
+            spool_dir: home_dir.join(SPOOL_DIR),
+            home_dir,
+        }
+    }
+
+    // Everything the controller may create, such that it stays accessible for the cmk-agent user
+    pub fn owned_by_agent_user(&self) -> Vec<PathBuf> {
+        let mut paths = vec![
+            self.home_dir.clone(),
+            self.state_path.clone(),
+            config::StateLock::path(&self.state_path),
+            self.config_path.clone(),
+            self.log_path.clone(),
+            self.runtime_path.clone(),
+            self.stats_path.clone(),
+            self.pending_path.clone(),
+            self.trusted_roots_path.clone(),
+            self.secrets_path.clone(),
+        ];
+        paths.extend(config::state_backups(&self.state_path).unwrap_or_default());
+        paths.extend(spool::all_paths(&self.spool_dir));
+        paths
+    }
+}
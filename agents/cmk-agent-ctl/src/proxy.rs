@@ -0,0 +1,235 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::address;
+use anyhow::{anyhow, Context, Result as AnyhowResult};
+use openssl::base64;
+use reqwest::Url;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+
+const MAX_RESPONSE_HEADER_SIZE: usize = 8192;
+const SOCKS_DEFAULT_PORT: u16 = 1080;
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASSWORD_AUTH: u8 = 2;
+const SOCKS_NO_ACCEPTABLE_AUTH: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN_NAME: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+// socks5:// resolves the agent receiver locally, socks5h:// lets the proxy resolve it
+pub fn parse(proxy_url: &str) -> AnyhowResult<Url> {
+    let url = Url::parse(proxy_url).context(format!("Invalid proxy URL {}", proxy_url))?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(url),
+        scheme => Err(anyhow!("Unsupported proxy scheme {}", scheme)),
+    }
+}
+
+// Requests to the API are proxied by reqwest, but raw TLS connections, like the one used
+// for fetching the root certificate, have to be tunneled via CONNECT or SOCKS5.
+pub fn tunnel(proxy_url: &str, address: &str) -> AnyhowResult<TcpStream> {
+    let url = parse(proxy_url)?;
+    if url.scheme() == "https" {
+        return Err(anyhow!(
+            "Tunneling is only supported through http:// and socks5:// proxies"
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("Proxy URL {} has no host", proxy_url))?;
+    let port = url.port_or_known_default().unwrap_or(match url.scheme() {
+        "http" => 80,
+        _ => SOCKS_DEFAULT_PORT,
+    });
+    let stream = TcpStream::connect((host, port))
+        .context(format!("Could not connect to proxy {}:{}", host, port))?;
+
+    match url.scheme() {
+        "http" => http_connect(&url, stream, address),
+        _ => socks5_connect(&url, stream, address),
+    }
+}
+
+fn http_connect(url: &Url, mut stream: TcpStream, address: &str) -> AnyhowResult<TcpStream> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", address);
+    if !url.username().is_empty() {
+        let credentials = format!("{}:{}", url.username(), url.password().unwrap_or(""));
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64::encode_block(credentials.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    // Read byte by byte, such that nothing of the tunneled connection is consumed
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0; 1];
+        if stream.read(&mut byte)? == 0 {
+            return Err(anyhow!("Proxy closed the connection"));
+        }
+        response.push(byte[0]);
+        if response.len() > MAX_RESPONSE_HEADER_SIZE {
+            return Err(anyhow!("Proxy sent an oversized response"));
+        }
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(anyhow!(
+            "Proxy refused to connect to {}: {}",
+            address,
+            status_line
+        ));
+    }
+    Ok(stream)
+}
+
+fn read_bytes(stream: &mut TcpStream, count: usize) -> AnyhowResult<Vec<u8>> {
+    let mut bytes = vec![0; count];
+    stream
+        .read_exact(&mut bytes)
+        .context("Proxy closed the connection")?;
+    Ok(bytes)
+}
+
+// The credentials in the URL are sent with the username/password method of RFC 1929
+fn socks5_authenticate(url: &Url, stream: &mut TcpStream) -> AnyhowResult<()> {
+    let with_credentials = !url.username().is_empty();
+    let mut greeting = vec![SOCKS_VERSION, 1, SOCKS_NO_AUTH];
+    if with_credentials {
+        greeting = vec![SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASSWORD_AUTH];
+    }
+    stream.write_all(&greeting)?;
+
+    match read_bytes(stream, 2)?[..] {
+        [SOCKS_VERSION, SOCKS_NO_AUTH] => Ok(()),
+        [SOCKS_VERSION, SOCKS_USER_PASSWORD_AUTH] if with_credentials => {
+            let username = url.username().as_bytes();
+            let password = url.password().unwrap_or("").as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(anyhow!(
+                    "SOCKS5 user names and passwords are limited to 255 bytes"
+                ));
+            }
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request)?;
+            match read_bytes(stream, 2)?[..] {
+                [1, 0] => Ok(()),
+                _ => Err(anyhow!("SOCKS5 proxy rejected the credentials")),
+            }
+        }
+        [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_AUTH] => Err(anyhow!(
+            "SOCKS5 proxy accepts none of the offered authentication methods"
+        )),
+        _ => Err(anyhow!("Proxy does not speak SOCKS5")),
+    }
+}
+
+fn socks5_destination(remote_dns: bool, address: &str) -> AnyhowResult<Vec<u8>> {
+    let address::Address { host, port } =
+        address::parse(address).map_err(|message| anyhow!(message))?;
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) if remote_dns => None,
+        Err(_) => Some(
+            (host, port)
+                .to_socket_addrs()
+                .context(format!("Could not resolve {}", address))?
+                .next()
+                .map(|socket_addr: SocketAddr| socket_addr.ip())
+                .ok_or_else(|| anyhow!("{} did not resolve to any address", address))?,
+        ),
+    };
+
+    let mut destination = match ip {
+        Some(IpAddr::V4(ip)) => [&[SOCKS_IPV4][..], &ip.octets()].concat(),
+        Some(IpAddr::V6(ip)) => [&[SOCKS_IPV6][..], &ip.octets()].concat(),
+        None if host.len() > 255 => return Err(anyhow!("Host name {} is too long", host)),
+        None => [&[SOCKS_DOMAIN_NAME, host.len() as u8][..], host.as_bytes()].concat(),
+    };
+    destination.extend_from_slice(&port.to_be_bytes());
+    Ok(destination)
+}
+
+fn socks5_reply_message(reply: u8) -> &'static str {
+    match reply {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn socks5_connect(url: &Url, mut stream: TcpStream, address: &str) -> AnyhowResult<TcpStream> {
+    socks5_authenticate(url, &mut stream)?;
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    request.extend(socks5_destination(url.scheme() == "socks5h", address)?);
+    stream.write_all(&request)?;
+
+    let reply = read_bytes(&mut stream, 4)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(anyhow!("Proxy does not speak SOCKS5"));
+    }
+    if reply[1] != 0 {
+        return Err(anyhow!(
+            "Proxy refused to connect to {}: {}",
+            address,
+            socks5_reply_message(reply[1])
+        ));
+    }
+    // The address the proxy bound to is of no interest, but has to be consumed
+    let bound_address_size = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN_NAME => usize::from(read_bytes(&mut stream, 1)?[0]),
+        _ => return Err(anyhow!("SOCKS5 proxy sent an invalid address type")),
+    };
+    read_bytes(&mut stream, bound_address_size + 2)?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socks5_destination() {
+        assert_eq!(
+            socks5_destination(true, "checkmk.example.com:8000").unwrap(),
+            [
+                &[SOCKS_DOMAIN_NAME, 19][..],
+                b"checkmk.example.com",
+                &[0x1f, 0x40]
+            ]
+            .concat()
+        );
+        assert_eq!(
+            socks5_destination(false, "10.1.2.3:443").unwrap(),
+            vec![SOCKS_IPV4, 10, 1, 2, 3, 1, 187]
+        );
+        assert_eq!(
+            socks5_destination(true, "[::1]:8000").unwrap()[..2],
+            [SOCKS_IPV6, 0]
+        );
+        assert_eq!(
+            socks5_destination(true, "checkmk.example.com").unwrap()[21..],
+            [0x1f, 0x40]
+        );
+        assert!(socks5_destination(true, "2001:db8::1").is_err());
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::certs;
+use super::error::ApiError;
+
+pub fn is_rejection(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ApiError>()
+        .is_some_and(ApiError::is_rejection)
+}
+
+// A registration is stale if the receiver rejects the UUID, or if it has been failing for
+// longer than stale_after seconds. Other errors may well be temporary.
+pub fn stale_reason(
+    error: Option<&anyhow::Error>,
+    failing_since: Option<i64>,
+    now: i64,
+    stale_after: u64,
+) -> Option<String> {
+    let error = error?;
+    if is_rejection(error) {
+        return Some(format!("Registration rejected: {}", error));
+    }
+    let failing_since = failing_since?;
+    if now - failing_since < stale_after as i64 {
+        return None;
+    }
+    Some(format!(
+        "Failing since {}: {}",
+        certs::format_timestamp(failing_since),
+        error
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    fn request_failed(status: StatusCode) -> anyhow::Error {
+        ApiError::from_status(status, String::new()).into()
+    }
+
+    #[test]
+    fn test_stale_reason() {
+        assert!(stale_reason(None, None, 1000, 100).is_none());
+        assert!(stale_reason(
+            Some(&request_failed(StatusCode::NOT_FOUND)),
+            None,
+            1000,
+            100
+        )
+        .is_some());
+
+        let unreachable = anyhow::anyhow!("Connection refused");
+        assert!(stale_reason(Some(&unreachable), Some(950), 1000, 100).is_none());
+        assert!(stale_reason(Some(&unreachable), Some(900), 1000, 100).is_some());
+
+        let server_error = request_failed(StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(stale_reason(Some(&server_error), Some(950), 1000, 100).is_none());
+    }
+}
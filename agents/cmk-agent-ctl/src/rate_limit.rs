@@ -0,0 +1,105 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use std::io::{Read, Result as IoResult};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Reads are split into pieces of a tenth of a second, such that the upload does not come in
+// bursts of whole chunks on slow links
+const PIECES_PER_SECOND: u64 = 10;
+const MIN_PIECE_SIZE: u64 = 1024;
+
+// Shared by the uploads to all agent receivers, such that they stay below the limit together.
+// Each piece of data gets the next slot of the link, unused time is not saved up for bursts.
+#[derive(Clone)]
+pub struct RateLimit {
+    bytes_per_second: u64,
+    next_slot: Arc<Mutex<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> RateLimit {
+        RateLimit {
+            bytes_per_second: bytes_per_second.max(1),
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    fn piece_size(&self) -> usize {
+        (self.bytes_per_second / PIECES_PER_SECOND).max(MIN_PIECE_SIZE) as usize
+    }
+
+    // Blocks until this many bytes may go out
+    fn wait(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let delay = {
+            let mut next_slot = match self.next_slot.lock() {
+                Ok(next_slot) => next_slot,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let now = Instant::now();
+            let slot = (*next_slot).max(now);
+            *next_slot =
+                slot + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            slot - now
+        };
+        thread::sleep(delay);
+    }
+
+    pub fn limit<'a>(&self, reader: impl Read + Send + 'a) -> Box<dyn Read + Send + 'a> {
+        Box::new(Limited {
+            reader,
+            rate_limit: self.clone(),
+        })
+    }
+}
+
+struct Limited<R> {
+    reader: R,
+    rate_limit: RateLimit,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let size = buf.len().min(self.rate_limit.piece_size());
+        let length = self.reader.read(&mut buf[..size])?;
+        self.rate_limit.wait(length);
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let rate_limit = RateLimit::new(200_000);
+        let data = vec![7; 60_000];
+        let started = Instant::now();
+        // Two uploads share the limit
+        let uploads: Vec<thread::JoinHandle<Vec<u8>>> = (0..2)
+            .map(|_| {
+                let (rate_limit, data) = (rate_limit.clone(), data.clone());
+                thread::spawn(move || {
+                    let mut sent = vec![];
+                    rate_limit.limit(&data[..]).read_to_end(&mut sent).unwrap();
+                    sent
+                })
+            })
+            .collect();
+        for upload in uploads {
+            assert_eq!(upload.join().unwrap(), data);
+        }
+        // 120 kB at 200 kB/s, the first piece goes out right away and the last one after half
+        // a second
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use nix::libc::c_int;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn install_handler() -> nix::Result<()> {
+    // No SA_RESTART, such that blocking calls like accept() return and the
+    // daemons get the chance to reload promptly.
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sighup),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { signal::sigaction(Signal::SIGHUP, &action) }?;
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+// The state file is replaced by renaming a temporary file, so its directory is watched
+// instead of the file itself, which would only report changes of the replaced inode.
+pub struct StateWatcher {
+    inotify: Inotify,
+    file_name: OsString,
+}
+
+impl StateWatcher {
+    pub fn new(state_path: &Path) -> nix::Result<StateWatcher> {
+        let dir = match state_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            dir,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+        )?;
+        Ok(StateWatcher {
+            inotify,
+            file_name: state_path.file_name().unwrap_or_default().to_os_string(),
+        })
+    }
+
+    // Whether the state file changed since the last call, without blocking
+    pub fn changed(&self) -> bool {
+        let mut changed = false;
+        while let Ok(events) = self.inotify.read_events() {
+            if events.is_empty() {
+                break;
+            }
+            changed |= events
+                .iter()
+                .any(|event| event.name.as_ref() == Some(&self.file_name));
+        }
+        changed
+    }
+}
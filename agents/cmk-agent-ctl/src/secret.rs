@@ -0,0 +1,65 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+// Private keys, passwords and passphrases, which are overwritten when dropped and never show
+// up in Debug output. Copies handed to libraries, e.g. HTTP headers, are out of reach.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+// Volatile writes, such that the compiler cannot skip them as dead stores
+pub fn wipe(value: &mut String) {
+    // Zeros are valid UTF-8, so the string stays intact
+    for byte in unsafe { value.as_bytes_mut() } {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+    value.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::from(String::from("password"));
+        assert_eq!(secret.expose(), "password");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"password\"");
+
+        let mut value = String::from("password");
+        wipe(&mut value);
+        assert!(value.is_empty());
+    }
+}
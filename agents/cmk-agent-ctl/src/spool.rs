@@ -0,0 +1,159 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Result as IoResult};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SUFFIX: &str = ".payload";
+
+// Monitoring data which could not be pushed because the receiver was unreachable, kept per
+// registration until it can be pushed again. The files are named after the time the data was
+// collected, such that they sort oldest first.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+pub struct Entry {
+    pub path: PathBuf,
+    pub collected_at: i64,
+}
+
+impl Spool {
+    pub fn new(spool_dir: &Path, uuid: &str) -> Spool {
+        Spool {
+            dir: spool_dir.join(uuid),
+        }
+    }
+
+    // The oldest payloads are dropped if more than max_entries would be kept
+    pub fn store(
+        &self,
+        collected_at: i64,
+        mut payload: impl Read,
+        max_entries: usize,
+    ) -> IoResult<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)?;
+        // Files in the making are hidden from entries(), such that no partial data is replayed
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or(0);
+        let name = format!("{:012}-{:09}{}", collected_at, nanos, SUFFIX);
+        let tmp_path = self.dir.join(format!(".{}", name));
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .and_then(|mut file| io::copy(&mut payload, &mut file))
+            .and_then(|_| fs::rename(&tmp_path, self.dir.join(name)));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result?;
+
+        let entries = self.entries()?;
+        for entry in &entries[..entries.len().saturating_sub(max_entries)] {
+            fs::remove_file(&entry.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> IoResult<Vec<Entry>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            let collected_at = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .filter(|name| !name.starts_with('.'))
+                .and_then(|name| name.strip_suffix(SUFFIX))
+                .and_then(|name| name.split('-').next())
+                .and_then(|collected_at| collected_at.parse().ok());
+            if let Some(collected_at) = collected_at {
+                entries.push(Entry { path, collected_at });
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    pub fn open(entry: &Entry) -> IoResult<File> {
+        File::open(&entry.path)
+    }
+
+    pub fn remove(entry: &Entry) -> IoResult<()> {
+        fs::remove_file(&entry.path)
+    }
+
+    // For registrations which are deleted
+    pub fn discard(&self) -> IoResult<()> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+        fs::remove_dir_all(&self.dir)
+    }
+}
+
+// The spool directory with the spools of all registrations and their payloads
+pub fn all_paths(spool_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    let mut dirs = vec![spool_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if let Ok(dir_entries) = fs::read_dir(&dir) {
+            for path in dir_entries.filter_map(|dir_entry| Some(dir_entry.ok()?.path())) {
+                if path.is_dir() {
+                    dirs.push(path.clone());
+                }
+                paths.push(path);
+            }
+            paths.push(dir);
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_bounded() {
+        let spool_dir =
+            std::env::temp_dir().join(format!("cmk-agent-ctl-spool-{}", std::process::id()));
+        let spool = Spool::new(&spool_dir, "uuid");
+        for collected_at in [300, 100, 200, 400] {
+            spool
+                .store(collected_at, format!("{}", collected_at).as_bytes(), 3)
+                .unwrap();
+        }
+        let entries = spool.entries().unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.collected_at)
+                .collect::<Vec<i64>>(),
+            vec![200, 300, 400]
+        );
+        let mut payload = String::new();
+        Spool::open(&entries[0])
+            .unwrap()
+            .read_to_string(&mut payload)
+            .unwrap();
+        assert_eq!(payload, "200");
+        spool.discard().unwrap();
+        assert!(spool.entries().unwrap().is_empty());
+        let _ = fs::remove_dir(&spool_dir);
+    }
+}
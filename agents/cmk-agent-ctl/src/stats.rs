@@ -0,0 +1,119 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::config;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+// Enough requests to smooth out single slow ones, few enough to notice a slowdown within an
+// hour of pushing every minute
+const LATENCY_SAMPLES: usize = 20;
+
+// Outcomes of the requests to each agent receiver, such that status shows receivers which get
+// slow or keep failing before data stops flowing
+#[derive(Serialize, Deserialize, Default)]
+pub struct Stats {
+    #[serde(default)]
+    pub receivers: HashMap<String, ReceiverStats>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct ReceiverStats {
+    #[serde(default)]
+    pub last_success: Option<i64>,
+
+    #[serde(default)]
+    pub last_failure: Option<i64>,
+
+    #[serde(default)]
+    pub last_error: Option<String>,
+
+    #[serde(default)]
+    pub consecutive_failures: u64,
+
+    // Milliseconds the latest successful requests took, oldest first. Failed requests are left
+    // out, timeouts would only measure the timeout.
+    #[serde(default)]
+    pub latencies: Vec<u64>,
+}
+
+impl ReceiverStats {
+    pub fn average_latency(&self) -> Option<u64> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        Some(self.latencies.iter().sum::<u64>() / self.latencies.len() as u64)
+    }
+
+    pub fn max_latency(&self) -> Option<u64> {
+        self.latencies.iter().max().copied()
+    }
+}
+
+impl Stats {
+    pub fn from_file(path: &Path) -> io::Result<Stats> {
+        if path.exists() {
+            return Ok(serde_json::from_str(&read_to_string(path)?)?);
+        }
+        Ok(Stats::default())
+    }
+
+    // Like the runtime state, written atomically by writers holding the StateLock of the path
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        config::write_atomically(path, &serde_json::to_string(self)?)
+    }
+
+    pub fn record(&mut self, address: &str, outcome: Result<Duration, String>, now: i64) {
+        let stats = self.receivers.entry(String::from(address)).or_default();
+        match outcome {
+            Ok(latency) => {
+                stats.last_success = Some(now);
+                stats.consecutive_failures = 0;
+                stats.latencies.push(latency.as_millis() as u64);
+                let excess = stats.latencies.len().saturating_sub(LATENCY_SAMPLES);
+                stats.latencies.drain(..excess);
+            }
+            Err(error) => {
+                stats.last_failure = Some(now);
+                stats.last_error = Some(error);
+                stats.consecutive_failures += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut stats = Stats::default();
+        for millis in 1..=LATENCY_SAMPLES as u64 + 5 {
+            stats.record("server:8000", Ok(Duration::from_millis(millis * 10)), 100);
+        }
+        stats.record("server:8000", Err(String::from("timeout")), 160);
+        stats.record("server:8000", Err(String::from("refused")), 220);
+
+        let receiver = &stats.receivers["server:8000"];
+        assert_eq!(receiver.latencies.len(), LATENCY_SAMPLES);
+        assert_eq!(receiver.latencies[0], 60);
+        assert_eq!(receiver.average_latency(), Some(155));
+        assert_eq!(receiver.max_latency(), Some(250));
+        assert_eq!(receiver.last_success, Some(100));
+        assert_eq!(receiver.last_failure, Some(220));
+        assert_eq!(receiver.last_error.as_deref(), Some("refused"));
+        assert_eq!(receiver.consecutive_failures, 2);
+
+        stats.record("server:8000", Ok(Duration::from_millis(10)), 280);
+        let receiver = &stats.receivers["server:8000"];
+        assert_eq!(receiver.consecutive_failures, 0);
+        assert_eq!(receiver.last_error.as_deref(), Some("refused"));
+        assert!(ReceiverStats::default().average_latency().is_none());
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{certs, config, stats};
+use anyhow::Result as AnyhowResult;
+use nix::unistd;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+#[derive(Serialize)]
+struct FileStatus {
+    path: String,
+    status: String,
+}
+
+#[derive(Serialize)]
+struct ConnectionStatus {
+    address: String,
+    uuid: String,
+    cert_not_before: Option<i64>,
+    cert_not_after: Option<i64>,
+    cert_error: Option<String>,
+    cert_expires_soon: bool,
+    stale: Option<String>,
+    key_rotation_pending: bool,
+    failover_addresses: Vec<String>,
+    active_address: Option<String>,
+    registration: config::RegistrationMetadata,
+    capabilities: Option<config::Capabilities>,
+    requests: Option<stats::ReceiverStats>,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    legacy_pull: bool,
+    last_push: Option<i64>,
+    last_pull: Option<i64>,
+    files: Vec<FileStatus>,
+    connections: Vec<ConnectionStatus>,
+}
+
+fn file_status(path: &Path, user: &str) -> String {
+    if !path.exists() {
+        return String::from("missing");
+    }
+    if let Err(error) = File::open(path) {
+        return format!("not readable ({})", error);
+    }
+    let owner = match fs::metadata(path) {
+        Ok(metadata) => metadata.uid(),
+        Err(error) => return format!("could not read metadata ({})", error),
+    };
+    match unistd::User::from_name(user) {
+        Ok(Some(expected)) if expected.uid.as_raw() == owner => String::from("ok"),
+        Ok(Some(_)) => format!("readable, but not owned by {}", user),
+        _ => format!("readable, but could not find user {}", user),
+    }
+}
+
+fn connection_status(
+    address: &str,
+    server_spec: &config::ServerSpec,
+    active_address: Option<&String>,
+    cert_expiry_warning: u64,
+    now: i64,
+) -> ConnectionStatus {
+    let (cert_not_before, cert_not_after, cert_error) =
+        match certs::validity(&server_spec.certificate) {
+            Ok(validity) => (Some(validity.not_before), Some(validity.not_after), None),
+            Err(error) => (None, None, Some(error.to_string())),
+        };
+    ConnectionStatus {
+        address: String::from(address),
+        uuid: server_spec.uuid.clone(),
+        cert_not_before,
+        cert_not_after,
+        cert_expires_soon: cert_not_after
+            .is_some_and(|not_after| certs::expires_within(not_after, now, cert_expiry_warning)),
+        cert_error,
+        stale: server_spec.stale.clone(),
+        key_rotation_pending: server_spec.previous_key.is_some(),
+        failover_addresses: server_spec.failover_addresses.clone(),
+        active_address: active_address.cloned(),
+        registration: server_spec.metadata.clone(),
+        capabilities: server_spec.capabilities.clone(),
+        requests: None,
+    }
+}
+
+fn format_optional_timestamp(timestamp: Option<i64>) -> String {
+    match timestamp {
+        Some(timestamp) => certs::format_timestamp(timestamp),
+        None => String::from("never"),
+    }
+}
+
+fn capabilities_text(capabilities: &config::Capabilities) -> String {
+    let mut features = vec![];
+    if !capabilities.compression.is_empty() {
+        features.push(format!(
+            "compression ({})",
+            capabilities.compression.join(", ")
+        ));
+    }
+    if capabilities.registration_status {
+        features.push(String::from("registration status"));
+    }
+    if capabilities.renewal {
+        features.push(String::from("certificate renewal"));
+    }
+    if features.is_empty() {
+        return String::from("none");
+    }
+    features.join(", ")
+}
+
+fn requests_text(requests: &stats::ReceiverStats) -> Vec<String> {
+    let mut lines = vec![format!(
+        "\tLast successful request: {}",
+        format_optional_timestamp(requests.last_success)
+    )];
+    if let Some(last_failure) = requests.last_failure {
+        lines.push(format!(
+            "\tLast failed request: {} ({})",
+            certs::format_timestamp(last_failure),
+            requests.last_error.as_deref().unwrap_or("unknown error")
+        ));
+    }
+    if requests.consecutive_failures > 0 {
+        lines.push(format!(
+            "\tConsecutive failed requests: {}",
+            requests.consecutive_failures
+        ));
+    }
+    if let (Some(average), Some(max)) = (requests.average_latency(), requests.max_latency()) {
+        lines.push(format!(
+            "\tLatency of the last {2} successful requests: {0} ms on average, at most {1} ms",
+            average,
+            max,
+            requests.latencies.len()
+        ));
+    }
+    lines
+}
+
+impl Status {
+    pub fn new(
+        reg_state: &config::RegistrationState,
+        runtime_state: &config::RuntimeState,
+        cert_expiry_warning: u64,
+        now: i64,
+        legacy_pull: bool,
+        files: &[&Path],
+        user: &str,
+    ) -> Status {
+        Status {
+            legacy_pull,
+            last_push: runtime_state.last_push,
+            last_pull: runtime_state.last_pull,
+            files: files
+                .iter()
+                .map(|path| FileStatus {
+                    path: path.display().to_string(),
+                    status: file_status(path, user),
+                })
+                .collect(),
+            connections: reg_state
+                .server_specs
+                .iter()
+                .map(|(address, server_spec)| {
+                    connection_status(
+                        address,
+                        server_spec,
+                        runtime_state.active_addresses.get(address),
+                        cert_expiry_warning,
+                        now,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn with_requests(mut self, stats: &stats::Stats) -> Status {
+        for connection in &mut self.connections {
+            connection.requests = stats.receivers.get(&connection.address).cloned();
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> AnyhowResult<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "Legacy mode: {}",
+                if self.legacy_pull {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            ),
+            format!("Last push: {}", format_optional_timestamp(self.last_push)),
+            format!("Last pull: {}", format_optional_timestamp(self.last_pull)),
+        ];
+
+        for file in &self.files {
+            lines.push(format!("{}: {}", file.path, file.status));
+        }
+
+        if self.connections.is_empty() {
+            lines.push(String::from("No connections"));
+        } else {
+            lines.push(String::from("Connections:"));
+            for connection in &self.connections {
+                lines.push(connection.address.clone());
+                lines.push(format!("\tUUID: {}", connection.uuid));
+                let registration = &connection.registration;
+                if let Some(registered_at) = registration.registered_at {
+                    lines.push(format!(
+                        "\tRegistered at: {}",
+                        certs::format_timestamp(registered_at)
+                    ));
+                }
+                if let Some(user) = &registration.user {
+                    lines.push(format!("\tRegistered by: {}", user));
+                }
+                if let Some(site) = &registration.site {
+                    lines.push(format!("\tSite: {}", site));
+                }
+                if let Some(api_version) = &registration.api_version {
+                    lines.push(format!("\tReceiver API version: {}", api_version));
+                }
+                lines.push(format!(
+                    "\tReceiver capabilities: {}",
+                    match &connection.capabilities {
+                        Some(capabilities) => capabilities_text(capabilities),
+                        None => String::from("unknown, all features are used as configured"),
+                    }
+                ));
+                if !connection.failover_addresses.is_empty() {
+                    lines.push(format!(
+                        "\tFailover addresses: {}",
+                        connection.failover_addresses.join(", ")
+                    ));
+                }
+                if let Some(active_address) = &connection.active_address {
+                    lines.push(format!("\tFailed over to: {}", active_address));
+                }
+                if let Some(stale) = &connection.stale {
+                    lines.push(format!("\tStale: {}", stale));
+                }
+                if connection.key_rotation_pending {
+                    lines.push(String::from(
+                        "\tKey rotation: pending until the site pulls with the new key",
+                    ));
+                }
+                if let Some(error) = &connection.cert_error {
+                    lines.push(format!("\tCertificate could not be parsed: {}", error));
+                }
+                if let Some(not_before) = connection.cert_not_before {
+                    lines.push(format!(
+                        "\tCertificate valid from: {}",
+                        certs::format_timestamp(not_before)
+                    ));
+                }
+                if let Some(not_after) = connection.cert_not_after {
+                    lines.push(format!(
+                        "\tCertificate valid until: {}",
+                        certs::format_timestamp(not_after)
+                    ));
+                }
+                if connection.cert_expires_soon {
+                    lines.push(String::from(
+                        "\tCertificate expires soon, run renew-certificate",
+                    ));
+                }
+                if let Some(requests) = &connection.requests {
+                    lines.extend(requests_text(requests));
+                }
+            }
+        }
+
+        lines.join("\n")
+    }
+}
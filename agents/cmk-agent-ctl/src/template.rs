@@ -0,0 +1,346 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::config;
+
+// Every configuration option with a description and an example, which is used for options
+// without a default value. Keep this in sync with config::Config.
+const OPTIONS: &[(&str, &str, &str)] = &[
+    (
+        "agent_receiver_address",
+        "Address of the agent receiver of the Checkmk site, as host:port, host for port 8000, or [IPv6 address]:port. An absolute path is the unix socket of an agent receiver on this host, which needs root_certificate",
+        "\"checkmk.example.com:8000\"",
+    ),
+    (
+        "failover_addresses",
+        "Addresses of further agent receivers of the same site, e.g. of a standby server, which are tried in order if agent_receiver_address is unreachable. They are stored with the registration",
+        "[\"standby.example.com:8000\"]",
+    ),
+    (
+        "proxy_url",
+        "HTTP or SOCKS5 proxy for connecting to the agent receiver, may contain user:password@ for authentication. socks5h:// lets the proxy resolve the agent receiver",
+        "\"http://proxy.example.com:3128\"",
+    ),
+    (
+        "tls_verify",
+        "Verify the agent receiver against the root certificate of the site, or against the system certificate store with \"system\", e.g. behind a publicly trusted reverse proxy",
+        "",
+    ),
+    (
+        "tls_verify_hostname",
+        "Check that the certificate of the agent receiver is issued for the host name or IP address in agent_receiver_address, disable for sites whose certificates lack it",
+        "",
+    ),
+    (
+        "receiver_cert_fingerprint",
+        "SHA256 fingerprint the certificate of the agent receiver must have in addition to being verified, requires agent_receiver_address to be a host name",
+        "\"AB:CD:...\"",
+    ),
+    (
+        "package_name",
+        "Name of the agent package, which determines the agent socket",
+        "",
+    ),
+    (
+        "agent_socket",
+        "Unix socket to read the agent output from, defaults to /run/<package_name>.socket",
+        "\"/run/check-mk-agent.socket\"",
+    ),
+    (
+        "agent_port",
+        "Local TCP port to read the agent output from instead of the unix socket",
+        "6557",
+    ),
+    (
+        "data_source",
+        "Where to read the agent output from instead of agent_socket and agent_port, as tcp://host:port or unix:///path. Use tcp://127.0.0.1:6556 for legacy agents serving plaintext, the host has to be this one, since the output is not encrypted",
+        "\"tcp://127.0.0.1:6556\"",
+    ),
+    (
+        "credentials",
+        "Credentials for registering, as \"user password\". Better put them into the secrets file",
+        "\"automation secret\"",
+    ),
+    (
+        "root_certificate",
+        "PEM root certificate of the site, which is trusted without asking",
+        "\"-----BEGIN CERTIFICATE-----\\n...\"",
+    ),
+    (
+        "root_certificate_path",
+        "File containing the PEM root certificate, relative to this file's directory",
+        "\"site-root-cert.pem\"",
+    ),
+    ("host_name", "Name of this host in Checkmk", "\"myhost\""),
+    (
+        "key_algorithm",
+        "Type of newly generated keys, one of rsa, ecdsa_p256 and ed25519",
+        "",
+    ),
+    (
+        "rsa_key_size",
+        "Size of newly generated RSA keys in bits, one of 2048, 3072 and 4096",
+        "",
+    ),
+    (
+        "pkcs11_token",
+        "PKCS#11 URI of a token or TPM to generate ecdsa_p256 keys in, only their URIs are stored",
+        "\"pkcs11:token=agent?module-path=/usr/lib/x86_64-linux-gnu/libtpm2_pkcs11.so.1&pin-source=file:/etc/cmk-agent-ctl/pin\"",
+    ),
+    (
+        "csr_attributes",
+        "Attributes as key=value added to the subject of certificate requests for the site, next to the host name",
+        "[\"datacenter=fra1\", \"environment=prod\"]",
+    ),
+    (
+        "push_interval",
+        "Seconds between two pushes of monitoring data",
+        "",
+    ),
+    (
+        "push_jitter",
+        "Maximum random delay in seconds added to each push, defaults to a tenth of the interval",
+        "6",
+    ),
+    (
+        "push_timeout",
+        "Seconds to wait for the agent receiver when pushing",
+        "",
+    ),
+    (
+        "push_rate_limit",
+        "Upload at most this many bytes per second, shared by the pushes to all agent receivers, e.g. on thin WAN links. push_timeout has to leave enough time for the upload",
+        "65536",
+    ),
+    (
+        "push_compression",
+        "Compress pushed monitoring data with \"gzip\" or \"zstd\", the agent receiver has to support it",
+        "",
+    ),
+    (
+        "push_compression_level",
+        "Compression level, 0 to 9 for gzip and 1 to 22 for zstd, defaults to 6 and 3",
+        "6",
+    ),
+    (
+        "min_push_interval",
+        "Never push to an agent receiver more often than every this many seconds, e.g. if a cron job pushes too often",
+        "60",
+    ),
+    (
+        "push_throttle",
+        "Whether pushes coming too soon are skipped with \"refuse\" or wait for min_push_interval to pass with \"delay\"",
+        "",
+    ),
+    (
+        "push_signature",
+        "Sign pushed payloads with the private key of the registration and send the signature in the payload-signature header, such that the agent receiver can verify them behind proxies terminating TLS. Signed payloads are held in memory",
+        "",
+    ),
+    (
+        "push_encryption_key_file",
+        "File containing a key shared with the agent receiver, with which pushed payloads are encrypted using AES-256-GCM, such that proxies inspecting TLS never see the monitoring data. Encrypted payloads are held in memory",
+        "\"/etc/cmk-agent-ctl/push-encryption-key\"",
+    ),
+    (
+        "push_spool_size",
+        "Keep up to this many pushes per agent receiver on disk while it is unreachable, and send them once it is back. 0 disables the spool",
+        "",
+    ),
+    (
+        "connect_timeout",
+        "Seconds to wait for the TCP connection to the agent receiver",
+        "",
+    ),
+    (
+        "request_timeout",
+        "Seconds to wait for the agent receiver to answer requests other than pushes, which use push_timeout",
+        "",
+    ),
+    (
+        "api_retries",
+        "How often requests to the agent receiver are repeated after connection errors, timeouts and the codes 429, 502, 503 and 504. Longer waits the agent receiver asks for with Retry-After are left to the next push",
+        "",
+    ),
+    (
+        "api_retry_backoff",
+        "Seconds to wait before the first repetition of a request, doubling with every further one up to a minute",
+        "",
+    ),
+    (
+        "http_headers",
+        "Headers as \"Name: value\" sent with every request to the agent receiver, e.g. for routing by a reverse proxy. A User-Agent given here replaces the one of the controller, the headers of the requests themselves cannot be replaced",
+        "[\"X-Tenant: acme\"]",
+    ),
+    (
+        "stale_after",
+        "Seconds an agent receiver may be failing before its registration counts as stale",
+        "",
+    ),
+    (
+        "mark_stale_on_push",
+        "Mark stale registrations when pushing, such that prune can remove them",
+        "",
+    ),
+    (
+        "cert_expiry_warning",
+        "Warn in seconds before the certificate of a registration expires, 0 disables the warning",
+        "",
+    ),
+    (
+        "renew_before",
+        "Renew certificates automatically in daemon modes this many seconds before they expire, requires credentials",
+        "1209600",
+    ),
+    (
+        "sections",
+        "Only send these sections",
+        "[\"check_mk\", \"df\"]",
+    ),
+    (
+        "exclude_sections",
+        "Never send these sections",
+        "[\"logwatch\"]",
+    ),
+    (
+        "listen_addresses",
+        "Addresses the daemon listens on for pull connections",
+        "",
+    ),
+    (
+        "listen_port",
+        "TCP port the daemon listens on for pull connections",
+        "",
+    ),
+    (
+        "only_from",
+        "Only accept pull connections from these addresses or networks in CIDR notation",
+        "[\"127.0.0.1\", \"10.0.0.0/8\"]",
+    ),
+    (
+        "legacy_pull",
+        "Set to \"never\" to refuse plaintext pull even if the allow-legacy-pull marker exists",
+        "",
+    ),
+    (
+        "crl_dir",
+        "Directory with CRLs of the site CAs as prepared by openssl rehash, pulling sites with revoked certificates are refused",
+        "\"/etc/cmk-agent-ctl/crls\"",
+    ),
+    (
+        "tls_min_version",
+        "Lowest TLS version accepted for pull connections, \"1.2\" or \"1.3\"",
+        "",
+    ),
+    (
+        "tls_cipher_suites",
+        "Only offer these cipher suites for pull connections, defaults to all suites of rustls",
+        "[\"TLS13_AES_256_GCM_SHA384\", \"TLS13_CHACHA20_POLY1305_SHA256\"]",
+    ),
+    (
+        "tls_session_resumption",
+        "Let sites resume TLS sessions of earlier pull connections to the daemon",
+        "",
+    ),
+    (
+        "crypto_policy",
+        "Set to \"fips\" to only allow FIPS approved algorithms for keys, certificates and TLS. Requires agent_receiver_address to be a host name",
+        "",
+    ),
+    (
+        "tls_key_log",
+        "Write TLS session keys to the file in the SSLKEYLOGFILE environment variable, for decrypting captured traffic. Only enable for troubleshooting",
+        "",
+    ),
+    (
+        "log_level",
+        "One of off, error, warn, info, debug and trace",
+        "",
+    ),
+    (
+        "key_passphrase_file",
+        "File containing the passphrase for encrypting the private keys in the state file",
+        "\"/etc/cmk-agent-ctl/key-passphrase\"",
+    ),
+    (
+        "use_keyring",
+        "Read credentials and the key passphrase from the Secret Service keyring instead of files, see the store-secret mode",
+        "",
+    ),
+];
+
+fn default_value(defaults: &serde_json::Value, name: &str) -> Option<String> {
+    match &defaults[name] {
+        serde_json::Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+// JSON has no comments, so all options are listed with their default values, or null
+pub fn render(toml: bool) -> String {
+    let defaults = serde_json::to_value(config::Config::defaults()).unwrap_or_default();
+    if !toml {
+        let lines: Vec<String> = OPTIONS
+            .iter()
+            .map(|(name, _, _)| {
+                format!(
+                    "  \"{}\": {}",
+                    name,
+                    default_value(&defaults, name).unwrap_or_else(|| String::from("null"))
+                )
+            })
+            .collect();
+        return format!("{{\n{}\n}}\n", lines.join(",\n"));
+    }
+
+    let mut rendered = String::from(
+        "# Configuration of cmk-agent-ctl\n\
+         #\n\
+         # All options are commented out and show their default value or an example.\n\
+         # Files in cmk-agent-ctl.d/, environment variables and command line options\n\
+         # take precedence over this file.\n",
+    );
+    for (name, description, example) in OPTIONS {
+        let value = match default_value(&defaults, name) {
+            Some(value) => value,
+            None => String::from(*example),
+        };
+        rendered.push_str(&format!("\n# {}\n# {} = {}\n", description, name, value));
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_options_documented() {
+        let mut documented: Vec<String> = OPTIONS
+            .iter()
+            .map(|(name, _, _)| name.to_string())
+            .collect();
+        documented.sort();
+        let mut field_names = config::Config::field_names();
+        field_names.sort();
+        assert_eq!(documented, field_names);
+    }
+
+    #[test]
+    fn test_rendered_templates_parse() {
+        let json: config::Config = serde_json::from_str(&render(false)).unwrap();
+        assert_eq!(json.push_interval, Some(config::DEFAULT_PUSH_INTERVAL));
+
+        let uncommented: String = render(true)
+            .lines()
+            .filter(|line| line.contains(" = "))
+            .map(|line| format!("{}\n", line.trim_start_matches("# ")))
+            .collect();
+        let toml: config::Config = toml::from_str(&uncommented).unwrap();
+        assert_eq!(
+            toml.package_name.as_deref(),
+            Some(config::DEFAULT_PACKAGE_NAME)
+        );
+    }
+}
@@ -1,87 +1,330 @@
-use super::config;
+use super::{certs, config, fips};
 use anyhow::{anyhow, Result as AnyhowResult};
-use rustls::RootCertStore;
+use log::warn;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::SslFiletype;
+use openssl::x509::store::{X509Lookup, X509Store, X509StoreBuilder};
+use openssl::x509::verify::X509VerifyFlags;
+use rustls::internal::msgs::enums::SignatureAlgorithm;
+use rustls::server::{
+    ClientCertVerified, ClientCertVerifier, NoServerSessionStorage, ServerSessionMemoryCache,
+};
+use rustls::{
+    kx_group, version, DistinguishedNames, KeyLogFile, RootCertStore, SignatureScheme,
+    SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion, Ticketer, ALL_CIPHER_SUITES,
+    ALL_KX_GROUPS,
+};
 use rustls::{
-    server::AllowAnyAuthenticatedClient, server::ResolvesServerCertUsingSni, sign::CertifiedKey,
-    sign::RsaSigningKey, Certificate, PrivateKey, ServerConfig, ServerConnection,
-    Stream as RustlsStream,
+    server::AllowAnyAuthenticatedClient, server::ClientHello, server::NoClientAuth,
+    server::ResolvesServerCert, sign, sign::CertifiedKey, Certificate, PrivateKey, ServerConfig,
+    ServerConnection, Stream as RustlsStream,
 };
-use rustls_pemfile::Item;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Result as IoResult};
 use std::io::{Read, Write};
 use std::os::unix::prelude::FromRawFd;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-pub fn tls_connection(reg_state: config::RegistrationState) -> AnyhowResult<ServerConnection> {
-    let server_specs = reg_state.server_specs.into_values().collect();
-    Ok(ServerConnection::new(tls_config(server_specs)?).unwrap())
+const SESSION_CACHE_SIZE: usize = 256;
+// Announces the protocol spoken after the handshake, such that it can evolve without further
+// magic bytes before the handshake
+pub const ALPN_PROTOCOL: &[u8] = b"cmk-agent/1";
+
+pub fn tls_connection(tls_config: Arc<ServerConfig>) -> AnyhowResult<ServerConnection> {
+    Ok(ServerConnection::new(tls_config)?)
 }
 
-pub fn tls_stream<'a>(
+pub fn tls_stream<'a, S: Read + Write>(
     server_connection: &'a mut ServerConnection,
-    stream: &'a mut IoStream,
-) -> RustlsStream<'a, ServerConnection, IoStream> {
+    stream: &'a mut S,
+) -> RustlsStream<'a, ServerConnection, S> {
     RustlsStream::new(server_connection, stream)
 }
 
-fn tls_config(server_specs: Vec<config::ServerSpec>) -> AnyhowResult<Arc<ServerConfig>> {
-    Ok(Arc::new(
-        ServerConfig::builder()
-            .with_safe_defaults()
-            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(root_cert_store(
-                &server_specs,
-            )?))
-            .with_cert_resolver(sni_resolver(&server_specs)?),
-    ))
+pub fn tls_config(
+    reg_state: config::RegistrationState,
+    config: &config::Config,
+) -> AnyhowResult<Arc<ServerConfig>> {
+    let server_specs: Vec<config::ServerSpec> = reg_state.server_specs.into_values().collect();
+    // Connections without a client certificate issued by a registered site are refused
+    let verifier = AllowAnyAuthenticatedClient::new(root_cert_store(&server_specs)?);
+    let verifier = match &config.crl_dir {
+        Some(crl_dir) => Arc::new(RevocationCheckingVerifier {
+            inner: verifier,
+            crl_store: crl_store(&server_specs, crl_dir)?,
+        }),
+        None => verifier,
+    };
+    server_config(&server_specs, config, verifier)
+}
+
+// There is no site to verify clients against in demo mode, see main
+pub fn demo_tls_config(
+    reg_state: config::RegistrationState,
+    config: &config::Config,
+) -> AnyhowResult<Arc<ServerConfig>> {
+    let server_specs: Vec<config::ServerSpec> = reg_state.server_specs.into_values().collect();
+    server_config(&server_specs, config, NoClientAuth::new())
+}
+
+fn server_config(
+    server_specs: &[config::ServerSpec],
+    config: &config::Config,
+    verifier: Arc<dyn ClientCertVerifier>,
+) -> AnyhowResult<Arc<ServerConfig>> {
+    let mut cipher_suites = match &config.tls_cipher_suites {
+        Some(names) => cipher_suites(names).map_err(|error| anyhow!(error))?,
+        None => ALL_CIPHER_SUITES.to_vec(),
+    };
+    let min_version = config.tls_min_version.unwrap_or(config::TlsVersion::Tls12);
+    let mut versions = protocol_versions(min_version);
+    let fips = fips::is_enabled(config.crypto_policy);
+    let kx_groups: &[&SupportedKxGroup] = if fips {
+        cipher_suites
+            .retain(|suite| fips::is_approved_cipher_suite(&format!("{:?}", suite.suite())));
+        // Clients offering X25519 key shares, like OpenSSL by default, need a HelloRetryRequest
+        // for TLS 1.3 then, which rustls sends without their session ID and OpenSSL rejects
+        if min_version == config::TlsVersion::Tls13 {
+            return Err(anyhow!(
+                "crypto_policy fips does not support tls_min_version 1.3"
+            ));
+        }
+        versions = vec![&version::TLS12];
+        &[&kx_group::SECP384R1, &kx_group::SECP256R1]
+    } else {
+        &ALL_KX_GROUPS
+    };
+    let mut server_config = ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites)
+        .with_kx_groups(kx_groups)
+        .with_protocol_versions(&versions)
+        .map_err(|_| {
+            anyhow!("None of the configured cipher suites supports the configured TLS versions")
+        })?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(sni_resolver(server_specs)?);
+    server_config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    if config.tls_key_log == Some(true) {
+        server_config.key_log = Arc::new(KeyLogFile::new());
+    }
+    // Sites pull every minute, resuming saves them a full handshake. This only helps the
+    // daemon, since socket activated pulls start with an empty cache every time.
+    if config.tls_session_resumption.unwrap_or(true) {
+        server_config.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+        server_config.ticketer = Ticketer::new()?;
+    } else {
+        server_config.session_storage = Arc::new(NoServerSessionStorage {});
+    }
+    Ok(Arc::new(server_config))
+}
+
+fn protocol_versions(min_version: config::TlsVersion) -> Vec<&'static SupportedProtocolVersion> {
+    match min_version {
+        config::TlsVersion::Tls12 => vec![&version::TLS13, &version::TLS12],
+        config::TlsVersion::Tls13 => vec![&version::TLS13],
+    }
+}
+
+// Suites are named as in the IANA registry, e.g. TLS13_AES_256_GCM_SHA384
+pub fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, String> {
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| format!("Unsupported cipher suite {}", name))
+        })
+        .collect()
+}
+
+// rustls cannot check revocations, so OpenSSL verifies the client certificate once more,
+// looking up the CRLs of the issuers in a directory prepared by openssl rehash. A site CA
+// without a CRL counts as failure, such that a missing CRL does not silently disable the check.
+fn crl_store(server_specs: &[config::ServerSpec], crl_dir: &str) -> AnyhowResult<X509Store> {
+    let mut builder = X509StoreBuilder::new()?;
+    for spec in server_specs {
+        for cert in certs::root_certs(&spec.root_cert)? {
+            builder.add_cert(cert)?;
+        }
+    }
+    builder
+        .add_lookup(X509Lookup::hash_dir())?
+        .add_dir(crl_dir, SslFiletype::PEM)?;
+    builder.set_flags(X509VerifyFlags::CRL_CHECK)?;
+    Ok(builder.build())
+}
+
+struct RevocationCheckingVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    crl_store: X509Store,
+}
+
+fn verify_with_store(
+    store: &X509Store,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+) -> AnyhowResult<()> {
+    let intermediates: Vec<&[u8]> = intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+    certs::verify_chain(store, &end_entity.0, &intermediates)
+}
+
+// The verifier accepts client certificates of any registered site, but a site may only pull
+// the data of its own registration, i.e. the one whose certificate was chosen via SNI
+pub fn verify_client_issuer(connection: &ServerConnection, root_cert: &str) -> AnyhowResult<()> {
+    let (end_entity, intermediates) = connection
+        .peer_certificates()
+        .and_then(|certs| certs.split_first())
+        .ok_or_else(|| anyhow!("The site sent no client certificate"))?;
+    let mut builder = X509StoreBuilder::new()?;
+    for cert in certs::root_certs(root_cert)? {
+        builder.add_cert(cert)?;
+    }
+    verify_with_store(&builder.build(), end_entity, intermediates)
+}
+
+impl ClientCertVerifier for RevocationCheckingVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        verify_with_store(&self.crl_store, end_entity, intermediates).map_err(|error| {
+            rustls::Error::InvalidCertificateData(format!("Revocation check failed: {}", error))
+        })?;
+        Ok(verified)
+    }
 }
 
-fn root_cert_store(server_specs: &Vec<config::ServerSpec>) -> AnyhowResult<RootCertStore> {
+fn root_cert_store(server_specs: &[config::ServerSpec]) -> AnyhowResult<RootCertStore> {
     let mut cert_store = RootCertStore::empty();
 
     for spec in server_specs {
-        cert_store
-            .add(&certificate(&mut spec.root_cert.as_bytes())?)
-            .unwrap();
+        for cert in certs::root_certs(&spec.root_cert)? {
+            cert_store.add(&Certificate(cert.to_der()?))?;
+        }
     }
 
     Ok(cert_store)
 }
 
-fn sni_resolver(
-    server_specs: &Vec<config::ServerSpec>,
-) -> AnyhowResult<Arc<ResolvesServerCertUsingSni>> {
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+struct CertResolver {
+    certified_keys: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    // Sites which send no SNI or their own host name instead of the UUID can only be answered
+    // unambiguously if there is a single registration
+    fn certified_key(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(certified_key) = server_name.and_then(|uuid| self.certified_keys.get(uuid)) {
+            return Some(Arc::clone(certified_key));
+        }
+        if self.certified_keys.len() == 1 {
+            return self.certified_keys.values().next().cloned();
+        }
+        match server_name {
+            Some(uuid) => warn!("Site asked for unknown registration {}", uuid),
+            None => warn!("Site sent no SNI, but there are several registrations"),
+        }
+        None
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.certified_key(client_hello.server_name())
+    }
+}
+
+fn sni_resolver(server_specs: &[config::ServerSpec]) -> AnyhowResult<Arc<CertResolver>> {
+    let mut certified_keys = HashMap::new();
 
     for spec in server_specs {
-        let key = private_key(&mut spec.private_key.as_bytes())?;
-        let cert = certificate(&mut spec.certificate.as_bytes())?;
+        let key = certs::key_backend(&spec.private_key)?;
+        let chain = certificate_chain(&mut spec.certificate.as_bytes())?;
 
-        let certified_key =
-            CertifiedKey::new(vec![cert], Arc::new(RsaSigningKey::new(&key).unwrap()));
+        // Registrations may use different key algorithms
+        let signing_key: Arc<dyn sign::SigningKey> = match key.exportable() {
+            Some(key_pair) => sign::any_supported_type(&private_key(key_pair)?)
+                .map_err(|_| anyhow!("Unsupported private key for {}", spec.uuid))?,
+            None => Arc::new(BackendSigningKey(certs::BackendSigningKey::new(key)?)),
+        };
+        let certified_key = CertifiedKey::new(chain, signing_key);
 
-        resolver.add(&spec.uuid, certified_key).unwrap();
+        certified_keys.insert(spec.uuid.clone(), Arc::new(certified_key));
     }
 
-    Ok(Arc::new(resolver))
+    Ok(Arc::new(CertResolver { certified_keys }))
 }
 
-fn private_key(bytes: &mut dyn io::BufRead) -> AnyhowResult<PrivateKey> {
-    if let Item::PKCS8Key(it) = rustls_pemfile::read_one(bytes).unwrap().unwrap() {
-        Ok(PrivateKey(it))
-    } else {
-        Err(anyhow!("Could not load private key"))
+// rustls only takes PKCS#8 for all key types, which keys of older registrations may not be
+fn private_key(key_pair: &PKey<Private>) -> AnyhowResult<PrivateKey> {
+    rustls_pemfile::pkcs8_private_keys(&mut key_pair.private_key_to_pem_pkcs8()?.as_slice())?
+        .pop()
+        .map(PrivateKey)
+        .ok_or_else(|| anyhow!("Could not load private key"))
+}
+
+// See certs::BackendSigningKey
+#[derive(Clone)]
+struct BackendSigningKey(certs::BackendSigningKey);
+
+impl sign::SigningKey for BackendSigningKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn sign::Signer>> {
+        if offered.contains(&SignatureScheme::ECDSA_NISTP256_SHA256) {
+            Some(Box::new(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ECDSA
     }
 }
 
-fn certificate(bytes: &mut dyn io::BufRead) -> AnyhowResult<Certificate> {
-    if let Item::X509Certificate(it) = rustls_pemfile::read_one(bytes).unwrap().unwrap() {
-        Ok(Certificate(it))
-    } else {
-        Err(anyhow!("Could not load certificate"))
+impl sign::Signer for BackendSigningKey {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        self.0.sign(message).map_err(rustls::Error::General)
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256
     }
 }
 
+// Sites with intermediate CAs hand out the certificate together with its chain, all of
+// which has to be presented to the site
+fn certificate_chain(bytes: &mut dyn io::BufRead) -> AnyhowResult<Vec<Certificate>> {
+    let chain: Vec<Certificate> = rustls_pemfile::certs(bytes)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(anyhow!("Could not load certificate"));
+    }
+    Ok(chain)
+}
+
 pub struct IoStream {
     reader: File,
     writer: File,
@@ -114,3 +357,126 @@ impl Write for IoStream {
         self.writer.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CA of a site with a CRL revoking one of its two client certificates, all valid for a
+    // hundred years
+    const CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBkzCCATmgAwIBAgIUZX5RzqGdfLl30qjt8f9HB9jX+gEwCgYIKoZIzj0EAwIw
+HjEcMBoGA1UEAwwTU2l0ZSAnY3JsJyBsb2NhbCBDQTAgFw0yNjEwMTQwNzM5NTBa
+GA8yMTI2MDkyMDA3Mzk1MFowHjEcMBoGA1UEAwwTU2l0ZSAnY3JsJyBsb2NhbCBD
+QTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABClBW9bIFv9otHH2lSqMTCfiw/+u
+y7Gck5RI4TvSgGGCMM1xNgic12HwZXoyq5iCBP5GFTILf5sJFX2VIh3jKG+jUzBR
+MB0GA1UdDgQWBBQQYX88yIiXINqv3uqOzq60sVbUjTAfBgNVHSMEGDAWgBQQYX88
+yIiXINqv3uqOzq60sVbUjTAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gA
+MEUCIHRypydPS9NEWU5M+x7pLTi9DORA3C6ITa91MUoHKNlGAiEA4Kku7pdjABYG
+nlTh2nREBeyr6qyLmYLpNDCod2Lb/Dg=
+-----END CERTIFICATE-----";
+    const OK: &str = "-----BEGIN CERTIFICATE-----
+MIIBcTCCARegAwIBAgIUbrU8lrQ2H2SQN1DCMEvTC7zirp0wCgYIKoZIzj0EAwIw
+HjEcMBoGA1UEAwwTU2l0ZSAnY3JsJyBsb2NhbCBDQTAgFw0yNjEwMTQwNzM5NTBa
+GA8yMTI2MDkyMDA3Mzk1MFowDTELMAkGA1UEAwwCb2swWTATBgcqhkjOPQIBBggq
+hkjOPQMBBwNCAAQknLfyAm9Ux6ho5qysUOOlz8wBI4RvaWOKn1A5pWmyuLvmMTrN
+25Ptk2D/cQXYF6wX5/n/1jjnB/MToaGzzmsEo0IwQDAdBgNVHQ4EFgQUeSG+4t2G
+YwRfQrC0Em167C3FtSowHwYDVR0jBBgwFoAUEGF/PMiIlyDar97qjs6utLFW1I0w
+CgYIKoZIzj0EAwIDSAAwRQIgDz/372B2Ol8QYudbOgmLw7ubZ/vsENu7vHkVZsFR
+g4ACIQDURfPCE+B2B92yqQFY/a44ACvYw1vNYC7kn4AwlfOroA==
+-----END CERTIFICATE-----";
+    const REVOKED: &str = "-----BEGIN CERTIFICATE-----
+MIIBdzCCARygAwIBAgIUbrU8lrQ2H2SQN1DCMEvTC7zirp4wCgYIKoZIzj0EAwIw
+HjEcMBoGA1UEAwwTU2l0ZSAnY3JsJyBsb2NhbCBDQTAgFw0yNjEwMTQwNzM5NTBa
+GA8yMTI2MDkyMDA3Mzk1MFowEjEQMA4GA1UEAwwHcmV2b2tlZDBZMBMGByqGSM49
+AgEGCCqGSM49AwEHA0IABJNfqzB6OkrQ7C6a8nRoT4od5TMkywZC+CIJYcN6hyND
+qT6HiL4oEX8Pai0DZSSxs365nzsTXQa1pEBj9d2G33ijQjBAMB0GA1UdDgQWBBTP
+xlzWj9cFzy+V0nVgjdcW2Mib6TAfBgNVHSMEGDAWgBQQYX88yIiXINqv3uqOzq60
+sVbUjTAKBggqhkjOPQQDAgNJADBGAiEAvSuZ6hfjPKLep2y8YZU3XAovLF1VYpP7
+iF16da87GoICIQCzEJSpykyFf96w/MyIA7dSW+1mX3DKpX9gsd0e2qL7/Q==
+-----END CERTIFICATE-----";
+    const CRL: &str = "-----BEGIN X509 CRL-----
+MIHiMIGIAgEBMAoGCCqGSM49BAMCMB4xHDAaBgNVBAMME1NpdGUgJ2NybCcgbG9j
+YWwgQ0EXDTI2MTAxNDA3Mzk1MFoYDzIxMjYwOTIwMDczOTUwWjAnMCUCFG61PJa0
+Nh9kkDdQwjBL0wu84q6eFw0yNjEwMTQwNzM5NTBaoA4wDDAKBgNVHRQEAwIBATAK
+BggqhkjOPQQDAgNJADBGAiEAsYtKnYlIu4A9wCN1ixiszm6/Y5nN7pAq5eHkoLde
+Ky0CIQCQ24rGJbQls0+2o7yzoq/dpYJiWXonGqeO5P5Len9AOg==
+-----END X509 CRL-----";
+
+    #[test]
+    fn test_cipher_suites() {
+        let suites = cipher_suites(&[
+            String::from("TLS13_AES_256_GCM_SHA384"),
+            String::from("tls_ecdhe_rsa_with_aes_128_gcm_sha256"),
+        ])
+        .unwrap();
+        assert_eq!(suites.len(), 2);
+        assert_eq!(suites[0].version(), &version::TLS13);
+        assert_eq!(suites[1].version(), &version::TLS12);
+        assert!(cipher_suites(&[String::from("TLS_RSA_WITH_RC4_128_MD5")]).is_err());
+    }
+
+    #[test]
+    fn test_revocation_checking_verifier() {
+        let crl_dir =
+            std::env::temp_dir().join(format!("cmk-agent-ctl-crl-{}", std::process::id()));
+        std::fs::create_dir_all(&crl_dir).unwrap();
+        // Looked up by the hash of the issuer, like c_rehash names them
+        let ca = openssl::x509::X509::from_pem(CA.as_bytes()).unwrap();
+        std::fs::write(
+            crl_dir.join(format!("{:08x}.r0", ca.subject_name_hash())),
+            CRL,
+        )
+        .unwrap();
+        let server_specs: Vec<config::ServerSpec> = vec![serde_json::from_value(
+            serde_json::json!({"uuid": "ok", "private_key": "", "certificate": "", "root_cert": CA}),
+        )
+        .unwrap()];
+        let verifier = RevocationCheckingVerifier {
+            inner: AllowAnyAuthenticatedClient::new(root_cert_store(&server_specs).unwrap()),
+            crl_store: crl_store(&server_specs, crl_dir.to_str().unwrap()).unwrap(),
+        };
+        let verify = |cert: &str| {
+            let cert = openssl::x509::X509::from_pem(cert.as_bytes()).unwrap();
+            verifier.verify_client_cert(
+                &Certificate(cert.to_der().unwrap()),
+                &[],
+                SystemTime::now(),
+            )
+        };
+        assert!(verify(OK).is_ok());
+        let error = verify(REVOKED).err().unwrap();
+        assert!(format!("{}", error).contains("Revocation check failed"));
+        std::fs::remove_dir_all(&crl_dir).unwrap();
+    }
+
+    #[test]
+    fn test_cert_resolver() {
+        let key_spec = certs::KeySpec {
+            algorithm: config::KeyAlgorithm::EcdsaP256,
+            rsa_key_size: 2048,
+            fips: false,
+            pkcs11_token: None,
+        };
+        let server_spec = |uuid: &str| -> config::ServerSpec {
+            let (certificate, private_key) = certs::make_self_signed(uuid, &key_spec).unwrap();
+            serde_json::from_value(serde_json::json!({
+                "uuid": uuid,
+                "private_key": private_key.expose(),
+                "certificate": certificate,
+                "root_cert": certificate,
+            }))
+            .unwrap()
+        };
+        let single = sni_resolver(&[server_spec("first")]).unwrap();
+        assert!(single.certified_key(Some("first")).is_some());
+        // Such as the host name of the site
+        assert!(single.certified_key(Some("site.example.com")).is_some());
+        assert!(single.certified_key(None).is_some());
+
+        let several = sni_resolver(&[server_spec("first"), server_spec("second")]).unwrap();
+        assert!(several.certified_key(Some("second")).is_some());
+        assert!(several.certified_key(Some("site.example.com")).is_none());
+        assert!(several.certified_key(None).is_none());
+    }
+}
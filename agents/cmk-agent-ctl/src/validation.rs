@@ -0,0 +1,668 @@
+// Copyright (C) 2019 tribe29 GmbH - License: GNU General Public License v2
+// This file is part of Checkmk (https://checkmk.com). It is subject to the terms and
+// conditions defined in the file COPYING, which is part of this source code package.
+
+use super::{
+    address, agent_receiver_api, certs, compression, config, fips, monitoring_data, only_from,
+    pkcs11, proxy, tls_server,
+};
+use log::LevelFilter;
+use openssl::pkey::PKey;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::Path;
+use uuid::Uuid;
+
+#[derive(Default)]
+pub struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn error(&mut self, location: &str, message: &str) {
+        println!("{}: error: {}", location, message);
+        self.errors += 1;
+    }
+
+    fn warning(&mut self, location: &str, message: &str) {
+        println!("{}: warning: {}", location, message);
+        self.warnings += 1;
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors == 0
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} error(s), {} warning(s)", self.errors, self.warnings)
+    }
+}
+
+fn location(path: &Path, line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("{}:{}", path.display(), line),
+        None => path.display().to_string(),
+    }
+}
+
+// Best effort, since neither serde_json nor toml keep track of where a key was defined
+fn key_line(content: &str, key: &str) -> Option<usize> {
+    let quoted = format!("\"{}\"", key);
+    content
+        .lines()
+        .position(|line| {
+            let line = line.trim_start();
+            line.contains(&quoted)
+                || (line.starts_with(key) && line[key.len()..].trim_start().starts_with('='))
+        })
+        .map(|index| index + 1)
+}
+
+// The rustls based client cannot connect to IP addresses
+fn has_ip_address(config: &config::Config) -> bool {
+    config
+        .agent_receiver_address
+        .as_deref()
+        .and_then(|address| address::parse(address).ok()?.ip())
+        .is_some()
+}
+
+fn top_level_keys(
+    report: &mut Report,
+    path: &Path,
+    content: &str,
+    toml: bool,
+) -> Option<Vec<String>> {
+    if toml {
+        match toml::from_str::<toml::Value>(content) {
+            Ok(toml::Value::Table(table)) => Some(table.keys().cloned().collect()),
+            Ok(_) => {
+                report.error(&location(path, None), "Expected a table");
+                None
+            }
+            Err(error) => {
+                report.error(
+                    &location(path, error.line_col().map(|(line, _)| line + 1)),
+                    &error.to_string(),
+                );
+                None
+            }
+        }
+    } else {
+        match serde_json::from_str::<serde_json::Value>(content) {
+            Ok(serde_json::Value::Object(object)) => Some(object.keys().cloned().collect()),
+            Ok(_) => {
+                report.error(&location(path, None), "Expected a JSON object");
+                None
+            }
+            Err(error) => {
+                report.error(&location(path, Some(error.line())), &error.to_string());
+                None
+            }
+        }
+    }
+}
+
+fn check_config_values(report: &mut Report, path: &Path, content: &str, config: &config::Config) {
+    let at = |key: &str| location(path, key_line(content, key));
+
+    if let Some(address) = &config.agent_receiver_address {
+        if let Err(message) = address::normalize(address) {
+            report.error(&at("agent_receiver_address"), &message);
+        }
+    }
+    for address in config.failover_addresses.iter().flatten() {
+        if let Err(message) = address::normalize(address) {
+            report.error(&at("failover_addresses"), &message);
+        }
+    }
+    if let Some(credentials) = &config.credentials {
+        if !credentials.expose().contains(' ') {
+            report.error(
+                &at("credentials"),
+                "credentials must have the form \"user password\"",
+            );
+        }
+    }
+    if let Some(root_certificate) = &config.root_certificate {
+        if certs::fingerprint(root_certificate).is_err() {
+            report.error(
+                &at("root_certificate"),
+                "root_certificate is no valid PEM certificate",
+            );
+        }
+    }
+    if let Some(fingerprint) = &config.receiver_cert_fingerprint {
+        let normalized = certs::normalize_fingerprint(fingerprint);
+        if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+            report.error(
+                &at("receiver_cert_fingerprint"),
+                "receiver_cert_fingerprint must be a SHA256 fingerprint in hex",
+            );
+        }
+        if has_ip_address(config) {
+            report.error(
+                &at("receiver_cert_fingerprint"),
+                "receiver_cert_fingerprint requires agent_receiver_address to be a host name",
+            );
+        }
+    }
+    if config.tls_key_log == Some(true) {
+        if has_ip_address(config) {
+            report.error(
+                &at("tls_key_log"),
+                "tls_key_log requires agent_receiver_address to be a host name",
+            );
+        }
+        report.warning(
+            &at("tls_key_log"),
+            "tls_key_log is meant for troubleshooting, disable it afterwards",
+        );
+    }
+    if fips::is_enabled(config.crypto_policy) {
+        if has_ip_address(config) {
+            report.error(
+                &at("crypto_policy"),
+                "crypto_policy fips requires agent_receiver_address to be a host name",
+            );
+        }
+        if config.tls_min_version == Some(config::TlsVersion::Tls13) {
+            report.error(
+                &at("tls_min_version"),
+                "crypto_policy fips limits pull connections to TLS 1.2",
+            );
+        }
+        if let Some(Err(error)) = config.key_algorithm.map(fips::check_key_algorithm) {
+            report.error(&at("key_algorithm"), &error.to_string());
+        }
+        for name in config.tls_cipher_suites.iter().flatten() {
+            if !fips::is_approved_cipher_suite(&name.to_uppercase()) {
+                report.warning(
+                    &at("tls_cipher_suites"),
+                    &format!(
+                        "Cipher suite {} is not allowed by crypto_policy fips and ignored",
+                        name
+                    ),
+                );
+            }
+        }
+    }
+    if let Some(proxy_url) = &config.proxy_url {
+        if let Err(error) = proxy::parse(proxy_url) {
+            report.error(&at("proxy_url"), &format!("{:#}", error));
+        }
+    }
+    if let Some(root_certificate_path) = &config.root_certificate_path {
+        let root_certificate_path = path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(root_certificate_path);
+        match read_to_string(&root_certificate_path) {
+            Ok(root_certificate) if certs::fingerprint(&root_certificate).is_err() => report.error(
+                &at("root_certificate_path"),
+                &format!(
+                    "{} contains no valid PEM certificate",
+                    root_certificate_path.display()
+                ),
+            ),
+            Ok(_) => {}
+            Err(error) => report.error(
+                &at("root_certificate_path"),
+                &format!("Cannot read {}: {}", root_certificate_path.display(), error),
+            ),
+        }
+        if config.root_certificate.is_some() {
+            report.warning(
+                &at("root_certificate_path"),
+                "root_certificate_path is ignored, since root_certificate is set as well",
+            );
+        }
+    }
+    if let Some(key_passphrase_file) = &config.key_passphrase_file {
+        if let Err(error) = config::read_key_passphrase(Path::new(key_passphrase_file)) {
+            report.error(
+                &at("key_passphrase_file"),
+                &format!("Cannot read key passphrase: {}", error),
+            );
+        }
+    }
+    if let Some(push_encryption_key_file) = &config.push_encryption_key_file {
+        if let Err(error) = config::read_push_encryption_key(Path::new(push_encryption_key_file)) {
+            report.error(
+                &at("push_encryption_key_file"),
+                &format!("Cannot read push encryption key: {}", error),
+            );
+        }
+    }
+    if let Some(crl_dir) = &config.crl_dir {
+        if !Path::new(crl_dir).is_dir() {
+            report.error(&at("crl_dir"), &format!("{} is no directory", crl_dir));
+        }
+    }
+    if let Some(names) = &config.tls_cipher_suites {
+        match tls_server::cipher_suites(names) {
+            Err(message) => report.error(&at("tls_cipher_suites"), &message),
+            Ok(suites)
+                if config.tls_min_version == Some(config::TlsVersion::Tls13)
+                    && !suites
+                        .iter()
+                        .any(|suite| suite.version() == &rustls::version::TLS13) =>
+            {
+                report.error(
+                    &at("tls_cipher_suites"),
+                    "tls_cipher_suites contains no TLS 1.3 suite, but tls_min_version is 1.3",
+                )
+            }
+            Ok(_) => {}
+        }
+    }
+    if config.agent_port == Some(0) {
+        report.error(&at("agent_port"), "agent_port must be positive");
+    }
+    if config.agent_socket.is_some() && config.agent_port.is_some() {
+        report.warning(
+            &at("agent_socket"),
+            "agent_socket is ignored, since agent_port is set as well",
+        );
+    }
+    if let Some(data_source) = &config.data_source {
+        match monitoring_data::parse_data_source(data_source) {
+            Err(message) => report.error(&at("data_source"), &message),
+            Ok(monitoring_data::DataSource::Tcp { port, .. })
+                if port == config.listen_port.unwrap_or(config::DEFAULT_LISTEN_PORT) =>
+            {
+                report.warning(
+                    &at("data_source"),
+                    &format!(
+                        "The daemon listens for pull connections on port {} as well, only push without the daemon or change listen_port",
+                        port
+                    ),
+                )
+            }
+            Ok(_) => {}
+        }
+        if config.agent_socket.is_some() || config.agent_port.is_some() {
+            report.warning(
+                &at("data_source"),
+                "agent_socket and agent_port are ignored, since data_source is set",
+            );
+        }
+    }
+    for address in config.listen_addresses.iter().flatten() {
+        if address.parse::<IpAddr>().is_err() {
+            report.error(
+                &at("listen_addresses"),
+                &format!("Listen address {} is no IP address", address),
+            );
+        }
+    }
+    if let Some(only_from) = &config.only_from {
+        if let Err(message) = only_from::parse(only_from) {
+            report.error(&at("only_from"), &message);
+        }
+    }
+    if config.listen_port == Some(0) {
+        report.error(&at("listen_port"), "listen_port must be positive");
+    }
+    if config.push_interval == Some(0) {
+        report.error(&at("push_interval"), "push_interval must be positive");
+    }
+    if config.stale_after == Some(0) {
+        report.error(&at("stale_after"), "stale_after must be positive");
+    }
+    if let Some(rsa_key_size) = config.rsa_key_size {
+        if !certs::RSA_KEY_SIZES.contains(&rsa_key_size) {
+            report.error(
+                &at("rsa_key_size"),
+                &format!(
+                    "rsa_key_size must be one of 2048, 3072 and 4096, not {}",
+                    rsa_key_size
+                ),
+            );
+        } else if config
+            .key_algorithm
+            .is_some_and(|key_algorithm| key_algorithm != config::KeyAlgorithm::Rsa)
+        {
+            report.warning(
+                &at("rsa_key_size"),
+                "rsa_key_size has no effect, key_algorithm is not rsa",
+            );
+        }
+    }
+    if let Some(token) = &config.pkcs11_token {
+        if let Err(message) = pkcs11::parse_uri(token) {
+            report.error(&at("pkcs11_token"), &message);
+        }
+        // Without key_algorithm, ecdsa_p256 is picked for the token
+        if config
+            .key_algorithm
+            .is_some_and(|key_algorithm| key_algorithm != config::KeyAlgorithm::EcdsaP256)
+        {
+            report.error(
+                &at("key_algorithm"),
+                "pkcs11_token needs key_algorithm ecdsa_p256",
+            );
+        }
+    }
+    for header in config.http_headers.iter().flatten() {
+        if let Err(message) = agent_receiver_api::parse_header(header) {
+            report.error(&at("http_headers"), &message);
+        }
+    }
+    for attribute in config.csr_attributes.iter().flatten() {
+        if certs::parse_csr_attribute(attribute).is_none() {
+            report.error(
+                &at("csr_attributes"),
+                &format!("CSR attribute {} must have the form key=value", attribute),
+            );
+        }
+    }
+    if config.renew_before == Some(0) {
+        report.error(&at("renew_before"), "renew_before must be positive");
+    }
+    if config.push_timeout == Some(0) {
+        report.error(&at("push_timeout"), "push_timeout must be positive");
+    }
+    if config.push_rate_limit == Some(0) {
+        report.error(&at("push_rate_limit"), "push_rate_limit must be positive");
+    }
+    if let Some(level) = config.push_compression_level {
+        if let Err(error) = compression::check_level(
+            config.push_compression.unwrap_or(config::Compression::None),
+            level,
+        ) {
+            report.error(&at("push_compression_level"), &error);
+        }
+    }
+    if config.connect_timeout == Some(0) {
+        report.error(&at("connect_timeout"), "connect_timeout must be positive");
+    }
+    if config.request_timeout == Some(0) {
+        report.error(&at("request_timeout"), "request_timeout must be positive");
+    }
+    if config.api_retries.unwrap_or(0) > 0 && config.api_retry_backoff == Some(0) {
+        report.warning(
+            &at("api_retry_backoff"),
+            "api_retry_backoff is 0, failed requests are repeated without delay",
+        );
+    }
+    if let (Some(push_interval), Some(min_push_interval)) =
+        (config.push_interval, config.min_push_interval)
+    {
+        if min_push_interval > push_interval {
+            report.warning(
+                &at("min_push_interval"),
+                "min_push_interval is larger than push_interval, pushes are less frequent than configured",
+            );
+        }
+    }
+    if let (Some(push_interval), Some(push_jitter)) = (config.push_interval, config.push_jitter) {
+        if push_jitter >= push_interval {
+            report.warning(
+                &at("push_jitter"),
+                "push_jitter is not smaller than push_interval, pushes may be far apart",
+            );
+        }
+    }
+    if let Some(log_level) = &config.log_level {
+        if log_level.parse::<LevelFilter>().is_err() {
+            report.error(
+                &at("log_level"),
+                &format!("Invalid log_level {}", log_level),
+            );
+        }
+    }
+    if let (Some(sections), Some(exclude_sections)) = (&config.sections, &config.exclude_sections) {
+        for section in sections.iter().filter(|s| exclude_sections.contains(s)) {
+            report.warning(
+                &at("exclude_sections"),
+                &format!("Section {} is both included and excluded", section),
+            );
+        }
+    }
+}
+
+fn check_config_file(report: &mut Report, path: &Path) -> Option<config::Config> {
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            report.error(&location(path, None), &format!("Cannot read: {}", error));
+            return None;
+        }
+    };
+    let toml = config::is_toml(path) || !content.trim_start().starts_with('{');
+
+    let field_names = config::Config::field_names();
+    for key in top_level_keys(report, path, &content, toml)? {
+        if !field_names.contains(&key) {
+            report.warning(
+                &location(path, key_line(&content, &key)),
+                &format!("Unknown key {}", key),
+            );
+        }
+    }
+
+    let parsed = if toml {
+        toml::from_str::<config::Config>(&content).map_err(|error| {
+            (
+                error.line_col().map(|(line, _)| line + 1),
+                error.to_string(),
+            )
+        })
+    } else {
+        serde_json::from_str::<config::Config>(&content)
+            .map_err(|error| (Some(error.line()), error.to_string()))
+    };
+    match parsed {
+        Ok(config) => {
+            check_config_values(report, path, &content, &config);
+            Some(config)
+        }
+        Err((line, message)) => {
+            report.error(&location(path, line), &message);
+            None
+        }
+    }
+}
+
+fn check_server_spec(report: &mut Report, at: &str, spec: &config::ServerSpec) {
+    if Uuid::parse_str(&spec.uuid).is_err() {
+        report.error(at, &format!("Invalid UUID {}", spec.uuid));
+    }
+    if pkcs11::is_uri(spec.private_key.expose()) {
+        if let Err(message) = pkcs11::parse_uri(spec.private_key.expose()) {
+            report.error(at, &format!("private_key: {}", message));
+        }
+    } else if PKey::private_key_from_pem(spec.private_key.expose().as_bytes()).is_err() {
+        report.error(at, "private_key is no valid PEM private key");
+    }
+    if certs::fingerprint(&spec.root_cert).is_err() {
+        report.error(at, "root_cert is no valid PEM certificate");
+    }
+    match certs::common_name(&spec.certificate) {
+        Ok(common_name) if common_name != spec.uuid => report.warning(
+            at,
+            &format!(
+                "certificate was issued for {}, not for the UUID {}",
+                common_name, spec.uuid
+            ),
+        ),
+        Ok(_) => {}
+        Err(_) => report.error(at, "certificate is no valid PEM certificate"),
+    }
+}
+
+fn check_state_file(
+    report: &mut Report,
+    path: &Path,
+    key_passphrase: Option<&str>,
+) -> Option<config::RegistrationState> {
+    if !path.exists() {
+        return Some(config::RegistrationState::empty_state());
+    }
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(error) => {
+            report.error(&location(path, None), &format!("Cannot read: {}", error));
+            return None;
+        }
+    };
+    let state = match serde_json::from_str::<serde_json::Value>(&content) {
+        Ok(state) => state,
+        Err(error) => {
+            report.error(&location(path, Some(error.line())), &error.to_string());
+            return None;
+        }
+    };
+    match config::RegistrationState::from_value(state, key_passphrase) {
+        Ok(reg_state) => {
+            for (address, spec) in &reg_state.server_specs {
+                let at = format!("{} ({})", location(path, None), address);
+                // Loading the state adds missing ports, unless a registration with the port
+                // exists as well
+                match address::normalize(address) {
+                    Ok(normalized) if normalized != *address => report.error(
+                        &at,
+                        &format!(
+                            "{} lacks a port and is registered as {} as well, only the latter is used",
+                            address, normalized
+                        ),
+                    ),
+                    Ok(_) => {}
+                    Err(message) => report.error(&at, &message),
+                }
+                check_server_spec(report, &at, spec);
+            }
+            Some(reg_state)
+        }
+        Err(error) => {
+            report.error(&location(path, None), &error.to_string());
+            None
+        }
+    }
+}
+
+fn check_modes(
+    report: &mut Report,
+    config: &config::Config,
+    reg_state: &config::RegistrationState,
+) {
+    let at = "configuration";
+    for (missing, name) in [
+        (
+            config.agent_receiver_address.is_none(),
+            "agent_receiver_address",
+        ),
+        (config.credentials.is_none(), "credentials"),
+        (config.host_name.is_none(), "host_name"),
+    ] {
+        if missing {
+            report.warning(
+                at,
+                &format!(
+                    "{} is not configured, register has to get it from the command line or a prompt",
+                    name
+                ),
+            );
+        }
+    }
+    if let Some(address) = &config.agent_receiver_address {
+        if address::normalize(address)
+            .is_ok_and(|address| !reg_state.server_specs.contains_key(&address))
+        {
+            report.warning(
+                at,
+                &format!(
+                    "No registration with {}, renew-certificate and delete will fail",
+                    address
+                ),
+            );
+        }
+    }
+    if config.renew_before.is_some() && config.credentials.is_none() {
+        report.warning(
+            at,
+            "renew_before is set, but certificates cannot be renewed without credentials",
+        );
+    }
+    if reg_state.server_specs.is_empty() {
+        report.warning(at, "No registrations, push and TLS pull will not work");
+    }
+    if fips::is_enabled(config.crypto_policy) {
+        if let Err(error) = fips::check_state(reg_state) {
+            report.error(at, &format!("{:#}", error));
+        }
+    }
+}
+
+pub fn validate(
+    config_paths: &[&Path],
+    secrets_path: &Path,
+    state_path: &Path,
+    key_passphrase: Option<&str>,
+) -> Report {
+    let mut report = Report::default();
+
+    let mut merged = Some(config::Config::empty_config());
+    for path in config_paths.iter().filter(|path| path.exists()) {
+        let config = check_config_file(&mut report, path);
+        if config
+            .as_ref()
+            .is_some_and(|config| config.credentials.is_some())
+        {
+            report.warning(
+                &location(path, None),
+                &format!(
+                    "credentials should be moved to {}, which is not readable by others",
+                    secrets_path.display()
+                ),
+            );
+        }
+        merged = match (merged, config) {
+            (Some(merged), Some(config)) => Some(config::Config::merge_two_configs(merged, config)),
+            _ => None,
+        };
+    }
+    let merged = match (merged, config::Secrets::from_file(secrets_path)) {
+        (Some(merged), Ok(secrets)) => Some(config::Config::merge_two_configs(
+            merged,
+            secrets.into_config(),
+        )),
+        (Some(merged), Err(error)) if error.is_missing() => Some(merged),
+        (_, Err(error)) => {
+            report.error(&location(secrets_path, None), &error.to_string());
+            None
+        }
+        (None, _) => None,
+    };
+
+    let merged = match (merged, config::Config::from_env()) {
+        (Some(merged), Ok(env)) => Some(config::Config::merge_two_configs(merged, env)),
+        (_, Err(error)) => {
+            report.error("environment", &error.to_string());
+            None
+        }
+        (None, _) => None,
+    };
+
+    let reg_state = check_state_file(&mut report, state_path, key_passphrase);
+
+    if let (Some(config), Some(reg_state)) = (merged, reg_state) {
+        check_modes(&mut report, &config, &reg_state);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_line() {
+        let content = "{\n  \"package_name\": \"x\",\n  \"log_level\": \"debug\"\n}";
+        assert_eq!(key_line(content, "log_level"), Some(3));
+        assert_eq!(key_line("log_level = \"debug\"\n", "log_level"), Some(1));
+        assert_eq!(key_line(content, "sections"), None);
+    }
+}